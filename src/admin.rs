@@ -0,0 +1,214 @@
+use crate::db::Domain;
+use crate::domain::Dname;
+use crate::ffdyndns::{DomainFilter, Error, RecordChange, Service};
+use crate::nsupdate::UpdateHandle;
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use chrono::Utc;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use serde_json::json;
+use std::net::IpAddr;
+use std::process::exit;
+
+
+/// the `domain` subcommand for admins
+pub fn domain_command() -> App<'static, 'static> {
+	let name = || Arg::with_name("name").required(true);
+
+	App::new("domain")
+		.about("manages the domains, changes are sent to the DNS server")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.arg(Arg::with_name("json")
+			.long("json")
+			.global(true)
+			.help("prints JSON instead of a table"))
+		.subcommand(App::new("list")
+			.arg(Arg::with_name("suffix")
+				.long("suffix")
+				.takes_value(true))
+			.arg(Arg::with_name("expired")
+				.long("expired")
+				.help("only the expired domains"))
+			.arg(Arg::with_name("suspended")
+				.long("suspended")
+				.help("only the suspended domains")))
+		.subcommand(App::new("show")
+			.arg(name()))
+		.subcommand(App::new("add")
+			.arg(name())
+			.arg(Arg::with_name("token")
+				.long("token")
+				.takes_value(true)
+				.help("default is a new random token")))
+		.subcommand(App::new("delete")
+			.arg(name()))
+		.subcommand(App::new("renew")
+			.about("makes the domain valid for the given days from now on")
+			.arg(name())
+			.arg(Arg::with_name("days")
+				.long("days")
+				.takes_value(true)
+				.required(true)))
+		.subcommand(App::new("set-ip")
+			.about("sets the A or AAAA record, or both")
+			.arg(name())
+			.arg(Arg::with_name("ip")
+				.required(true)
+				.multiple(true)))
+		.subcommand(App::new("reset-token")
+			.arg(name()))
+		.subcommand(App::new("lock")
+			.about("keeps the records, but the token cannot change them anymore")
+			.arg(name()))
+		.subcommand(App::new("unlock")
+			.arg(name()))
+		.subcommand(App::new("suspend")
+			.about("removes the records, blocks updates and keeps the name from being registered again")
+			.arg(name())
+			.arg(Arg::with_name("reason")
+				.long("reason")
+				.takes_value(true)
+				.required(true)))
+		.subcommand(App::new("unsuspend")
+			.arg(name()))
+}
+
+
+pub async fn cmd_domain(service: &Service, args: &ArgMatches<'_>) {
+	let (cmd, args) = match args.subcommand() {
+		(cmd, Some(args)) => (cmd, args),
+		_ => unreachable!(),
+	};
+	let json = args.is_present("json");
+	let name = args.value_of("name").map(|n| Dname::new(n.to_lowercase()).to_string());
+
+	match cmd {
+		"list" => {
+			let domains = check(service.find_domains(&DomainFilter {
+				suffix: args.value_of("suffix").map(String::from),
+				expires_before: args.is_present("expired").then(Utc::now),
+				suspended: args.is_present("suspended").then_some(true),
+				..Default::default()
+			}));
+			print_domains(&domains, json);
+		}
+		"show" => {
+			let name = name.unwrap();
+			match check(service.get_domain(&name)) {
+				Some(d) => print_domains(&[d], json),
+				None => fail(Error::DomainNotFound),
+			}
+		}
+		"add" => {
+			let name = name.unwrap();
+			let token = check(service.add_domain(Dname::new(name.clone()), args.value_of("token").map(String::from)));
+			print_token(&name, &token, json);
+		}
+		"delete" => {
+			let handle = check(service.remove_domain(&name.unwrap()).await);
+			wait(service, handle).await;
+		}
+		"renew" => {
+			let days: i64 = args.value_of("days").unwrap().parse().unwrap_or_else(|_| {
+				error!("--days needs a number");
+				exit(1);
+			});
+			let d = check(service.renew_domain(&name.unwrap(), chrono::Duration::days(days)));
+			print_domains(&[d], json);
+		}
+		"set-ip" => {
+			let changes = args.values_of("ip").unwrap()
+				.map(|ip| ip.parse::<IpAddr>().map(RecordChange::SetAddr).unwrap_or_else(|e| {
+					error!("invalid address {}: {}", ip, e);
+					exit(1);
+				}))
+				.collect::<Vec<RecordChange>>();
+
+			let handle = check(service.set_records(&name.unwrap(), &changes).await);
+			wait(service, handle).await;
+		}
+		"reset-token" => {
+			let name = name.unwrap();
+			let token = check(service.reset_token(&name));
+			print_token(&name, &token, json);
+		}
+		"lock" | "unlock" => {
+			let d = check(service.lock_domain(&name.unwrap(), cmd == "lock"));
+			print_domains(&[d], json);
+		}
+		"suspend" => {
+			let handle = check(service.suspend_domain(&name.unwrap(), args.value_of("reason").unwrap()).await);
+			wait(service, handle).await;
+		}
+		"unsuspend" => {
+			let d = check(service.unsuspend_domain(&name.unwrap()));
+			print_domains(&[d], json);
+		}
+		_ => unreachable!(),
+	}
+}
+
+
+fn check<T>(r: Result<T, Error>) -> T {
+	r.unwrap_or_else(|e| fail(e))
+}
+
+
+fn fail(e: Error) -> ! {
+	error!("{}", e);
+	exit(1);
+}
+
+
+/// the change is stored already, but the DNS server may not have it
+async fn wait(service: &Service, handle: UpdateHandle) {
+	if let Err(e) = service.wait_for_update(handle).await {
+		error!("the change is saved, but {}", e);
+		exit(1);
+	}
+}
+
+
+fn print_token(name: &str, token: &str, json: bool) {
+	if json {
+		println!("{}", json!({"domainname": name, "token": token}));
+	} else {
+		println!("{} {}", name, token);
+	}
+}
+
+
+fn print_domains(domains: &[Domain], json: bool) {
+	if json {
+		println!("{}", serde_json::to_string_pretty(domains).unwrap());
+		return;
+	}
+
+	let opt = |a: Option<String>| a.unwrap_or_else(|| "-".to_string());
+	let rows: Vec<[String; 7]> = domains.iter().map(|d| [
+		d.domainname.clone(),
+		opt(d.ipv4.map(|a| a.to_string())),
+		opt(d.ipv6.map(|a| a.to_string())),
+		d.txt.len().to_string(),
+		d.lastupdate.format("%Y-%m-%d %H:%M").to_string(),
+		d.valid_until.format("%Y-%m-%d %H:%M").to_string(),
+		match (&d.suspended, d.locked) {
+			(Some(s), _) => format!("suspended: {}", s.reason),
+			(None, true) => "locked".to_string(),
+			(None, false) => String::new(),
+		},
+	]).collect();
+
+	let header = ["DOMAIN", "IPV4", "IPV6", "TXT", "LAST UPDATE", "VALID UNTIL", ""].map(String::from);
+	let mut widths = [0; 7];
+	for row in std::iter::once(&header).chain(&rows) {
+		for (w, col) in widths.iter_mut().zip(row) {
+			*w = (*w).max(col.len());
+		}
+	}
+
+	for row in std::iter::once(&header).chain(&rows) {
+		let cols: Vec<String> = row.iter().zip(&widths).map(|(c, w)| format!("{:w$}", c, w = w)).collect();
+		println!("{}", cols.join("  ").trim_end());
+	}
+}
@@ -1,9 +1,9 @@
-use chrono::Duration;
+use chrono::{Duration, Utc};
 use serde::{self, Deserialize, Deserializer};
 use serde::de::{self, Visitor};
 use std::fmt;
 use std::marker::PhantomData;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 // use void::Void;
 
@@ -18,12 +18,248 @@ pub struct Config {
 	pub dns_server: String,
 	pub bind_address: IpAddr,
 	pub bind_port: u16,
+	/// proxies whose X-Forwarded-For header is believed
+	#[serde(default = "default_trusted_proxies")]
+	pub trusted_proxies: Vec<IpAddr>,
+	/// how updates get into the DNS server
+	#[serde(default)]
+	pub backend: Backend,
+	/// where the DNSSEC keys of signed domains are kept
+	#[serde(default = "default_key_directory")]
+	pub key_directory: String,
+	/// address for the UDP and TCP listener taking RFC 2136 updates
+	/// from the users, eg. 0.0.0.0:5353
+	#[serde(default)]
+	pub update_listen: Option<SocketAddr>,
+	/// snapshots of the database taken while the server runs
+	#[serde(default)]
+	pub backup: Option<Backup>,
+	/// days the changes of the domains are kept in the history
+	#[serde(default = "default_history_days")]
+	pub history_days: i64,
+	/// days an expired name is kept from other users,
+	/// only the previous owner can reclaim it meanwhile
+	#[serde(default = "default_quarantine_days")]
+	pub quarantine_days: i64,
+	/// who may log in to the admin pages at /admin
+	#[serde(default)]
+	pub admin: Vec<AdminUser>,
+	/// keys for the admin API at /api/admin
+	#[serde(default)]
+	pub api_key: Vec<ApiKey>,
+	/// base64 key for the login cookies of the accounts, at least 32 bytes
+	#[serde(default)]
+	pub secret_key: Option<String>,
+	/// relay for the mails to the owners, without it no mails are sent
+	#[serde(default)]
+	pub smtp: Option<Smtp>,
+	/// days before the expiry the owners get a reminder
+	#[serde(default = "default_reminder_days")]
+	pub reminder_days: Vec<i64>,
+}
+
+fn default_key_directory() -> String {
+	"/var/lib/ffdyndns-keys".to_string()
+}
+
+fn default_history_days() -> i64 {
+	30
+}
+
+fn default_quarantine_days() -> i64 {
+	30
+}
+
+fn default_reminder_days() -> Vec<i64> {
+	vec![14, 3]
+}
+
+fn default_trusted_proxies() -> Vec<IpAddr> {
+	vec![IpAddr::from([127, 0, 0, 1]), IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]
 }
 
 impl Config {
 	pub fn get_domain_config(&self, domain: &String) -> Option<&Domain> {
 		self.domain.iter().find(|e| &e.name == domain)
 	}
+
+	/// the decoded `secret_key`, `None` if there is none
+	pub fn secret_key(&self) -> Result<Option<Vec<u8>>, String> {
+		match &self.secret_key {
+			Some(key) => match base64::decode(key.trim()) {
+				Ok(key) if key.len() >= 32 => Ok(Some(key)),
+				_ => Err("secret_key must be at least 32 bytes in base64, eg. from `openssl rand -base64 32`".to_string()),
+			},
+			None => Ok(None),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+	/// RFC 2136 updates through nsupdate, sent to `dns_server`
+	Nsupdate,
+	/// PATCH requests against the PowerDNS HTTP API
+	#[serde(rename = "powerdns")]
+	PowerDns {
+		/// base url of the API, eg. http://127.0.0.1:8081
+		url: String,
+		api_key: String,
+		#[serde(default = "default_pdns_server_id")]
+		server_id: String,
+	},
+	/// ffdyndns answers the DNS queries for the configured domains itself
+	Builtin(BuiltinServer),
+	/// a zone file per domain, for servers without dynamic updates
+	#[serde(rename = "zonefile")]
+	ZoneFile(ZoneFiles),
+}
+
+impl Default for Backend {
+	fn default() -> Self {
+		Self::Nsupdate
+	}
+}
+
+fn default_pdns_server_id() -> String {
+	"localhost".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BuiltinServer {
+	/// address for the UDP and TCP listener, eg. 0.0.0.0:53
+	pub listen: SocketAddr,
+	/// secondaries that get a NOTIFY after every change
+	#[serde(default)]
+	pub notify: Vec<SocketAddr>,
+	/// networks that may transfer the zones without a key
+	#[serde(default)]
+	pub allow_transfer: Vec<String>,
+	/// keys that may transfer the zones from anywhere
+	#[serde(default)]
+	pub transfer_keys: Vec<TsigKey>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ZoneFiles {
+	/// directory the <domain>.zone files are written to
+	pub directory: String,
+	/// run by the shell after a zone file changed, {zone}
+	/// is replaced by the domain. eg. nsd-control reload {zone}
+	pub reload_command: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Backup {
+	/// directory the snapshots are written to
+	pub directory: String,
+	/// hours between two snapshots
+	#[serde(default = "default_backup_interval")]
+	pub interval: u64,
+	/// number of snapshots kept, older ones are removed
+	#[serde(default = "default_backup_keep")]
+	pub keep: usize,
+}
+
+fn default_backup_interval() -> u64 {
+	24
+}
+
+fn default_backup_keep() -> usize {
+	7
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AdminUser {
+	pub name: String,
+	/// scrypt hash of the password, as printed by `ffdyndns hash-password`
+	pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiKey {
+	/// who uses the key, for the log
+	pub name: String,
+	/// sha256 of the key in hex, eg. from `printf %s <key> | sha256sum`
+	pub key_sha256: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Smtp {
+	pub server: String,
+	/// default is the port of `security`
+	#[serde(default)]
+	pub port: Option<u16>,
+	#[serde(default)]
+	pub security: SmtpSecurity,
+	#[serde(default)]
+	pub username: Option<String>,
+	#[serde(default)]
+	pub password: Option<String>,
+	/// sender of the mails, eg. "FFdynDNS <dyndns@example.org>"
+	pub from: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+	/// plain text on port 25, eg. for a relay on localhost
+	None,
+	/// STARTTLS on port 587
+	Starttls,
+	/// TLS from the start on port 465
+	Tls,
+}
+
+impl Default for SmtpSecurity {
+	fn default() -> Self {
+		Self::Starttls
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TsigKey {
+	pub name: String,
+	/// hmac-sha256 or hmac-sha512
+	#[serde(default = "default_tsig_algorithm")]
+	pub algorithm: String,
+	/// base64 encoded secret, as generated by tsig-keygen
+	pub secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+	"hmac-sha256".to_string()
+}
+
+/// checks if an address is in one of the networks (eg. 10.0.0.0/8 or ::1)
+pub fn ip_in_networks(ip: IpAddr, networks: &[String]) -> bool {
+	// peers on a dual stack socket show up as ipv4 mapped addresses
+	let ip = match ip {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+		v4 => v4,
+	};
+
+	networks.iter().any(|net| {
+		let (addr, len) = match net.split_once('/') {
+			Some((a, l)) => (a, l.parse::<u32>().ok()),
+			None => (net.as_str(), None),
+		};
+
+		match (addr.parse::<IpAddr>(), ip) {
+			(Ok(IpAddr::V4(net)), IpAddr::V4(ip)) => {
+				let len = len.unwrap_or(32).min(32);
+				let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(Ok(IpAddr::V6(net)), IpAddr::V6(ip)) => {
+				let len = len.unwrap_or(128).min(128);
+				let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	})
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -39,6 +275,150 @@ pub struct Domain {
 	/// duration in days before a subdomain gets 'released`
 	#[serde(deserialize_with = "deserialize_duration")]
 	pub validity: Duration,
+	/// wait for the answer of the DNS server on every update
+	/// request, even if the client did not ask for it
+	#[serde(default)]
+	pub wait_for_update: bool,
+	/// nameservers of the zone, the first one is the primary
+	#[serde(default)]
+	pub nameservers: Vec<String>,
+	/// SOA record of the zone
+	#[serde(default)]
+	pub soa: Soa,
+	/// sign the zone, only with the built-in
+	/// nameserver and the zone file backend
+	#[serde(default)]
+	pub dnssec: Option<Dnssec>,
+}
+
+impl Domain {
+	/// primary nameserver of the zone
+	pub fn mname(&self) -> String {
+		self.soa.mname.clone()
+			.or_else(|| self.nameservers.first().cloned())
+			.unwrap_or_else(|| format!("ns.{}", self.name))
+	}
+
+	/// mailbox of the zone admin in domain name notation
+	pub fn rname(&self) -> String {
+		self.soa.rname.clone()
+			.unwrap_or_else(|| format!("hostmaster.{}", self.name))
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Soa {
+	/// primary nameserver, defaults to the first of `nameservers`
+	pub mname: Option<String>,
+	/// mailbox of the zone admin, defaults to hostmaster.<domain>.
+	/// Can be given as mail address, eg. hostmaster@example.org
+	#[serde(deserialize_with = "deserialize_rname")]
+	pub rname: Option<String>,
+	/// how the serial is counted
+	pub serial: SerialFormat,
+	pub refresh: u32,
+	pub retry: u32,
+	pub expire: u32,
+	pub minimum: u32,
+	/// ttl of the SOA and NS records
+	pub ttl: u32,
+}
+
+impl Default for Soa {
+	fn default() -> Self {
+		Self {
+			mname: None,
+			rname: None,
+			serial: SerialFormat::Unixtime,
+			refresh: 3600,
+			retry: 600,
+			expire: 2600,
+			minimum: 30,
+			ttl: 30,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Dnssec {
+	/// days a signature is valid
+	pub signature_validity: u32,
+	/// days before the zone signing key is replaced
+	pub zsk_lifetime: u32,
+}
+
+impl Default for Dnssec {
+	fn default() -> Self {
+		Self {
+			signature_validity: 14,
+			zsk_lifetime: 90,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialFormat {
+	/// seconds since 1970, then counted up
+	Unixtime,
+	/// YYYYMMDDnn, nn counts the changes of the day
+	Date,
+}
+
+impl SerialFormat {
+	/// serial of a zone that has none yet
+	pub fn initial(&self) -> u32 {
+		let now = Utc::now();
+		match self {
+			Self::Unixtime => now.timestamp() as u32,
+			Self::Date => now.format("%Y%m%d").to_string().parse::<u32>().unwrap() * 100,
+		}
+	}
+
+	/// the serial after `current`. Never goes backwards.
+	pub fn next(&self, current: u32) -> u32 {
+		match self {
+			Self::Unixtime => current.wrapping_add(1),
+			// after 99 changes a day the serial moves into the next day
+			Self::Date => current.wrapping_add(1).max(self.initial()),
+		}
+	}
+}
+
+/// converts a mail address to the domain name notation of the SOA
+/// record (RFC 1035 section 8). Dots in the local part are escaped.
+pub fn rname_from_mail(rname: &str) -> Result<String, String> {
+	if rname.is_empty() || rname.chars().any(|c| c.is_whitespace()) {
+		return Err(format!("invalid rname {:?}", rname));
+	}
+
+	let name = match rname.split_once('@') {
+		None => rname.to_string(),
+		Some((local, domain)) => {
+			if local.is_empty() || domain.is_empty() || domain.contains('@') {
+				return Err(format!("invalid mail address {:?}", rname));
+			}
+			format!("{}.{}", local.replace('.', "\\."), domain)
+		}
+	};
+
+	if name.trim_end_matches('.').split('.').any(|l| l.is_empty()) {
+		return Err(format!("invalid rname {:?}", rname));
+	}
+
+	Ok(format!("{}.", name.trim_end_matches('.')))
+}
+
+fn deserialize_rname<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	match Option::<String>::deserialize(deserializer)? {
+		Some(r) => rname_from_mail(&r).map(Some).map_err(de::Error::custom),
+		None => Ok(None),
+	}
 }
 
 
@@ -84,3 +464,42 @@ where
 
     deserializer.deserialize_any(DurationDeserializer(PhantomData))
 }
+
+
+
+#[test]
+fn networks() {
+	let nets = vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string(), "192.168.1.1".to_string()];
+
+	assert!(ip_in_networks("10.1.2.3".parse().unwrap(), &nets));
+	assert!(ip_in_networks("::ffff:10.1.2.3".parse().unwrap(), &nets));
+	assert!(ip_in_networks("fd12::1".parse().unwrap(), &nets));
+	assert!(ip_in_networks("192.168.1.1".parse().unwrap(), &nets));
+	assert!(!ip_in_networks("192.168.1.2".parse().unwrap(), &nets));
+	assert!(!ip_in_networks("11.0.0.1".parse().unwrap(), &nets));
+	assert!(ip_in_networks("1.2.3.4".parse().unwrap(), &["0.0.0.0/0".to_string()]));
+}
+
+
+#[test]
+fn rname_notation() {
+	assert_eq!(rname_from_mail("hostmaster@ffhl.de").unwrap(), "hostmaster.ffhl.de.");
+	assert_eq!(rname_from_mail("john.doe@ffhl.de.").unwrap(), "john\\.doe.ffhl.de.");
+	assert_eq!(rname_from_mail("hostmaster.ffhl.de.").unwrap(), "hostmaster.ffhl.de.");
+	assert!(rname_from_mail("@ffhl.de").is_err());
+	assert!(rname_from_mail("a@b@ffhl.de").is_err());
+	assert!(rname_from_mail("host master@ffhl.de").is_err());
+	assert!(rname_from_mail("hostmaster..ffhl.de").is_err());
+}
+
+
+#[test]
+fn serial_format() {
+	let date = SerialFormat::Date.initial();
+	assert_eq!(date % 100, 0);
+	assert_eq!(SerialFormat::Date.next(date), date + 1);
+	// a serial below the date of today, eg. a unix time, moves up to it
+	assert_eq!(SerialFormat::Date.next(1_000_000), date);
+	assert_eq!(SerialFormat::Date.next(1_800_000_000), date);
+	assert_eq!(SerialFormat::Unixtime.next(5), 6);
+}
@@ -0,0 +1,139 @@
+use super::{sqlite, Database};
+use chrono::Utc;
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+const SNAPSHOT_PREFIX: &str = "ffdyndns-";
+const SNAPSHOT_SUFFIX: &str = ".db";
+
+
+/// writes a snapshot of the database into `dir` while it is in use
+/// and removes the oldest snapshots beyond `keep`
+pub fn backup(db: &Database, dir: &Path, keep: usize) -> Result<PathBuf, String> {
+	fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+
+	// the names sort by time
+	let path = loop {
+		let time = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+		let path = dir.join(format!("{}{}{}", SNAPSHOT_PREFIX, time, SNAPSHOT_SUFFIX));
+		if !path.exists() {
+			break path;
+		}
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	};
+
+	// a snapshot that was not written completely never has the final name
+	let tmp = path.with_extension("tmp");
+	let _ = fs::remove_file(&tmp);
+	db.store.snapshot(&tmp)?;
+	fs::rename(&tmp, &path).map_err(|e| format!("cannot rename {}: {}", tmp.display(), e))?;
+
+	for old in snapshots(dir)?.iter().rev().skip(keep.max(1)) {
+		match fs::remove_file(old) {
+			Ok(()) => info!("removed old snapshot {}", old.display()),
+			Err(e) => warn!("cannot remove old snapshot {}: {}", old.display(), e),
+		}
+	}
+
+	Ok(path)
+}
+
+
+/// the snapshots in `dir`, oldest first
+pub fn snapshots(dir: &Path) -> Result<Vec<PathBuf>, String> {
+	let entries = fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+
+	let mut snapshots: Vec<PathBuf> = entries
+		.filter_map(|e| e.ok())
+		.map(|e| e.path())
+		.filter(|p| {
+			let name = p.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+			name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX)
+		})
+		.collect();
+	snapshots.sort();
+
+	Ok(snapshots)
+}
+
+
+/// checks a snapshot and returns the number of domains in it
+pub fn check(snapshot: &Path) -> Result<usize, String> {
+	sqlite::check_snapshot(snapshot).map_err(|e| format!("{} is not a usable snapshot: {}", snapshot.display(), e))
+}
+
+
+/// replaces the database at `target` with a snapshot and returns the
+/// number of domains. The server must not be running. The replaced
+/// database is kept next to it.
+pub fn restore(snapshot: &Path, target: &Path) -> Result<usize, String> {
+	let count = check(snapshot)?;
+
+	let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+	let tmp = target.with_file_name(format!("{}.restore", name));
+	fs::copy(snapshot, &tmp).map_err(|e| format!("cannot copy {}: {}", snapshot.display(), e))?;
+
+	if target.exists() {
+		let mut saved = target.with_file_name(format!("{}.pre-restore.bak", name));
+		let mut i = 1;
+		while saved.exists() {
+			saved = target.with_file_name(format!("{}.pre-restore.{}.bak", name, i));
+			i += 1;
+		}
+
+		// the copy includes what is still in the write-ahead log
+		Database::open(target)?.store.snapshot(&saved)?;
+		info!("saved the replaced database to {}", saved.display());
+
+		for suffix in &["", "-wal", "-shm"] {
+			let f = target.with_file_name(format!("{}{}", name, suffix));
+			if f.exists() {
+				fs::remove_file(&f).map_err(|e| format!("cannot remove {}: {}", f.display(), e))?;
+			}
+		}
+	}
+
+	fs::rename(&tmp, target).map_err(|e| format!("cannot move the snapshot to {}: {}", target.display(), e))?;
+	// brings an older snapshot up to the current schema
+	Database::open(target)?;
+
+	Ok(count)
+}
+
+
+
+#[test]
+fn backup_and_restore() {
+	use super::Domain;
+
+	let (tmp, db) = super::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let path = tmp.path();
+	let file = path.join("ffdyndns.db");
+	let dir = path.join("backup");
+
+	let first = backup(&db, &dir, 2).unwrap();
+	assert_eq!(check(&first), Ok(1));
+	db.insert_new_domain(&Domain::new("bar.ffhl.de.".to_string(), chrono::Duration::hours(1))).unwrap();
+	backup(&db, &dir, 2).unwrap();
+	let last = backup(&db, &dir, 2).unwrap();
+
+	// only the newest are kept
+	let kept = snapshots(&dir).unwrap();
+	assert_eq!(kept.len(), 2);
+	assert!(!kept.contains(&first));
+	assert_eq!(kept.last(), Some(&last));
+
+	// broken snapshots are refused and change nothing
+	let broken = path.join("broken.db");
+	fs::write(&broken, b"not a database").unwrap();
+	assert!(check(&broken).is_err());
+	assert!(restore(&broken, &file).is_err());
+
+	drop(db);
+	assert_eq!(restore(&last, &file), Ok(2));
+	assert!(path.join("ffdyndns.db.pre-restore.bak").exists());
+	assert_eq!(Database::open(&file).unwrap().get_all().unwrap().len(), 2);
+}
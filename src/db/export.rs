@@ -0,0 +1,258 @@
+use super::{Database, Domain};
+use crate::domain::Dname;
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+
+// An export is a JSON Lines file. The first line is the header, every
+// following line is one domain exactly as `Domain` is serialized:
+//
+// {"format":"ffdyndns-export","version":1,"exported":"2021-06-01T12:00:00Z"}
+// {"domainname":"foo.ffhl.de.","token":"…","lastupdate":"…","valid_until":"…","ipv4":"10.0.0.1","ipv6":null,"txt":[]}
+
+
+const FORMAT: &str = "ffdyndns-export";
+/// version of the export format, raised when old versions cannot read it anymore
+pub const EXPORT_VERSION: u32 = 1;
+
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+	format: String,
+	version: u32,
+	exported: DateTime<Utc>,
+}
+
+
+/// what to do with domains that already exist
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conflict {
+	/// keep the existing domain
+	Skip,
+	/// replace the existing domain with the imported one
+	Overwrite,
+	/// import nothing if any domain exists
+	Fail,
+}
+
+impl FromStr for Conflict {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"skip" => Ok(Self::Skip),
+			"overwrite" => Ok(Self::Overwrite),
+			"fail" => Ok(Self::Fail),
+			_ => Err(format!("unknown conflict mode: {}", s)),
+		}
+	}
+}
+
+
+/// the names of the domains by what the import did, or would do, with them
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+	pub added: Vec<String>,
+	pub overwritten: Vec<String>,
+	pub skipped: Vec<String>,
+}
+
+
+/// writes all domains and returns their number.
+/// Domains that cannot be read are logged and left out.
+pub fn export<W: Write>(db: &Database, mut out: W) -> Result<usize, String> {
+	let err = |e: std::io::Error| format!("cannot write export: {}", e);
+	let domains = db.get_all()?;
+
+	let header = Header {
+		format: FORMAT.to_string(),
+		version: EXPORT_VERSION,
+		exported: Utc::now(),
+	};
+	writeln!(out, "{}", json::to_string(&header).map_err(|e| e.to_string())?).map_err(err)?;
+
+	for d in &domains {
+		writeln!(out, "{}", json::to_string(d).map_err(|e| e.to_string())?).map_err(err)?;
+	}
+	out.flush().map_err(err)?;
+
+	Ok(domains.len())
+}
+
+
+/// reads an export into the database. The whole file is checked before
+/// it is written in one transaction, with `dry_run` nothing is written.
+/// Accounts are not exported, domains whose owner has no account
+/// here are imported without owner.
+pub fn import<R: BufRead>(db: &Database, input: R, conflict: Conflict, dry_run: bool) -> Result<ImportReport, String> {
+	let mut domains = read_export(input)?;
+	for d in &mut domains {
+		if let Some(owner) = &d.owner {
+			if db.get_account(owner)?.is_none() {
+				warn!("{} is imported without its owner {}, the account does not exist", d.domainname, owner);
+				d.owner = None;
+			}
+		}
+	}
+
+	let mut report = ImportReport::default();
+	let mut existing = HashSet::new();
+	for d in &domains {
+		if db.get_domain(&d.domainname)?.is_none() {
+			report.added.push(d.domainname.clone());
+			continue;
+		}
+
+		existing.insert(d.domainname.clone());
+		match conflict {
+			Conflict::Skip => report.skipped.push(d.domainname.clone()),
+			Conflict::Overwrite => report.overwritten.push(d.domainname.clone()),
+			Conflict::Fail => (),
+		}
+	}
+
+	if conflict == Conflict::Fail && !existing.is_empty() {
+		let mut names: Vec<String> = existing.into_iter().collect();
+		names.sort();
+		return Err(format!("domains already exist: {}", names.join(", ")));
+	}
+
+	if dry_run {
+		return Ok(report);
+	}
+
+	// a name taken meanwhile fails the whole import
+	let (new, old): (Vec<Domain>, Vec<Domain>) = domains.into_iter()
+		.partition(|d| !existing.contains(&d.domainname));
+	let replace = if conflict == Conflict::Overwrite { old } else { Vec::new() };
+	db.store.import_domains(&new, &replace)?;
+
+	Ok(report)
+}
+
+
+/// the domains of an export, after checking the header and every line
+fn read_export<R: BufRead>(input: R) -> Result<Vec<Domain>, String> {
+	let mut lines = input.lines().enumerate()
+		.map(|(i, l)| l.map(|l| (i + 1, l)).map_err(|e| format!("cannot read export: {}", e)))
+		.filter(|l| !matches!(l, Ok((_, l)) if l.trim().is_empty()));
+
+	let header: Header = match lines.next() {
+		Some(l) => json::from_str(&l?.1).map_err(|e| format!("invalid header: {}", e))?,
+		None => return Err("the export is empty".to_string()),
+	};
+	if header.format != FORMAT {
+		return Err(format!("not an ffdyndns export: {}", header.format));
+	}
+	if header.version > EXPORT_VERSION {
+		return Err(format!(
+			"the export has version {}, this version of ffdyndns only reads up to {}",
+			header.version, EXPORT_VERSION
+		));
+	}
+
+	let mut domains = Vec::new();
+	let mut names = HashSet::new();
+	for l in lines {
+		let (n, line) = l?;
+		let d: Domain = json::from_str(&line).map_err(|e| format!("line {}: invalid domain: {}", n, e))?;
+
+		if !d.domainname.ends_with('.') || !Dname::new(d.domainname.clone()).is_valid() {
+			return Err(format!("line {}: invalid domain name: {}", n, d.domainname));
+		}
+		if !names.insert(d.domainname.to_lowercase()) {
+			return Err(format!("line {}: {} appears twice", n, d.domainname));
+		}
+
+		domains.push(d);
+	}
+
+	Ok(domains)
+}
+
+
+
+#[test]
+fn export_and_import() {
+	let tmp = tempfile::tempdir().unwrap();
+	let path = tmp.path();
+	let from = Database::open(path.join("from.db")).unwrap();
+	let to = Database::open(path.join("to.db")).unwrap();
+
+	let account = super::Account {
+		name: "alice".to_string(),
+		password: "$rscrypt$0$...".to_string(),
+		created: Utc::now(),
+	};
+	from.insert_account(&account).unwrap();
+	for name in &["foo.ffhl.de.", "bar.ffhl.de."] {
+		let mut d = Domain::new(name.to_string(), chrono::Duration::hours(1));
+		d.ipv6 = Some("fd00::1".parse().unwrap());
+		d.txt = vec!["hello".to_string()];
+		d.owner = Some("alice".to_string());
+		from.insert_new_domain(&d).unwrap();
+	}
+
+	let mut out = Vec::new();
+	assert_eq!(export(&from, &mut out), Ok(2));
+	assert!(String::from_utf8_lossy(&out).starts_with("{\"format\":\"ffdyndns-export\",\"version\":1,"));
+
+	let existing = Domain::new("foo.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	to.insert_new_domain(&existing).unwrap();
+	let token = |db: &Database| db.get_domain(&"foo.ffhl.de.".to_string()).unwrap().unwrap().token;
+
+	// nothing is written on a conflict, or in a dry run
+	assert!(import(&to, out.as_slice(), Conflict::Fail, false).is_err());
+	let report = import(&to, out.as_slice(), Conflict::Overwrite, true).unwrap();
+	assert_eq!(report.added, vec!["bar.ffhl.de."]);
+	assert_eq!(report.overwritten, vec!["foo.ffhl.de."]);
+	assert_eq!(to.get_all().unwrap().len(), 1);
+
+	let report = import(&to, out.as_slice(), Conflict::Skip, false).unwrap();
+	assert_eq!(report.skipped, vec!["foo.ffhl.de."]);
+	assert_eq!(token(&to), existing.token);
+	let bar = to.get_domain(&"bar.ffhl.de.".to_string()).unwrap().unwrap();
+	assert_eq!(bar.txt, vec!["hello".to_string()]);
+	assert_eq!(bar.ipv6, Some("fd00::1".parse().unwrap()));
+	// the account is not exported
+	assert_eq!(bar.owner, None);
+
+	import(&to, out.as_slice(), Conflict::Overwrite, false).unwrap();
+	assert_eq!(token(&to), token(&from));
+	to.insert_account(&account).unwrap();
+	import(&to, out.as_slice(), Conflict::Overwrite, false).unwrap();
+	assert_eq!(to.get_by_owner("alice").unwrap().len(), 2);
+
+	// the writes happen in one transaction, a name taken
+	// meanwhile leaves everything as it was
+	let taken = Domain::new("taken.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	to.insert_new_domain(&taken).unwrap();
+	let new = Domain::new("new.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	let mut replaced = to.get_domain(&"bar.ffhl.de.".to_string()).unwrap().unwrap();
+	replaced.txt = vec!["replaced".to_string()];
+	assert!(matches!(to.store.import_domains(&[new, taken], &[replaced]), Err(super::Error::Exists(_))));
+	assert!(to.get_domain(&"new.ffhl.de.".to_string()).unwrap().is_none());
+	assert_eq!(to.get_domain(&"bar.ffhl.de.".to_string()).unwrap().unwrap().txt, vec!["hello".to_string()]);
+
+	// newer versions and broken lines are refused
+	assert!(import(&to, &b"{\"format\":\"ffdyndns-export\",\"version\":2,\"exported\":\"2021-06-01T12:00:00Z\"}\n"[..], Conflict::Skip, true).is_err());
+	let mut broken = out.clone();
+	broken.extend_from_slice(b"{\"domainname\":\"x\"}\n");
+	assert!(import(&to, broken.as_slice(), Conflict::Skip, true).unwrap_err().starts_with("line 4:"));
+
+	// and so are names that would break the zone
+	let mut injected = out.clone();
+	let mut d = Domain::new("evil.ffhl.de. 60 IN A 192.0.2.1\nx.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	injected.extend_from_slice(format!("{}\n", json::to_string(&d).unwrap()).as_bytes());
+	assert!(import(&to, injected.as_slice(), Conflict::Skip, true).unwrap_err().starts_with("line 4:"));
+	d.domainname = format!("{}.ffhl.de.", "a".repeat(64));
+	let mut injected = out.clone();
+	injected.extend_from_slice(format!("{}\n", json::to_string(&d).unwrap()).as_bytes());
+	assert!(import(&to, injected.as_slice(), Conflict::Skip, true).is_err());
+}
@@ -0,0 +1,649 @@
+pub mod backup;
+pub mod export;
+mod schema;
+mod sled_import;
+mod sqlite;
+
+pub use sled_import::import_sled;
+
+use crate::config;
+use crate::domain::Dname;
+use std::path::Path;
+use chrono::{Utc, DateTime, Duration};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use serde_json as json;
+use std::fmt;
+use crate::ffdyndns::Token;
+
+/// number of changes kept in the journal of a zone
+const JOURNAL_SIZE: usize = 1000;
+
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Error {
+	/// the database is busy or cannot be opened, retrying later may help
+	Unavailable(String),
+	/// a stored value cannot be read
+	Corrupt(String),
+	/// the domain was removed in the meantime
+	NotFound(String),
+	/// the domain was registered in the meantime
+	Exists(String),
+	Failed(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Unavailable(e) => write!(f, "database unavailable: {}", e),
+			Self::Corrupt(e) => write!(f, "corrupt database entry: {}", e),
+			Self::NotFound(d) => write!(f, "domain {} does not exist", d),
+			Self::Exists(d) => write!(f, "domain {} already exists", d),
+			Self::Failed(e) => write!(f, "database error: {}", e),
+		}
+	}
+}
+
+impl From<rusqlite::Error> for Error {
+	fn from(e: rusqlite::Error) -> Self {
+		use rusqlite::ErrorCode::{DatabaseBusy, DatabaseLocked, CannotOpen};
+		match &e {
+			rusqlite::Error::SqliteFailure(f, _) if matches!(f.code, DatabaseBusy | DatabaseLocked | CannotOpen) => {
+				Self::Unavailable(e.to_string())
+			}
+			rusqlite::Error::FromSqlConversionFailure(..) | rusqlite::Error::InvalidColumnType(..) => {
+				Self::Corrupt(e.to_string())
+			}
+			_ => Self::Failed(e.to_string()),
+		}
+	}
+}
+
+impl From<json::Error> for Error {
+	fn from(e: json::Error) -> Self {
+		Self::Corrupt(e.to_string())
+	}
+}
+
+/// the backends report errors as strings
+impl From<Error> for String {
+	fn from(e: Error) -> Self {
+		e.to_string()
+	}
+}
+
+
+/// where the domains and the state of the zones are kept
+pub trait Storage: Send + Sync {
+	/// inserts a domain, `Error::Exists` if the name is taken
+	fn insert_domain(&self, d: &Domain) -> Result<(), Error>;
+	/// inserts `new` and replaces `replace` in one transaction. Nothing
+	/// is written if one of `new` is taken by then, `Error::Exists`.
+	fn import_domains(&self, new: &[Domain], replace: &[Domain]) -> Result<(), Error>;
+	fn get_domain(&self, name: &str) -> Result<Option<Domain>, Error>;
+	/// reads, changes and writes a domain in one transaction. Nothing
+	/// is written if `f` returns false. `None` if the domain does not exist.
+	fn modify_domain(&self, name: &str, f: &mut dyn FnMut(&mut Domain) -> bool) -> Result<Option<Domain>, Error>;
+	/// removes a domain with its history and returns it, `None` if it does not exist
+	fn remove_domain(&self, name: &str) -> Result<Option<Domain>, Error>;
+	/// removes the records of the domains that expired before `now` and
+	/// returns the domains with the removed records. Suspended domains
+	/// and domains without records are left alone.
+	fn clear_expired(&self, now: DateTime<Utc>) -> Result<Vec<Domain>, Error>;
+	/// removes the domains that expired before `time` with their
+	/// history and returns them. Suspended domains are kept.
+	fn remove_expired(&self, time: DateTime<Utc>) -> Result<Vec<Domain>, Error>;
+	/// all domains. Domains that cannot be read are reported and skipped.
+	fn domains(&self) -> Result<Vec<Domain>, Error>;
+	/// the domains directly below a suffix
+	fn domains_by_suffix(&self, suffix: &str) -> Result<Vec<Domain>, Error>;
+	/// the domains of an account
+	fn domains_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error>;
+
+	/// inserts an account, `Error::Exists` if the name is taken
+	fn insert_account(&self, account: &Account) -> Result<(), Error>;
+	fn get_account(&self, name: &str) -> Result<Option<Account>, Error>;
+
+	fn get_serial(&self, zone: &str) -> Result<Option<u32>, Error>;
+	/// replaces the serial of a zone with `next(current)` in one step
+	fn update_serial(&self, zone: &str, next: &dyn Fn(Option<u32>) -> u32) -> Result<u32, Error>;
+
+	/// the serial of a zone and all records published in it, read at once
+	fn published_zone(&self, zone: &str) -> Result<(Option<u32>, Vec<ZoneRecord>), Error>;
+	/// the serial of a zone and the published records of one name in it
+	fn published_name(&self, zone: &str, name: &str) -> Result<(Option<u32>, Vec<ZoneRecord>), Error>;
+	/// replaces the published records of the names of a zone. If that
+	/// changes anything, the serial goes from the current one (`initial`
+	/// if there is none) to `next` of it and the change is added to the
+	/// journal, all in one transaction.
+	fn publish(&self, zone: &str, records: &[(String, Vec<ZoneRecord>)], initial: u32, next: &dyn Fn(u32) -> u32, keep: usize) -> Result<Option<JournalEntry>, Error>;
+
+	/// the journal of a zone, oldest change first
+	fn journal(&self, zone: &str) -> Result<Vec<JournalEntry>, Error>;
+
+	/// appends to the history and returns the id of the entry
+	fn history_add(&self, entry: &HistoryEntry) -> Result<i64, Error>;
+	fn history_set_status(&self, id: i64, status: &UpdateStatus) -> Result<(), Error>;
+	/// the last `limit` changes of a domain, newest first
+	fn history(&self, name: &str, limit: usize) -> Result<Vec<HistoryEntry>, Error>;
+	/// removes the entries older than `time` and returns their number
+	fn history_remove_before(&self, time: DateTime<Utc>) -> Result<usize, Error>;
+
+	/// writes a consistent copy of everything to a new file
+	fn snapshot(&self, path: &Path) -> Result<(), Error>;
+	/// writes everything taken over from another database in one
+	/// transaction, existing domains are replaced. The journals
+	/// keep the last `keep` entries.
+	fn import(&self, data: &Import, keep: usize) -> Result<(), Error>;
+}
+
+
+#[derive(Clone)]
+pub struct Database {
+	store: Arc<dyn Storage>,
+}
+
+
+
+impl Database {
+	/// opens the SQLite database at `path`, it is created if it does not
+	/// exist and migrated if it has an older schema
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		Ok(Self { store: Arc::new(sqlite::Sqlite::open(path.as_ref())?) })
+	}
+
+	/// `Error::Exists` if the name is already taken
+	pub fn insert_new_domain(&self, d: &Domain) -> Result<(), Error> {
+		self.store.insert_domain(d)
+	}
+
+
+	pub fn get_domain(&self, domain: &String) -> Result<Option<Domain>, Error> {
+		self.store.get_domain(domain)
+	}
+
+	/// removes a domain and returns it
+	pub fn remove_domain(&self, domain: &str) -> Result<Option<Domain>, Error> {
+		self.store.remove_domain(domain)
+	}
+
+
+	/// changes a domain without other changes getting in between.
+	/// An error of `f` aborts the change and is returned.
+	pub fn modify_domain<E, F>(&self, domain: &str, f: F) -> Result<Domain, E>
+	where
+		E: From<Error>,
+		F: FnOnce(&mut Domain) -> Result<(), E>,
+	{
+		let mut f = Some(f);
+		let mut failed = None;
+		let d = self.store.modify_domain(domain, &mut |d| match f.take().map(|f| f(d)) {
+			Some(Err(e)) => {
+				failed = Some(e);
+				false
+			}
+			_ => true,
+		})?;
+
+		match (failed, d) {
+			(Some(e), _) => Err(e),
+			(None, Some(d)) => Ok(d),
+			(None, None) => Err(Error::NotFound(domain.to_string()).into()),
+		}
+	}
+
+	/// removes the records of the expired domains, the names stay
+	/// taken during the quarantine. Returns the domains with their
+	/// old records.
+	pub fn clear_expired(&self) -> Result<Vec<Domain>, Error> {
+		self.store.clear_expired(Utc::now())
+	}
+
+	/// removes the domains that expired longer than
+	/// `quarantine` ago and returns them
+	pub fn remove_expired(&self, quarantine: Duration) -> Result<Vec<Domain>, Error> {
+		self.store.remove_expired(Utc::now() - quarantine)
+	}
+
+	/// all domains, the ones that cannot be read are skipped
+	pub fn get_all(&self) -> Result<Vec<Domain>, Error> {
+		self.store.domains()
+	}
+
+	/// the dynamic domains of a zone
+	pub fn get_by_suffix(&self, suffix: &str) -> Result<Vec<Domain>, Error> {
+		self.store.domains_by_suffix(suffix)
+	}
+
+	/// the domains owned by an account
+	pub fn get_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error> {
+		self.store.domains_by_owner(owner)
+	}
+
+	/// `Error::Exists` if the name is already taken
+	pub fn insert_account(&self, account: &Account) -> Result<(), Error> {
+		self.store.insert_account(account)
+	}
+
+	pub fn get_account(&self, name: &str) -> Result<Option<Account>, Error> {
+		self.store.get_account(name)
+	}
+
+
+	/// current SOA serial of a zone. Starts with the
+	/// initial serial of the configured format.
+	pub fn get_serial(&self, zone: &config::Domain) -> Result<u32, Error> {
+		Ok(self.store.get_serial(&zone_key(&zone.name))?.unwrap_or_else(|| zone.soa.serial.initial()))
+	}
+
+	/// increases the serial of a zone and returns the new one
+	pub fn bump_serial(&self, zone: &config::Domain) -> Result<u32, Error> {
+		let current = self.get_serial(zone)?;
+		self.store.update_serial(&zone_key(&zone.name), &|old| zone.soa.serial.next(old.unwrap_or(current)))
+	}
+
+	/// the serial of a zone and the records the secondaries have with it
+	pub fn published_zone(&self, zone: &config::Domain) -> Result<(u32, Vec<ZoneRecord>), Error> {
+		let (serial, records) = self.store.published_zone(&zone_key(&zone.name))?;
+		Ok((serial.unwrap_or_else(|| zone.soa.serial.initial()), records))
+	}
+
+	/// the serial of a zone and the published records of one name in it
+	pub fn published_name(&self, zone: &config::Domain, name: &str) -> Result<(u32, Vec<ZoneRecord>), Error> {
+		let (serial, records) = self.store.published_name(&zone_key(&zone.name), &name.to_lowercase())?;
+		Ok((serial.unwrap_or_else(|| zone.soa.serial.initial()), records))
+	}
+
+	/// publishes the records of the names in a zone with a new serial and
+	/// keeps the change in the journal for IXFR. `None` if nothing changed.
+	pub fn publish(&self, zone: &config::Domain, records: &[(String, Vec<ZoneRecord>)]) -> Result<Option<JournalEntry>, Error> {
+		let records: Vec<(String, Vec<ZoneRecord>)> = records.iter()
+			.map(|(name, r)| (name.to_lowercase(), r.clone()))
+			.collect();
+		self.store.publish(
+			&zone_key(&zone.name),
+			&records,
+			zone.soa.serial.initial(),
+			&|s| zone.soa.serial.next(s),
+			JOURNAL_SIZE,
+		)
+	}
+
+	/// all changes of a zone since the given serial, in order.
+	/// `None` if the journal does not reach back that far.
+	pub fn journal_since(&self, zone: &str, serial: u32) -> Result<Option<Vec<JournalEntry>>, Error> {
+		let entries: Vec<JournalEntry> = self.store.journal(&zone_key(zone))?
+			.into_iter()
+			.skip_while(|e: &JournalEntry| e.serial_from != serial)
+			.collect();
+
+		let contiguous = entries.windows(2).all(|w| w[0].serial_to == w[1].serial_from);
+		if entries.is_empty() || !contiguous {
+			return Ok(None);
+		}
+
+		Ok(Some(entries))
+	}
+
+	/// records a change of a domain, returns the id to set the status later
+	pub fn history_add(&self, entry: &HistoryEntry) -> Result<i64, Error> {
+		self.store.history_add(entry)
+	}
+
+	pub fn history_set_status(&self, id: i64, status: &UpdateStatus) -> Result<(), Error> {
+		self.store.history_set_status(id, status)
+	}
+
+	/// the last `limit` changes of a domain, newest first
+	pub fn history(&self, domain: &str, limit: usize) -> Result<Vec<HistoryEntry>, Error> {
+		self.store.history(domain, limit)
+	}
+
+	/// removes the history older than `days`
+	pub fn expire_history(&self, days: i64) -> Result<usize, Error> {
+		self.store.history_remove_before(Utc::now() - Duration::days(days))
+	}
+}
+
+
+/// zones are kept lowercase with a trailing dot
+fn zone_key(zone: &str) -> String {
+	format!("{}.", zone.trim_end_matches('.').to_lowercase())
+}
+
+
+/// a record as published in the zone
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum ZoneRecord {
+	A(String, Ipv4Addr),
+	Aaaa(String, Ipv6Addr),
+	Txt(String, String),
+}
+
+impl ZoneRecord {
+	pub fn name(&self) -> &str {
+		match self {
+			Self::A(name, _) | Self::Aaaa(name, _) | Self::Txt(name, _) => name,
+		}
+	}
+}
+
+
+/// the domains and the state of the zones of another database
+#[derive(Debug, Default)]
+pub struct Import {
+	pub domains: Vec<Domain>,
+	/// the serial of every zone
+	pub serials: Vec<(String, u32)>,
+	/// the published records of every name
+	pub published: Vec<(String, Vec<ZoneRecord>)>,
+	/// the journal entries of every zone, oldest first
+	pub journal: Vec<(String, JournalEntry)>,
+}
+
+
+/// a change of the records of a domain
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+	pub domainname: String,
+	pub time: DateTime<Utc>,
+	/// where the change came from, `None` for changes by ffdyndns itself
+	pub source: Option<IpAddr>,
+	/// fingerprint of the token that was used
+	pub token: Option<String>,
+	pub old: Vec<ZoneRecord>,
+	pub new: Vec<ZoneRecord>,
+	pub status: UpdateStatus,
+}
+
+
+/// whether a change made it into the DNS server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateStatus {
+	Pending,
+	Ok,
+	Failed(String),
+}
+
+
+/// changes to a zone from one serial to the next
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JournalEntry {
+	pub serial_from: u32,
+	pub serial_to: u32,
+	#[serde(default)]
+	pub removed: Vec<ZoneRecord>,
+	#[serde(default)]
+	pub added: Vec<ZoneRecord>,
+}
+
+
+/// why and since when a domain is suspended. A suspended domain has
+/// no records, cannot be updated and does not expire, so nobody else
+/// can register the name.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Suspension {
+	pub reason: String,
+	pub since: DateTime<Utc>,
+}
+
+
+/// the mail address of the owner of a domain. Only the verification
+/// is sent before the owner followed the link in it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Email {
+	pub address: String,
+	#[serde(default)]
+	pub verified: bool,
+	/// when the last expiry reminder was sent
+	#[serde(default)]
+	pub reminded: Option<DateTime<Utc>>,
+}
+
+
+/// a user who owns domains, the name is case insensitive
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Account {
+	pub name: String,
+	/// scrypt hash of the password
+	#[serde(skip)]
+	pub password: String,
+	pub created: DateTime<Utc>,
+}
+
+
+/// a registered domain
+///
+/// The JSON form is read from older sled databases, fields added
+/// later need `#[serde(default)]` so those can still be read. New
+/// columns in the database need a migration in `schema::MIGRATIONS`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Domain {
+	pub domainname: String,
+	pub token: Token,
+	pub lastupdate: DateTime<Utc>,
+	pub valid_until: DateTime<Utc>,
+	pub ipv4: Option<Ipv4Addr>,
+	pub ipv6: Option<Ipv6Addr>,
+	#[serde(default)]
+	pub txt: Vec<String>,
+	/// set by an admin, the records cannot be changed with the token
+	#[serde(default)]
+	pub locked: bool,
+	/// set by an admin for abuse, see `Suspension`
+	#[serde(default)]
+	pub suspended: Option<Suspension>,
+	/// gets the expiry reminders, see `Email`
+	#[serde(default)]
+	pub email: Option<Email>,
+	/// name of the account the domain belongs to
+	#[serde(default)]
+	pub owner: Option<String>,
+}
+
+impl Domain {
+	/// all records this domain has in the zone
+	pub fn zone_records(&self) -> Vec<ZoneRecord> {
+		let name = self.domainname.to_lowercase();
+		let mut records = Vec::new();
+
+		if let Some(a) = self.ipv4 {
+			records.push(ZoneRecord::A(name.clone(), a));
+		}
+		if let Some(a) = self.ipv6 {
+			records.push(ZoneRecord::Aaaa(name.clone(), a));
+		}
+		for txt in &self.txt {
+			records.push(ZoneRecord::Txt(name.clone(), txt.clone()));
+		}
+
+		records
+	}
+
+	pub fn new_with_token(domain: &Dname, token: String, validity: Duration) -> Self {
+		Self {
+			domainname: domain.to_string(),
+			token: token,
+			lastupdate: Utc::now(),
+			valid_until: Utc::now() + validity,
+			ipv4: None,
+			ipv6: None,
+			txt: Vec::new(),
+			locked: false,
+			suspended: None,
+			email: None,
+			owner: None,
+		}
+	}
+
+	/// creates a new Domain object and generates a random token
+	#[allow(dead_code)]
+	pub fn new(domain: String, validity: Duration) -> Self {
+		Self {
+			domainname: domain,
+			token: crate::ffdyndns::generate_token(),
+			lastupdate: Utc::now(),
+			valid_until: Utc::now() + validity,
+			ipv4: None,
+			ipv6: None,
+			txt: Vec::new(),
+			locked: false,
+			suspended: None,
+			email: None,
+			owner: None,
+		}
+	}
+}
+
+
+
+/// a database in a new temporary directory with domains of the given
+/// names, valid for `validity`. For the tests, the directory goes away
+/// with the `TempDir`.
+#[cfg(test)]
+pub fn test_database(names: &[&str], validity: Duration) -> (tempfile::TempDir, Database) {
+	let tmp = tempfile::tempdir().unwrap();
+	let db = Database::open(tmp.path().join("ffdyndns.db")).unwrap();
+	for name in names {
+		db.insert_new_domain(&Domain::new(name.to_string(), validity)).unwrap();
+	}
+
+	(tmp, db)
+}
+
+
+
+#[test]
+fn concurrent_changes() {
+	let (_tmp, db) = test_database(&["foo.ffhl.de."], Duration::hours(1));
+	let d = db.get_domain(&"foo.ffhl.de.".to_string()).unwrap().unwrap();
+	assert_eq!(db.insert_new_domain(&d), Err(Error::Exists(d.domainname.clone())));
+
+	// no change gets lost
+	let threads: Vec<_> = (0..8).map(|i| {
+		let db = db.clone();
+		std::thread::spawn(move || {
+			for j in 0..10 {
+				db.modify_domain("foo.ffhl.de.", |d| {
+					d.txt.push(format!("{}-{}", i, j));
+					Ok::<_, Error>(())
+				}).unwrap();
+			}
+		})
+	}).collect();
+	threads.into_iter().for_each(|t| t.join().unwrap());
+	assert_eq!(db.get_domain(&d.domainname).unwrap().unwrap().txt.len(), 80);
+
+	// an error leaves the domain as it was
+	let r = db.modify_domain("foo.ffhl.de.", |d| {
+		d.txt.clear();
+		Err(Error::Failed("no".to_string()))
+	});
+	assert!(r.is_err());
+	assert_eq!(db.get_domain(&d.domainname).unwrap().unwrap().txt.len(), 80);
+	assert_eq!(db.modify_domain("bar.ffhl.de.", |_| Ok::<_, Error>(())).err(), Some(Error::NotFound("bar.ffhl.de.".to_string())));
+
+	// a domain removed by the cleanup stays removed
+	db.modify_domain("foo.ffhl.de.", |d| {
+		d.valid_until = Utc::now() - Duration::seconds(1);
+		Ok::<_, Error>(())
+	}).unwrap();
+	let removed: Vec<String> = db.remove_expired(Duration::zero()).unwrap().into_iter().map(|d| d.domainname).collect();
+	assert_eq!(removed, vec![d.domainname.clone()]);
+	assert!(db.modify_domain("foo.ffhl.de.", |_| Ok::<_, Error>(())).is_err());
+	assert!(db.get_domain(&d.domainname).unwrap().is_none());
+}
+
+
+
+#[test]
+fn history() {
+	let (_tmp, db) = test_database(&["foo.ffhl.de."], Duration::hours(-1));
+
+	let entry = |time, new: &str| HistoryEntry {
+		domainname: "foo.ffhl.de.".to_string(),
+		time,
+		source: Some("192.0.2.1".parse().unwrap()),
+		token: Some("01234567".to_string()),
+		old: Vec::new(),
+		new: vec![ZoneRecord::A("foo.ffhl.de.".to_string(), new.parse().unwrap())],
+		status: UpdateStatus::Pending,
+	};
+	let old = entry(Utc::now() - Duration::days(40), "10.0.0.1");
+	let new = entry(Utc::now(), "10.0.0.2");
+	db.history_add(&old).unwrap();
+	let id = db.history_add(&new).unwrap();
+	db.history_set_status(id, &UpdateStatus::Failed("refused".to_string())).unwrap();
+
+	let history = db.history("Foo.ffhl.de.", 10).unwrap();
+	assert_eq!(history.len(), 2);
+	assert_eq!(history[0].new, new.new);
+	assert_eq!(history[0].source, new.source);
+	assert_eq!(history[0].status, UpdateStatus::Failed("refused".to_string()));
+	assert_eq!(history[1].status, UpdateStatus::Pending);
+	assert_eq!(db.history("foo.ffhl.de.", 1).unwrap().len(), 1);
+
+	assert_eq!(db.expire_history(30), Ok(1));
+	assert_eq!(db.history("foo.ffhl.de.", 10).unwrap().len(), 1);
+
+	// the history goes with the expired domain
+	assert_eq!(db.remove_expired(Duration::zero()).unwrap().len(), 1);
+	assert!(db.history("foo.ffhl.de.", 10).unwrap().is_empty());
+}
+
+
+
+#[test]
+fn suspended_domains() {
+	let (_tmp, db) = test_database(&["foo.ffhl.de.", "bar.ffhl.de."], Duration::hours(-1));
+	let d = db.modify_domain("foo.ffhl.de.", |d| {
+		d.suspended = Some(Suspension {
+			reason: "phishing".to_string(),
+			since: Utc::now() - Duration::days(1),
+		});
+		Ok::<_, Error>(())
+	}).unwrap();
+
+	// only the domain that is not suspended expires
+	let removed: Vec<String> = db.remove_expired(Duration::zero()).unwrap().into_iter().map(|d| d.domainname).collect();
+	assert_eq!(removed, vec!["bar.ffhl.de."]);
+
+	let stored = db.get_domain(&d.domainname).unwrap().unwrap();
+	assert_eq!(stored.suspended.as_ref().map(|s| s.reason.as_str()), Some("phishing"));
+	assert_eq!(stored.suspended.map(|s| s.since.timestamp()), d.suspended.as_ref().map(|s| s.since.timestamp()));
+	assert_eq!(db.insert_new_domain(&d), Err(Error::Exists(d.domainname.clone())));
+}
+
+
+
+#[test]
+fn quarantine() {
+	let (_tmp, db) = test_database(&["foo.ffhl.de.", "bar.ffhl.de.", "baz.ffhl.de."], Duration::days(-2));
+	let valid = |name, validity| db.modify_domain(name, |d| {
+		d.valid_until = Utc::now() + validity;
+		Ok::<_, Error>(())
+	}).unwrap();
+	valid("bar.ffhl.de.", Duration::days(-10));
+	valid("baz.ffhl.de.", Duration::days(1));
+	let d = db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("192.0.2.1".parse().unwrap());
+		Ok::<_, Error>(())
+	}).unwrap();
+
+	// the records go away at once, the name stays taken
+	let cleared: Vec<Domain> = db.clear_expired().unwrap();
+	assert_eq!(cleared.len(), 1);
+	assert_eq!(cleared[0].ipv4, d.ipv4);
+	assert_eq!(db.get_domain(&d.domainname).unwrap().unwrap().ipv4, None);
+	assert!(db.clear_expired().unwrap().is_empty());
+
+	let removed: Vec<String> = db.remove_expired(Duration::days(5)).unwrap().into_iter().map(|d| d.domainname).collect();
+	assert_eq!(removed, vec!["bar.ffhl.de."]);
+	assert!(db.get_domain(&d.domainname).unwrap().is_some());
+	assert!(db.get_domain(&"baz.ffhl.de.".to_string()).unwrap().is_some());
+}
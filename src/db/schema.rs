@@ -0,0 +1,140 @@
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+
+/// the changes to the schema in order. The schema version stored in the
+/// database is the number of applied migrations, new migrations are only
+/// ever appended.
+pub const MIGRATIONS: &[&str] = &[
+	include_str!("../init.sql"),
+	include_str!("../migrations/0002_history.sql"),
+	include_str!("../migrations/0003_locked.sql"),
+	include_str!("../migrations/0004_suspended.sql"),
+	include_str!("../migrations/0005_email.sql"),
+	include_str!("../migrations/0006_accounts.sql"),
+];
+
+
+/// version of the schema in the database, 0 for a new database
+pub fn version(conn: &Connection) -> rusqlite::Result<usize> {
+	conn.query_row("PRAGMA user_version", [], |r| r.get::<_, i64>(0)).map(|v| v as usize)
+}
+
+
+/// brings the schema up to date. A database that already has data is
+/// backed up next to `path` before the first change.
+pub fn migrate(conn: &mut Connection, path: &Path, migrations: &[&str]) -> Result<(), String> {
+	let err = |e: rusqlite::Error| format!("cannot migrate database {}: {}", path.display(), e);
+	let current = version(conn).map_err(err)?;
+
+	if current > migrations.len() {
+		return Err(format!(
+			"database {} has schema version {}, this version of ffdyndns only knows {}",
+			path.display(), current, migrations.len()
+		));
+	}
+	if current == migrations.len() {
+		return Ok(());
+	}
+
+	if has_tables(conn).map_err(err)? {
+		let backup = backup_path(path, current);
+		conn.execute("VACUUM INTO ?", params![backup.to_string_lossy()]).map_err(err)?;
+		info!("backed up database with schema version {} to {}", current, backup.display());
+	}
+
+	for (i, sql) in migrations.iter().enumerate().skip(current) {
+		let tx = conn.transaction().map_err(err)?;
+		tx.execute_batch(sql).map_err(err)?;
+		tx.pragma_update(None, "user_version", (i + 1) as i64).map_err(err)?;
+		tx.commit().map_err(err)?;
+		info!("migrated database to schema version {}", i + 1);
+	}
+
+	Ok(())
+}
+
+
+fn has_tables(conn: &Connection) -> rusqlite::Result<bool> {
+	conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table'", [], |r| r.get::<_, i64>(0))
+		.map(|n| n > 0)
+}
+
+
+/// `ffdyndns.db.v1.bak`, an existing backup is never overwritten
+fn backup_path(path: &Path, version: usize) -> PathBuf {
+	let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+	let mut backup = path.with_file_name(format!("{}.v{}.bak", name, version));
+	let mut i = 1;
+	while backup.exists() {
+		backup = path.with_file_name(format!("{}.v{}.{}.bak", name, version, i));
+		i += 1;
+	}
+	backup
+}
+
+
+
+#[test]
+fn migrations() {
+	let tmp = tempfile::tempdir().unwrap();
+	let path = tmp.path();
+	let file = path.join("ffdyndns.db");
+	let v1 = "CREATE TABLE domains (domainname TEXT PRIMARY KEY);";
+	let v2 = "ALTER TABLE domains ADD COLUMN token TEXT NOT NULL DEFAULT '';";
+
+	// a new database is not backed up
+	let mut conn = Connection::open(&file).unwrap();
+	migrate(&mut conn, &file, &[v1]).unwrap();
+	assert_eq!(version(&conn), Ok(1));
+	assert!(!path.join("ffdyndns.db.v1.bak").exists());
+	conn.execute("INSERT INTO domains (domainname) VALUES ('foo.ffhl.de.')", []).unwrap();
+
+	// nothing to do
+	migrate(&mut conn, &file, &[v1]).unwrap();
+	assert!(!path.join("ffdyndns.db.v1.bak").exists());
+
+	migrate(&mut conn, &file, &[v1, v2]).unwrap();
+	assert_eq!(version(&conn), Ok(2));
+	let token: String = conn.query_row("SELECT token FROM domains", [], |r| r.get(0)).unwrap();
+	assert_eq!(token, "");
+
+	// the backup has the old schema and the data
+	let backup = Connection::open(path.join("ffdyndns.db.v1.bak")).unwrap();
+	assert_eq!(version(&backup), Ok(1));
+	let count: i64 = backup.query_row("SELECT count(*) FROM domains", [], |r| r.get(0)).unwrap();
+	assert_eq!(count, 1);
+
+	// a failed migration changes nothing
+	assert!(migrate(&mut conn, &file, &[v1, v2, "ALTER TABLE missing ADD COLUMN x TEXT;"]).is_err());
+	assert_eq!(version(&conn), Ok(2));
+
+	// newer databases are refused
+	assert!(migrate(&mut conn, &file, &[v1]).is_err());
+}
+
+
+
+#[test]
+fn owner_account() {
+	let tmp = tempfile::tempdir().unwrap();
+	let file = tmp.path().join("ffdyndns.db");
+	let mut conn = Connection::open(&file).unwrap();
+	conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+	migrate(&mut conn, &file, MIGRATIONS).unwrap();
+
+	conn.execute_batch("
+		INSERT INTO accounts (name, password, created) VALUES ('alice', '', '2021-06-01T12:00:00Z');
+		INSERT INTO domains (domainname, suffix, token, lastupdate, valid_until, owner)
+			VALUES ('foo.ffhl.de.', 'ffhl.de.', 't', '2021-06-01T12:00:00Z', '2021-06-01T12:00:00Z', 'Alice');
+	").unwrap();
+
+	// the owner must have an account
+	assert!(conn.execute("UPDATE domains SET owner = 'ghost'", []).is_err());
+	conn.execute("DELETE FROM accounts WHERE name = 'ALICE'", []).unwrap();
+	let owner: Option<String> = conn.query_row("SELECT owner FROM domains WHERE domainname = 'foo.ffhl.de.'", [], |r| r.get(0)).unwrap();
+	assert_eq!(owner, None);
+}
+
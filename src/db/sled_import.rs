@@ -0,0 +1,132 @@
+use super::{Database, Domain, Import, JournalEntry, ZoneRecord, JOURNAL_SIZE};
+use crate::domain::Dname;
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use serde_json as json;
+use std::convert::TryFrom;
+use std::path::Path;
+
+
+// the sled database of older versions. Domains were kept as JSON in the
+// default tree, keyed by the sha256 of their name, the zones had a tree
+// for serials, published records and the journal each.
+
+
+const SERIAL_TREE: &str = "serials";
+const PUBLISHED_TREE: &str = "published";
+const JOURNAL_TREE: &str = "journal";
+
+
+/// copies everything from the sled database in `dir` into `db`
+/// and returns the number of domains. Everything is read before
+/// anything is written, so a broken entry leaves `db` as it was.
+/// The server must not be running.
+pub fn import_sled(dir: &Path, db: &Database) -> Result<usize, String> {
+	let data = read_sled(dir)?;
+	db.store.import(&data, JOURNAL_SIZE)?;
+	Ok(data.domains.len())
+}
+
+
+fn read_sled(dir: &Path) -> Result<Import, String> {
+	// sled would create an empty database
+	if !dir.join("db").is_file() {
+		return Err(format!("{} is not a sled database", dir.display()));
+	}
+
+	let sled = sled::open(dir)
+		.map_err(|e| format!("cannot open sled database {}: {}", dir.display(), e))?;
+	let err = |e: sled::Error| format!("cannot read sled database: {}", e);
+	let mut data = Import::default();
+
+	for v in sled.iter().values() {
+		let d: Domain = json::from_slice(&v.map_err(err)?)
+			.map_err(|e| format!("invalid domain in sled database: {}", e))?;
+		if !Dname::new(d.domainname.clone()).is_valid() {
+			return Err(format!("invalid domain name in sled database: {:?}", d.domainname));
+		}
+		data.domains.push(d);
+	}
+
+	for r in sled.open_tree(SERIAL_TREE).map_err(err)?.iter() {
+		let (zone, serial) = r.map_err(err)?;
+		let zone = String::from_utf8_lossy(&zone).to_string();
+		let serial = <[u8; 4]>::try_from(&serial[..])
+			.map_err(|_| format!("invalid serial of {} in sled database", zone))?;
+		data.serials.push((zone, u32::from_be_bytes(serial)));
+	}
+
+	for r in sled.open_tree(PUBLISHED_TREE).map_err(err)?.iter() {
+		let (name, records) = r.map_err(err)?;
+		let records: Vec<ZoneRecord> = json::from_slice(&records)
+			.map_err(|e| format!("invalid published records in sled database: {}", e))?;
+		data.published.push((String::from_utf8_lossy(&name).to_string(), records));
+	}
+
+	// keys are the zone followed by a 8 byte id, in order
+	for r in sled.open_tree(JOURNAL_TREE).map_err(err)?.iter() {
+		let (key, entry) = r.map_err(err)?;
+		let zone = String::from_utf8_lossy(&key[..key.len().saturating_sub(8)]).to_string();
+		let entry: JournalEntry = json::from_slice(&entry)
+			.map_err(|e| format!("invalid journal entry in sled database: {}", e))?;
+		data.journal.push((zone, entry));
+	}
+
+	Ok(data)
+}
+
+
+
+#[test]
+fn import_from_sled() {
+
+	let tmp = tempfile::tempdir().unwrap();
+	let path = tmp.path();
+
+	// a database as written by older versions
+	{
+		let sled = sled::open(path.join("sled")).unwrap();
+		for i in 0..3 {
+			let mut d = Domain::new_with_token(&Dname::new(format!("host{}.ffhl.de.", i)), "t".to_string(), chrono::Duration::hours(1));
+			d.ipv4 = Some("10.0.0.1".parse().unwrap());
+			d.txt = vec!["hello".to_string()];
+			sled.insert(crate::sha256!(&d.domainname), json::to_vec(&d).unwrap()).unwrap();
+		}
+		sled.open_tree(SERIAL_TREE).unwrap().insert("ffhl.de.", 2021010100u32.to_be_bytes().to_vec()).unwrap();
+		let records = vec![ZoneRecord::A("host0.ffhl.de.".to_string(), "10.0.0.1".parse().unwrap())];
+		sled.open_tree(PUBLISHED_TREE).unwrap().insert("host0.ffhl.de.", json::to_vec(&records).unwrap()).unwrap();
+		let journal = sled.open_tree(JOURNAL_TREE).unwrap();
+		for serial in 0..2u32 {
+			let mut key = b"ffhl.de.".to_vec();
+			key.extend_from_slice(&(serial as u64).to_be_bytes());
+			let entry = JournalEntry { serial_from: serial, serial_to: serial + 1, removed: vec![], added: records.clone() };
+			journal.insert(key, json::to_vec(&entry).unwrap()).unwrap();
+		}
+		sled.flush().unwrap();
+	}
+
+	let db = Database::open(path.join("ffdyndns.db")).unwrap();
+	assert_eq!(import_sled(&path.join("sled"), &db), Ok(3));
+
+	let domains = db.get_by_suffix("ffhl.de.").unwrap();
+	assert_eq!(domains.len(), 3);
+	assert_eq!(domains[0].domainname, "host0.ffhl.de.");
+	assert_eq!(domains[0].txt, vec!["hello".to_string()]);
+	assert_eq!(db.store.get_serial("ffhl.de."), Ok(Some(2021010100)));
+	assert_eq!(db.store.published_name("ffhl.de.", "host0.ffhl.de.").unwrap().1.len(), 1);
+	assert_eq!(db.journal_since("ffhl.de.", 0).unwrap().map(|j| j.len()), Some(2));
+
+	assert!(import_sled(&path.join("missing"), &db).is_err());
+
+	// a broken serial stops the import before anything is written
+	{
+		let sled = sled::open(path.join("broken")).unwrap();
+		let d = Domain::new_with_token(&Dname::new("foo.ffhl.de.".to_string()), "t".to_string(), chrono::Duration::hours(1));
+		sled.insert(crate::sha256!(&d.domainname), json::to_vec(&d).unwrap()).unwrap();
+		sled.open_tree(SERIAL_TREE).unwrap().insert("ffhl.de.", vec![1, 2]).unwrap();
+		sled.flush().unwrap();
+	}
+	let empty = Database::open(path.join("empty.db")).unwrap();
+	assert!(import_sled(&path.join("broken"), &empty).unwrap_err().contains("invalid serial"));
+	assert!(empty.get_all().unwrap().is_empty());
+}
@@ -0,0 +1,708 @@
+use super::{schema, Account, Domain, Email, Error, HistoryEntry, Import, JournalEntry, Storage, Suspension, UpdateStatus, ZoneRecord};
+use crate::domain::Dname;
+use chrono::{DateTime, SecondsFormat, Utc};
+#[allow(unused_imports)]
+use log::{info, warn, error};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, Transaction, TransactionBehavior};
+use serde_json as json;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+
+const DOMAIN_COLUMNS: &str = "domainname, token, lastupdate, valid_until, ipv4, ipv6, locked, suspended_reason, suspended_since,
+	email, email_verified, email_reminded, owner";
+
+
+pub struct Sqlite {
+	conn: Mutex<Connection>,
+}
+
+impl Sqlite {
+	/// opens the database and migrates it to the current schema
+	pub fn open(path: &Path) -> Result<Self, Error> {
+		if let Some(dir) = path.parent() {
+			std::fs::create_dir_all(dir)
+				.map_err(|e| Error::Unavailable(format!("cannot create {}: {}", dir.display(), e)))?;
+		}
+
+		let mut conn = Connection::open(path)
+			.map_err(|e| Error::Unavailable(format!("cannot open database {}: {}", path.display(), e)))?;
+		conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA foreign_keys = ON;")?;
+		schema::migrate(&mut conn, path, schema::MIGRATIONS).map_err(Error::Failed)?;
+
+		Ok(Self { conn: Mutex::new(conn) })
+	}
+
+	fn conn(&self) -> Result<MutexGuard<'_, Connection>, Error> {
+		self.conn.lock().map_err(|_| Error::Unavailable("connection poisoned".to_string()))
+	}
+
+	/// domains matching a where clause, with their TXT records
+	fn query_domains<P: rusqlite::Params>(&self, filter: &str, params: P) -> Result<Vec<Domain>, Error> {
+		read_domains(&*self.conn()?, filter, params)
+	}
+}
+
+
+impl Storage for Sqlite {
+	fn insert_domain(&self, d: &Domain) -> Result<(), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+		insert_new_domain(&tx, d)?;
+		Ok(tx.commit()?)
+	}
+
+	fn import_domains(&self, new: &[Domain], replace: &[Domain]) -> Result<(), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		for d in new {
+			insert_new_domain(&tx, d)?;
+		}
+		for d in replace {
+			write_domain(&tx, d)?;
+		}
+
+		Ok(tx.commit()?)
+	}
+
+	fn get_domain(&self, name: &str) -> Result<Option<Domain>, Error> {
+		read_domain(&*self.conn()?, name)
+	}
+
+	fn modify_domain(&self, name: &str, f: &mut dyn FnMut(&mut Domain) -> bool) -> Result<Option<Domain>, Error> {
+		let mut conn = self.conn()?;
+		// takes the write lock right away, so nobody can write between reading and writing
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		let mut d = match read_domain(&tx, name)? {
+			Some(d) => d,
+			None => return Ok(None),
+		};
+		if f(&mut d) {
+			write_domain(&tx, &d)?;
+			tx.commit()?;
+		}
+
+		Ok(Some(d))
+	}
+
+	fn remove_domain(&self, name: &str) -> Result<Option<Domain>, Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		let d = read_domain(&tx, name)?;
+		tx.execute("DELETE FROM domains WHERE domainname = ?", params![name])?;
+		tx.execute("DELETE FROM history WHERE domainname = ?", params![name])?;
+
+		tx.commit()?;
+		Ok(d)
+	}
+
+	fn clear_expired(&self, now: DateTime<Utc>) -> Result<Vec<Domain>, Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		let domains = read_domains(
+			&tx,
+			"WHERE valid_until < ? AND suspended_reason IS NULL
+				AND (ipv4 IS NOT NULL OR ipv6 IS NOT NULL
+					OR EXISTS (SELECT 1 FROM txt_records t WHERE t.domainname = domains.domainname))",
+			params![format_time(&now)],
+		)?;
+		for d in &domains {
+			let mut cleared = d.clone();
+			cleared.ipv4 = None;
+			cleared.ipv6 = None;
+			cleared.txt.clear();
+			write_domain(&tx, &cleared)?;
+		}
+
+		tx.commit()?;
+		Ok(domains)
+	}
+
+	fn remove_expired(&self, time: DateTime<Utc>) -> Result<Vec<Domain>, Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		// only the domains that could be read, the records
+		// of the others would stay in the DNS server
+		let domains = read_domains(&tx, "WHERE valid_until < ? AND suspended_reason IS NULL", params![format_time(&time)])?;
+		for d in &domains {
+			tx.execute("DELETE FROM domains WHERE domainname = ?", params![d.domainname])?;
+			tx.execute("DELETE FROM history WHERE domainname = ?", params![d.domainname])?;
+		}
+
+		tx.commit()?;
+		Ok(domains)
+	}
+
+	fn domains(&self) -> Result<Vec<Domain>, Error> {
+		self.query_domains("", [])
+	}
+
+	fn domains_by_suffix(&self, suffix: &str) -> Result<Vec<Domain>, Error> {
+		let suffix = format!("{}.", suffix.trim_end_matches('.'));
+		self.query_domains("WHERE suffix = ?", params![suffix])
+	}
+
+	fn domains_by_owner(&self, owner: &str) -> Result<Vec<Domain>, Error> {
+		self.query_domains("WHERE owner = ?", params![owner])
+	}
+
+	fn insert_account(&self, account: &Account) -> Result<(), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		let taken = tx.query_row("SELECT 1 FROM accounts WHERE name = ?", params![account.name], |_| Ok(()))
+			.optional()?;
+		if taken.is_some() {
+			return Err(Error::Exists(account.name.clone()));
+		}
+
+		tx.execute(
+			"INSERT INTO accounts (name, password, created) VALUES (?, ?, ?)",
+			params![account.name, account.password, format_time(&account.created)],
+		)?;
+		Ok(tx.commit()?)
+	}
+
+	fn get_account(&self, name: &str) -> Result<Option<Account>, Error> {
+		let conn = self.conn()?;
+		Ok(conn.query_row("SELECT name, password, created FROM accounts WHERE name = ?", params![name], |r| {
+			Ok(Account {
+				name: r.get(0)?,
+				password: r.get(1)?,
+				created: time_column(r, 2)?,
+			})
+		}).optional()?)
+	}
+
+	fn get_serial(&self, zone: &str) -> Result<Option<u32>, Error> {
+		read_serial(&*self.conn()?, zone)
+	}
+
+	fn update_serial(&self, zone: &str, next: &dyn Fn(Option<u32>) -> u32) -> Result<u32, Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction()?;
+
+		let serial = next(read_serial(&tx, zone)?);
+		write_serial(&tx, zone, serial)?;
+
+		tx.commit()?;
+		Ok(serial)
+	}
+
+	fn published_zone(&self, zone: &str) -> Result<(Option<u32>, Vec<ZoneRecord>), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction()?;
+
+		let mut stmt = tx.prepare_cached("SELECT records FROM published WHERE suffix = ? ORDER BY name")?;
+		let mut records = Vec::new();
+		for r in stmt.query_map(params![zone], |r| json_column::<Vec<ZoneRecord>>(r, 0))? {
+			records.extend(r?);
+		}
+		drop(stmt);
+
+		Ok((read_serial(&tx, zone)?, records))
+	}
+
+	fn published_name(&self, zone: &str, name: &str) -> Result<(Option<u32>, Vec<ZoneRecord>), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction()?;
+		Ok((read_serial(&tx, zone)?, read_published(&tx, name)?))
+	}
+
+	fn publish(&self, zone: &str, records: &[(String, Vec<ZoneRecord>)], initial: u32, next: &dyn Fn(u32) -> u32, keep: usize) -> Result<Option<JournalEntry>, Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		let mut removed = Vec::new();
+		let mut added = Vec::new();
+		for (name, new) in records {
+			let old = read_published(&tx, name)?;
+			removed.extend(old.iter().filter(|r| !new.contains(r)).cloned());
+			added.extend(new.iter().filter(|r| !old.contains(r)).cloned());
+			write_published(&tx, name, new)?;
+		}
+
+		if removed.is_empty() && added.is_empty() {
+			return Ok(None);
+		}
+
+		let serial_from = read_serial(&tx, zone)?.unwrap_or(initial);
+		let entry = JournalEntry {
+			serial_from,
+			serial_to: next(serial_from),
+			removed,
+			added,
+		};
+		write_serial(&tx, zone, entry.serial_to)?;
+		write_journal(&tx, zone, &entry, keep)?;
+
+		tx.commit()?;
+		Ok(Some(entry))
+	}
+
+	fn journal(&self, zone: &str) -> Result<Vec<JournalEntry>, Error> {
+		let conn = self.conn()?;
+		let mut stmt = conn.prepare_cached(
+			"SELECT serial_from, serial_to, removed, added FROM journal WHERE zone = ? ORDER BY id"
+		)?;
+
+		let entries = stmt.query_map(params![zone], |r| {
+			Ok(JournalEntry {
+				serial_from: r.get(0)?,
+				serial_to: r.get(1)?,
+				removed: json_column(r, 2)?,
+				added: json_column(r, 3)?,
+			})
+		})?.collect::<rusqlite::Result<_>>();
+
+		// without the journal the secondaries get the full zone
+		Ok(entries.unwrap_or_else(|e| {
+			error!("invalid journal of {}: {}", zone, e);
+			Vec::new()
+		}))
+	}
+
+	fn history_add(&self, e: &HistoryEntry) -> Result<i64, Error> {
+		let (status, error) = status_columns(&e.status);
+		let conn = self.conn()?;
+		conn.execute(
+			"INSERT INTO history (domainname, time, source, token, old_records, new_records, status, error)
+				VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+			params![
+				e.domainname,
+				format_time(&e.time),
+				e.source.map(|a| a.to_string()),
+				e.token,
+				json::to_string(&e.old)?,
+				json::to_string(&e.new)?,
+				status,
+				error,
+			],
+		)?;
+		Ok(conn.last_insert_rowid())
+	}
+
+	fn history_set_status(&self, id: i64, status: &UpdateStatus) -> Result<(), Error> {
+		let (status, error) = status_columns(status);
+		self.conn()?.execute("UPDATE history SET status = ?, error = ? WHERE id = ?", params![status, error, id])?;
+		Ok(())
+	}
+
+	fn history(&self, name: &str, limit: usize) -> Result<Vec<HistoryEntry>, Error> {
+		let conn = self.conn()?;
+		let mut stmt = conn.prepare_cached(
+			"SELECT domainname, time, source, token, old_records, new_records, status, error
+			FROM history WHERE domainname = ? ORDER BY id DESC LIMIT ?"
+		)?;
+
+		let entries = stmt.query_map(params![name, limit as i64], |r| {
+			Ok(HistoryEntry {
+				domainname: r.get(0)?,
+				time: time_column(r, 1)?,
+				source: parse_column(r, 2)?,
+				token: r.get(3)?,
+				old: json_column(r, 4)?,
+				new: json_column(r, 5)?,
+				status: match (r.get::<_, String>(6)?.as_str(), r.get::<_, Option<String>>(7)?) {
+					("ok", _) => UpdateStatus::Ok,
+					("failed", e) => UpdateStatus::Failed(e.unwrap_or_default()),
+					_ => UpdateStatus::Pending,
+				},
+			})
+		})?.collect::<rusqlite::Result<_>>()?;
+
+		Ok(entries)
+	}
+
+	fn history_remove_before(&self, time: DateTime<Utc>) -> Result<usize, Error> {
+		Ok(self.conn()?.execute("DELETE FROM history WHERE time < ?", params![format_time(&time)])?)
+	}
+
+	fn snapshot(&self, path: &Path) -> Result<(), Error> {
+		self.conn()?.execute("VACUUM INTO ?", params![path.to_string_lossy()])?;
+		Ok(())
+	}
+
+	fn import(&self, data: &Import, keep: usize) -> Result<(), Error> {
+		let mut conn = self.conn()?;
+		let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		for d in &data.domains {
+			write_domain(&tx, d)?;
+		}
+		for (zone, serial) in &data.serials {
+			write_serial(&tx, zone, *serial)?;
+		}
+		for (name, records) in &data.published {
+			write_published(&tx, name, records)?;
+		}
+		for (zone, entry) in &data.journal {
+			write_journal(&tx, zone, entry, keep)?;
+		}
+
+		Ok(tx.commit()?)
+	}
+}
+
+
+/// checks a snapshot without changing it and returns the number of domains.
+/// Unlike the live database every domain has to be readable.
+pub fn check_snapshot(path: &Path) -> Result<usize, Error> {
+	if !path.is_file() {
+		return Err(Error::NotFound(path.display().to_string()));
+	}
+	let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+	let integrity: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+	if integrity != "ok" {
+		return Err(Error::Corrupt(integrity));
+	}
+
+	match schema::version(&conn)? {
+		0 => return Err(Error::Corrupt("not a ffdyndns database".to_string())),
+		v if v > schema::MIGRATIONS.len() => return Err(Error::Failed(format!("schema version {} is too new", v))),
+		_ => (),
+	}
+
+	let sql = format!("SELECT {} FROM domains", DOMAIN_COLUMNS);
+	let mut stmt = conn.prepare(&sql)?;
+	let mut count = 0;
+	for d in stmt.query_map([], domain_from_row)? {
+		txt_records(&conn, &d?.domainname)?;
+		count += 1;
+	}
+
+	Ok(count)
+}
+
+
+/// the domains matching `filter` with their TXT records. A broken row
+/// must not take down everything else, it is logged and skipped.
+fn read_domains<P: rusqlite::Params>(conn: &Connection, filter: &str, params: P) -> Result<Vec<Domain>, Error> {
+	let sql = format!("SELECT {} FROM domains {} ORDER BY domainname", DOMAIN_COLUMNS, filter);
+
+	let mut stmt = conn.prepare_cached(&sql)?;
+	let rows = stmt.query_map(params, domain_from_row)?;
+
+	let mut domains = Vec::new();
+	for row in rows {
+		match row {
+			Ok(mut d) => {
+				d.txt = txt_records(conn, &d.domainname)?;
+				domains.push(d);
+			}
+			Err(e) => error!("skipping invalid domain in database: {}", Error::from(e)),
+		}
+	}
+
+	Ok(domains)
+}
+
+
+fn read_domain(conn: &Connection, name: &str) -> Result<Option<Domain>, Error> {
+	let sql = format!("SELECT {} FROM domains WHERE domainname = ?", DOMAIN_COLUMNS);
+
+	match conn.query_row(&sql, params![name], domain_from_row).optional()? {
+		Some(mut d) => {
+			d.txt = txt_records(conn, &d.domainname)?;
+			Ok(Some(d))
+		}
+		None => Ok(None),
+	}
+}
+
+
+/// inserts or replaces a domain with its TXT records, within a transaction
+fn insert_new_domain(tx: &Transaction, d: &Domain) -> Result<(), Error> {
+	let taken = tx.query_row("SELECT 1 FROM domains WHERE domainname = ?", params![d.domainname], |_| Ok(()))
+		.optional()?;
+	if taken.is_some() {
+		return Err(Error::Exists(d.domainname.clone()));
+	}
+
+	write_domain(tx, d)
+}
+
+
+fn write_domain(tx: &Transaction, d: &Domain) -> Result<(), Error> {
+	tx.execute(
+		"INSERT INTO domains (domainname, suffix, token, lastupdate, valid_until, ipv4, ipv6, locked, suspended_reason, suspended_since,
+				email, email_verified, email_reminded, owner)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+		ON CONFLICT (domainname) DO UPDATE SET
+			token = excluded.token,
+			lastupdate = excluded.lastupdate,
+			valid_until = excluded.valid_until,
+			ipv4 = excluded.ipv4,
+			ipv6 = excluded.ipv6,
+			locked = excluded.locked,
+			suspended_reason = excluded.suspended_reason,
+			suspended_since = excluded.suspended_since,
+			email = excluded.email,
+			email_verified = excluded.email_verified,
+			email_reminded = excluded.email_reminded,
+			owner = excluded.owner",
+		params![
+			d.domainname,
+			suffix(&d.domainname),
+			d.token,
+			format_time(&d.lastupdate),
+			format_time(&d.valid_until),
+			d.ipv4.map(|a| a.to_string()),
+			d.ipv6.map(|a| a.to_string()),
+			d.locked,
+			d.suspended.as_ref().map(|s| &s.reason),
+			d.suspended.as_ref().map(|s| format_time(&s.since)),
+			d.email.as_ref().map(|e| &e.address),
+			d.email.as_ref().is_some_and(|e| e.verified),
+			d.email.as_ref().and_then(|e| e.reminded.as_ref()).map(format_time),
+			d.owner,
+		],
+	)?;
+
+	tx.execute("DELETE FROM txt_records WHERE domainname = ?", params![d.domainname])?;
+	for (i, txt) in d.txt.iter().enumerate() {
+		tx.execute(
+			"INSERT INTO txt_records (domainname, position, content) VALUES (?, ?, ?)",
+			params![d.domainname, i as i64, txt],
+		)?;
+	}
+
+	Ok(())
+}
+
+
+fn read_serial(conn: &Connection, zone: &str) -> Result<Option<u32>, Error> {
+	Ok(conn.query_row("SELECT serial FROM serials WHERE zone = ?", params![zone], |r| r.get::<_, u32>(0))
+		.optional()?)
+}
+
+
+fn write_serial(conn: &Connection, zone: &str, serial: u32) -> Result<(), Error> {
+	conn.execute(
+		"INSERT INTO serials (zone, serial) VALUES (?, ?)
+		ON CONFLICT (zone) DO UPDATE SET serial = excluded.serial",
+		params![zone, serial],
+	)?;
+	Ok(())
+}
+
+
+fn read_published(conn: &Connection, name: &str) -> Result<Vec<ZoneRecord>, Error> {
+	let records = conn.query_row("SELECT records FROM published WHERE name = ?", params![name], |r| r.get::<_, String>(0))
+		.optional()?;
+
+	match records {
+		Some(r) => Ok(json::from_str(&r)?),
+		None => Ok(Vec::new()),
+	}
+}
+
+
+/// names without records are not kept
+fn write_published(conn: &Connection, name: &str, records: &[ZoneRecord]) -> Result<(), Error> {
+	if records.is_empty() {
+		conn.execute("DELETE FROM published WHERE name = ?", params![name])?;
+	} else {
+		conn.execute(
+			"INSERT INTO published (name, suffix, records) VALUES (?, ?, ?)
+			ON CONFLICT (name) DO UPDATE SET records = excluded.records",
+			params![name, suffix(name), json::to_string(records)?],
+		)?;
+	}
+	Ok(())
+}
+
+
+/// appends to the journal of a zone and drops the oldest entries beyond `keep`
+fn write_journal(conn: &Connection, zone: &str, entry: &JournalEntry, keep: usize) -> Result<(), Error> {
+	conn.execute(
+		"INSERT INTO journal (zone, serial_from, serial_to, removed, added) VALUES (?, ?, ?, ?, ?)",
+		params![
+			zone,
+			entry.serial_from,
+			entry.serial_to,
+			json::to_string(&entry.removed)?,
+			json::to_string(&entry.added)?,
+		],
+	)?;
+	conn.execute(
+		"DELETE FROM journal WHERE zone = ?1 AND id NOT IN
+			(SELECT id FROM journal WHERE zone = ?1 ORDER BY id DESC LIMIT ?2)",
+		params![zone, keep as i64],
+	)?;
+	Ok(())
+}
+
+
+fn domain_from_row(row: &Row) -> rusqlite::Result<Domain> {
+	Ok(Domain {
+		domainname: row.get(0)?,
+		token: row.get(1)?,
+		lastupdate: time_column(row, 2)?,
+		valid_until: time_column(row, 3)?,
+		ipv4: parse_column(row, 4)?,
+		ipv6: parse_column(row, 5)?,
+		txt: Vec::new(),
+		locked: row.get(6)?,
+		suspended: match row.get::<_, Option<String>>(7)? {
+			Some(reason) => Some(Suspension { reason, since: time_column(row, 8)? }),
+			None => None,
+		},
+		email: match row.get::<_, Option<String>>(9)? {
+			Some(address) => Some(Email {
+				address,
+				verified: row.get(10)?,
+				reminded: parse_column(row, 11)?,
+			}),
+			None => None,
+		},
+		owner: row.get(12)?,
+	})
+}
+
+
+fn txt_records(conn: &Connection, name: &str) -> Result<Vec<String>, Error> {
+	let mut stmt = conn.prepare_cached("SELECT content FROM txt_records WHERE domainname = ? ORDER BY position")?;
+	let txt = stmt.query_map(params![name], |r| r.get(0))?.collect::<rusqlite::Result<_>>()?;
+	Ok(txt)
+}
+
+
+/// the configured domain a dynamic domain belongs to
+fn suffix(name: &str) -> String {
+	Dname::new(name.to_lowercase()).strip_subdomain()
+}
+
+
+/// timestamps are stored in UTC with a fixed format, so they sort as text
+fn format_time(t: &DateTime<Utc>) -> String {
+	t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+
+fn time_column(row: &Row, i: usize) -> rusqlite::Result<DateTime<Utc>> {
+	DateTime::parse_from_rfc3339(&row.get::<_, String>(i)?)
+		.map(|t| t.with_timezone(&Utc))
+		.map_err(|e| rusqlite::Error::FromSqlConversionFailure(i, Type::Text, Box::new(e)))
+}
+
+
+fn parse_column<T>(row: &Row, i: usize) -> rusqlite::Result<Option<T>>
+where
+	T: std::str::FromStr,
+	T::Err: std::error::Error + Send + Sync + 'static,
+{
+	row.get::<_, Option<String>>(i)?
+		.map(|v| v.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(i, Type::Text, Box::new(e))))
+		.transpose()
+}
+
+
+fn status_columns(status: &UpdateStatus) -> (&'static str, Option<&str>) {
+	match status {
+		UpdateStatus::Pending => ("pending", None),
+		UpdateStatus::Ok => ("ok", None),
+		UpdateStatus::Failed(e) => ("failed", Some(e.as_str())),
+	}
+}
+
+
+fn json_column<T: serde::de::DeserializeOwned>(row: &Row, i: usize) -> rusqlite::Result<T> {
+	json::from_str(&row.get::<_, String>(i)?)
+		.map_err(|e| rusqlite::Error::FromSqlConversionFailure(i, Type::Text, Box::new(e)))
+}
+
+
+
+/// writes an address that cannot be read into the database of `test_database`
+#[cfg(test)]
+fn break_ipv4(dir: &Path, name: &str) {
+	Connection::open(dir.join("ffdyndns.db")).unwrap()
+		.execute("UPDATE domains SET ipv4 = 'garbage' WHERE domainname = ?", params![name]).unwrap();
+}
+
+
+
+#[test]
+fn domain_storage() {
+	let (tmp, db) = super::test_database(&["foo.ffhl.de.", "bar.ffhl.de.", "foo_bar.ffdyn.net."], chrono::Duration::hours(1));
+	let store = &db.store;
+
+	let mut d = store.get_domain("Foo.FFHL.de.").unwrap().unwrap();
+	assert_eq!(d.domainname, "foo.ffhl.de.");
+	d.ipv6 = Some("fd00::1".parse().unwrap());
+	d.txt = vec!["b".to_string(), "a".to_string()];
+	d.email = Some(Email {
+		address: "owner@example.org".to_string(),
+		verified: true,
+		reminded: Some("2021-06-01T12:00:00Z".parse().unwrap()),
+	});
+	store.import_domains(&[], &[d.clone()]).unwrap();
+
+	let stored = store.get_domain("foo.ffhl.de.").unwrap().unwrap();
+	assert_eq!(stored.ipv6, d.ipv6);
+	assert_eq!(stored.txt, d.txt);
+	assert_eq!(stored.email, d.email);
+	assert_eq!(format_time(&stored.valid_until), format_time(&d.valid_until));
+
+	let names = |ds: Vec<Domain>| ds.into_iter().map(|d| d.domainname).collect::<Vec<String>>();
+	assert_eq!(names(store.domains_by_suffix("ffhl.de").unwrap()), vec!["bar.ffhl.de.", "foo.ffhl.de."]);
+
+	let account = Account {
+		name: "Alice".to_string(),
+		password: "$rscrypt$0$...".to_string(),
+		created: "2021-06-01T12:00:00Z".parse().unwrap(),
+	};
+	store.insert_account(&account).unwrap();
+	assert!(matches!(store.insert_account(&Account { name: "alice".to_string(), ..account.clone() }), Err(Error::Exists(_))));
+	assert_eq!(store.get_account("ALICE").unwrap(), Some(account));
+	let mut owned = store.get_domain("bar.ffhl.de.").unwrap().unwrap();
+	owned.owner = Some("Alice".to_string());
+	store.import_domains(&[], &[owned.clone()]).unwrap();
+	assert_eq!(names(store.domains_by_owner("alice").unwrap()), vec!["bar.ffhl.de."]);
+	owned.owner = Some("bob".to_string());
+	assert!(store.import_domains(&[], &[owned.clone()]).is_err());
+
+	store.remove_domain("foo.ffhl.de.").unwrap();
+	assert!(store.get_domain("foo.ffhl.de.").unwrap().is_none());
+	assert_eq!(store.domains().unwrap().len(), 2);
+
+	// a broken row is skipped, the others are still there
+	break_ipv4(tmp.path(), "bar.ffhl.de.");
+	assert_eq!(names(store.domains().unwrap()), vec!["foo_bar.ffdyn.net."]);
+	assert!(matches!(store.get_domain("bar.ffhl.de."), Err(Error::Corrupt(_))));
+}
+
+
+
+#[test]
+fn broken_expired_rows() {
+	let (tmp, db) = super::test_database(&["bad.ffhl.de.", "good.ffhl.de."], chrono::Duration::hours(-1));
+	let store = &db.store;
+
+	for name in &["bad.ffhl.de.", "good.ffhl.de."] {
+		store.modify_domain(name, &mut |d| {
+			d.ipv4 = Some("10.0.0.1".parse().unwrap());
+			true
+		}).unwrap();
+	}
+	break_ipv4(tmp.path(), "bad.ffhl.de.");
+
+	// the broken domain is skipped, the others are cleared anyway
+	let cleared = store.clear_expired(Utc::now()).unwrap();
+	assert_eq!(cleared.iter().map(|d| d.domainname.as_str()).collect::<Vec<_>>(), vec!["good.ffhl.de."]);
+	assert_eq!(store.get_domain("good.ffhl.de.").unwrap().unwrap().ipv4, None);
+
+	// and only the domains that could be read are removed
+	let removed = store.remove_expired(Utc::now()).unwrap();
+	assert_eq!(removed.iter().map(|d| d.domainname.as_str()).collect::<Vec<_>>(), vec!["good.ffhl.de."]);
+	assert!(store.get_domain("good.ffhl.de.").unwrap().is_none());
+	assert!(matches!(store.get_domain("bad.ffhl.de."), Err(Error::Corrupt(_))));
+}
@@ -0,0 +1,446 @@
+use super::message::{labels, rtype, write_name, Dnskey, NameError, Nsec, RData, Record, Rrsig, CLASS_IN};
+use crate::config;
+use crate::DNSSEC_PREPUBLISH;
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use chrono::{DateTime, Duration, Utc};
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+
+// signing of the served zones (RFC 4033 - 4035) with Ed25519 (RFC 8080).
+// Every zone has a long lived key signing key, whose DS record is in the
+// parent zone, and a zone signing key that is replaced regularly.
+
+
+const ALGORITHM: u8 = 15;
+const FLAG_ZONE: u16 = 256;
+const FLAG_SEP: u16 = 1;
+/// seconds a signature is valid before it was made, for clocks running late
+const INCEPTION_OFFSET: i64 = 3600;
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Key {
+	/// key signing key, signs only the DNSKEY set
+	pub ksk: bool,
+	/// base64 encoded Ed25519 seed
+	seed: String,
+	/// when the key shows up in the DNSKEY set
+	pub publish: DateTime<Utc>,
+	/// when the key starts signing
+	pub activate: DateTime<Utc>,
+	/// when the key stops signing
+	pub retire: Option<DateTime<Utc>>,
+	/// when the key is removed from the DNSKEY set
+	pub remove: Option<DateTime<Utc>>,
+}
+
+impl Key {
+	pub fn generate(ksk: bool, publish: DateTime<Utc>, activate: DateTime<Utc>) -> Self {
+		let seed: [u8; 32] = rand::random();
+		Self {
+			ksk,
+			seed: base64::encode(seed),
+			publish,
+			activate,
+			retire: None,
+			remove: None,
+		}
+	}
+
+	fn is_valid(&self) -> bool {
+		base64::decode(&self.seed).is_ok_and(|s| s.len() == 32)
+	}
+
+	/// secret and public key
+	fn keypair(&self) -> ([u8; 64], [u8; 32]) {
+		ed25519::keypair(&base64::decode(&self.seed).unwrap())
+	}
+
+	pub fn dnskey(&self) -> Dnskey {
+		Dnskey {
+			flags: if self.ksk { FLAG_ZONE | FLAG_SEP } else { FLAG_ZONE },
+			algorithm: ALGORITHM,
+			public_key: self.keypair().1.to_vec(),
+		}
+	}
+
+	/// key tag (RFC 4034 appendix B)
+	pub fn tag(&self) -> u16 {
+		let rdata = RData::Dnskey(self.dnskey()).to_vec().expect("a DNSKEY has no names");
+		let mut ac: u32 = 0;
+		for (i, b) in rdata.iter().enumerate() {
+			ac += if i % 2 == 0 { (*b as u32) << 8 } else { *b as u32 };
+		}
+		ac += ac >> 16;
+		(ac & 0xffff) as u16
+	}
+
+	/// DS record with a SHA-256 digest, to be added to the parent zone
+	pub fn ds(&self, zone: &str) -> Result<String, NameError> {
+		let mut data = Vec::new();
+		write_name(&mut data, &zone.to_lowercase())?;
+		data.extend_from_slice(&RData::Dnskey(self.dnskey()).to_vec()?);
+
+		let mut sha = Sha256::new();
+		sha.input(&data);
+		Ok(format!("{} IN DS {} {} 2 {}", zone, self.tag(), ALGORITHM, sha.result_str()))
+	}
+
+	pub fn is_published(&self, now: DateTime<Utc>) -> bool {
+		self.publish <= now && self.remove.is_none_or(|r| now < r)
+	}
+
+	pub fn is_signing(&self, now: DateTime<Utc>) -> bool {
+		self.activate <= now && self.retire.is_none_or(|r| now < r)
+	}
+}
+
+
+/// the keys of all signed zones, kept as json files in the key directory
+pub struct KeyStore {
+	dir: PathBuf,
+	keys: Mutex<HashMap<String, Vec<Key>>>,
+}
+
+impl KeyStore {
+	pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+		Self {
+			dir: dir.into(),
+			keys: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn path(&self, zone: &str) -> PathBuf {
+		self.dir.join(format!("{}.json", zone.trim_end_matches('.').to_lowercase()))
+	}
+
+	/// the keys of a zone. Keys are generated if the zone has none yet.
+	/// A signed zone must not be served without its keys, its DS record
+	/// in the parent would make it bogus.
+	pub fn get(&self, zone: &config::Domain) -> Result<Vec<Key>, String> {
+		if let Some(keys) = self.keys.lock().unwrap().get(&zone.name.to_lowercase()) {
+			return Ok(keys.clone());
+		}
+
+		self.maintain(zone, Utc::now())
+			.map_err(|e| format!("no keys for {}: {}", zone.name, e))?;
+		Ok(self.keys.lock().unwrap().get(&zone.name.to_lowercase()).cloned().unwrap_or_default())
+	}
+
+	/// generates, rolls over and removes the keys of a zone as scheduled.
+	/// Returns true if the keys changed.
+	pub fn maintain(&self, zone: &config::Domain, now: DateTime<Utc>) -> Result<bool, String> {
+		let conf = match &zone.dnssec {
+			Some(c) => c,
+			None => return Ok(false),
+		};
+
+		let mut cache = self.keys.lock().unwrap();
+		let name = zone.name.to_lowercase();
+		let mut keys = match cache.get(&name) {
+			Some(k) => k.clone(),
+			None => self.load(&name)?,
+		};
+
+		let changed = schedule(conf, &mut keys, now);
+		if changed {
+			self.save(&name, &keys)?;
+			for k in keys.iter().filter(|k| k.ksk && k.publish == now) {
+				info!("new key signing key for {}, add to the parent zone: {}", zone.name, k.ds(&zone.name)?);
+			}
+		}
+
+		cache.insert(name, keys);
+		Ok(changed)
+	}
+
+	fn load(&self, zone: &str) -> Result<Vec<Key>, String> {
+		let path = self.path(zone);
+		if !path.exists() {
+			return Ok(Vec::new());
+		}
+
+		let content = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+		let keys: Vec<Key> = json::from_slice(&content).map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+		if let Some(k) = keys.iter().find(|k| !k.is_valid()) {
+			return Err(format!("{} contains an invalid key {}", path.display(), k.seed));
+		}
+
+		Ok(keys)
+	}
+
+	/// replaces the key file, readable only by us
+	fn save(&self, zone: &str, keys: &[Key]) -> Result<(), String> {
+		let path = self.path(zone);
+		let tmp = path.with_extension("json.tmp");
+		let err = |e: std::io::Error| format!("cannot write {}: {}", path.display(), e);
+
+		fs::create_dir_all(&self.dir).map_err(err)?;
+		let mut f = fs::OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.mode(0o600)
+			.open(&tmp)
+			.map_err(err)?;
+		f.write_all(&json::to_vec_pretty(keys).unwrap()).map_err(err)?;
+		f.sync_all().map_err(err)?;
+		fs::rename(&tmp, &path).map_err(err)
+	}
+}
+
+
+/// key rollover with pre-publication (RFC 6781 section 4.1.1.1).
+/// The successor of a zone signing key is published `DNSSEC_PREPUBLISH`
+/// days before it takes over, the old key stays published until its
+/// signatures expired. The key signing key is never replaced.
+fn schedule(conf: &config::Dnssec, keys: &mut Vec<Key>, now: DateTime<Utc>) -> bool {
+	let count = keys.len();
+	keys.retain(|k| k.remove.is_none_or(|r| now < r));
+	let mut changed = keys.len() != count;
+
+	if !keys.iter().any(|k| k.ksk) {
+		keys.push(Key::generate(true, now, now));
+		changed = true;
+	}
+
+	let lifetime = Duration::days(conf.zsk_lifetime as i64);
+	let prepublish = Duration::days(DNSSEC_PREPUBLISH);
+	let validity = Duration::days(conf.signature_validity as i64);
+
+	let current = keys.iter()
+		.enumerate()
+		.filter(|(_, k)| !k.ksk && k.retire.is_none() && k.activate <= now)
+		.max_by_key(|(_, k)| k.activate)
+		.map(|(i, _)| i);
+	let has_successor = keys.iter().any(|k| !k.ksk && k.activate > now);
+
+	match current {
+		None if !has_successor => {
+			keys.push(Key::generate(false, now, now));
+			changed = true;
+		}
+		Some(i) if !has_successor && now >= keys[i].activate + lifetime - prepublish => {
+			let activate = (keys[i].activate + lifetime).max(now + prepublish);
+			keys[i].retire = Some(activate);
+			keys[i].remove = Some(activate + validity);
+			keys.push(Key::generate(false, now, activate));
+			changed = true;
+		}
+		_ => (),
+	}
+
+	changed
+}
+
+
+/// the DNSKEY records of a zone
+pub fn dnskey_records(zone: &config::Domain, keys: &[Key], now: DateTime<Utc>) -> Vec<Record> {
+	keys.iter()
+		.filter(|k| k.is_published(now))
+		.map(|k| Record::new(&zone.name, zone.soa.ttl, RData::Dnskey(k.dnskey())))
+		.collect()
+}
+
+
+/// RRSIG records for all RRsets in `records`. The DNSKEY set is signed
+/// by the key signing key, everything else by the zone signing key.
+/// RRsets that cannot be written in wire format are left unsigned,
+/// they cannot be sent either.
+pub fn sign(zone: &config::Domain, keys: &[Key], records: &[Record], now: DateTime<Utc>) -> Vec<Record> {
+	let mut rrsets: Vec<Vec<&Record>> = Vec::new();
+	let mut index = HashMap::new();
+	for r in records.iter().filter(|r| r.rtype != rtype::RRSIG) {
+		let i = *index.entry((r.name.to_lowercase(), r.rtype)).or_insert_with(|| {
+			rrsets.push(Vec::new());
+			rrsets.len() - 1
+		});
+		rrsets[i].push(r);
+	}
+
+	let mut sigs = Vec::new();
+	for rrset in rrsets {
+		let ksk = rrset[0].rtype == rtype::DNSKEY;
+		for key in keys.iter().filter(|k| k.ksk == ksk && k.is_signing(now)) {
+			match sign_rrset(zone, key, &rrset, now) {
+				Ok(sig) => sigs.push(sig),
+				Err(e) => error!("cannot sign {}: {}", rrset[0].name, e),
+			}
+		}
+	}
+	sigs
+}
+
+
+fn sign_rrset(zone: &config::Domain, key: &Key, rrset: &[&Record], now: DateTime<Utc>) -> Result<Record, NameError> {
+	let validity = zone.dnssec.as_ref().map_or(14, |d| d.signature_validity);
+	let owner = rrset[0].name.to_lowercase();
+
+	let mut rrsig = Rrsig {
+		type_covered: rrset[0].rtype,
+		algorithm: ALGORITHM,
+		labels: labels(&owner).len() as u8,
+		original_ttl: rrset[0].ttl,
+		expiration: (now.timestamp() - INCEPTION_OFFSET + Duration::days(validity as i64).num_seconds()) as u32,
+		inception: (now.timestamp() - INCEPTION_OFFSET) as u32,
+		key_tag: key.tag(),
+		signer: zone.name.to_lowercase(),
+		signature: Vec::new(),
+	};
+
+	// RRSIG rdata without signature followed by the RRset
+	// in canonical form and order (RFC 4034 section 3.1.8.1)
+	let mut data = RData::Rrsig(rrsig.clone()).to_canonical_vec()?;
+	let mut rdatas: Vec<Vec<u8>> = rrset.iter().map(|r| r.data.to_canonical_vec()).collect::<Result<_, _>>()?;
+	rdatas.sort();
+	rdatas.dedup();
+	for rdata in rdatas {
+		write_name(&mut data, &owner)?;
+		data.extend_from_slice(&rrset[0].rtype.to_be_bytes());
+		data.extend_from_slice(&CLASS_IN.to_be_bytes());
+		data.extend_from_slice(&rrsig.original_ttl.to_be_bytes());
+		data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+		data.extend_from_slice(&rdata);
+	}
+
+	rrsig.signature = ed25519::signature(&data, &key.keypair().0).to_vec();
+	Ok(Record::new(&rrset[0].name, rrset[0].ttl, RData::Rrsig(rrsig)))
+}
+
+
+/// NSEC record that only denies the types of `name` and no other
+/// name (compact denial of existence, RFC 9824). Used for online signing,
+/// where a nonexistent name gets NXNAME in its types.
+pub fn compact_denial(zone: &config::Domain, name: &str, types: &[u16]) -> Record {
+	let mut types = types.to_vec();
+	types.extend_from_slice(&[rtype::RRSIG, rtype::NSEC]);
+	types.sort_unstable();
+	types.dedup();
+
+	Record::new(name, zone.soa.ttl.min(zone.soa.minimum), RData::Nsec(Nsec {
+		next: format!("\\000.{}", name.trim_start_matches('.')),
+		types,
+	}))
+}
+
+
+/// NSEC records linking all names of the zone in canonical order
+fn nsec_chain(zone: &config::Domain, records: &[Record]) -> Vec<Record> {
+	let mut names: BTreeMap<Vec<Vec<u8>>, (&str, Vec<u16>)> = BTreeMap::new();
+	for r in records {
+		let (_, types) = names.entry(canonical_labels(&r.name))
+			.or_insert_with(|| (&r.name, vec![rtype::RRSIG, rtype::NSEC]));
+		types.push(r.rtype);
+	}
+
+	let ttl = zone.soa.ttl.min(zone.soa.minimum);
+	let owners: Vec<&str> = names.values().map(|(name, _)| *name).collect();
+	names.into_iter()
+		.enumerate()
+		.map(|(i, (_, (name, mut types)))| {
+			types.sort_unstable();
+			types.dedup();
+
+			let next = owners[(i + 1) % owners.len()];
+			Record::new(name, ttl, RData::Nsec(Nsec { next: next.to_string(), types }))
+		})
+		.collect()
+}
+
+
+/// labels from the root on and lowercase, sorts
+/// names in canonical order (RFC 4034 section 6.1)
+fn canonical_labels(name: &str) -> Vec<Vec<u8>> {
+	labels(name).into_iter().rev().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+
+/// adds DNSKEY, NSEC and RRSIG records to a complete zone
+pub fn sign_zone(zone: &config::Domain, keys: &[Key], mut records: Vec<Record>, now: DateTime<Utc>) -> Vec<Record> {
+	records.extend(dnskey_records(zone, keys, now));
+	let nsec = nsec_chain(zone, &records);
+	records.extend(nsec);
+	let sigs = sign(zone, keys, &records, now);
+	records.extend(sigs);
+	records
+}
+
+
+
+#[test]
+fn rfc8080_example() {
+	// example 1 of RFC 8080 section 6
+	let key = Key {
+		ksk: true,
+		seed: "ODIyNjAzODQ2MjgwODAxMjI2NDUxOTAyMDQxNDIyNjI=".to_string(),
+		publish: Utc::now(),
+		activate: Utc::now(),
+		retire: None,
+		remove: None,
+	};
+	assert_eq!(base64::encode(&key.dnskey().public_key), "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=");
+	assert_eq!(key.tag(), 3613);
+	assert_eq!(key.ds("example.com.").unwrap(), "example.com. IN DS 3613 15 2 3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b");
+
+	let zone: config::Domain = toml::from_str(r#"
+		name = "example.com."
+		description = "test"
+		allowed_ips = []
+		validity = 1
+		[dnssec]
+		signature_validity = 21
+	"#).unwrap();
+
+	// MX 10 mail.example.com.
+	let mut mx = vec![0, 10];
+	write_name(&mut mx, "mail.example.com.").unwrap();
+	let mx = Record { name: "example.com.".to_string(), rtype: 15, class: CLASS_IN, ttl: 3600, data: RData::Raw(mx) };
+
+	// inception 1438207200
+	let now = DateTime::parse_from_rfc3339("2015-07-29T23:00:00Z").unwrap().with_timezone(&Utc);
+	let sig = sign_rrset(&zone, &key, &[&mx], now).unwrap();
+	assert_eq!(sig.to_string(), "example.com. 3600 IN RRSIG TYPE15 15 2 3600 1440021600 1438207200 3613 example.com. \
+		oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==");
+}
+
+
+#[test]
+fn zsk_rollover() {
+	let conf = config::Dnssec { signature_validity: 14, zsk_lifetime: 30 };
+	let start = Utc::now();
+	let mut keys = Vec::new();
+
+	assert!(schedule(&conf, &mut keys, start));
+	assert_eq!(keys.len(), 2);
+	assert!(!schedule(&conf, &mut keys, start + Duration::days(1)));
+
+	// successor is published before it signs
+	let t = start + Duration::days(29);
+	assert!(schedule(&conf, &mut keys, t));
+	assert_eq!(keys.len(), 3);
+	let zsk = |keys: &[Key], t| keys.iter().filter(|k| !k.ksk && k.is_signing(t)).count();
+	assert_eq!(zsk(&keys, t), 1);
+	assert!(!keys[2].is_signing(t) && keys[2].is_published(t));
+
+	// the old key stays published until its signatures expired
+	let t = start + Duration::days(31);
+	assert_eq!(zsk(&keys, t), 1);
+	assert!(keys[2].is_signing(t) && keys[1].is_published(t));
+	assert!(!schedule(&conf, &mut keys, t));
+
+	let t = start + Duration::days(45);
+	assert!(schedule(&conf, &mut keys, t));
+	assert_eq!(keys.len(), 2);
+	assert!(keys[0].ksk);
+}
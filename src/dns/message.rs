@@ -0,0 +1,998 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+
+// minimal implementation of the DNS wire format (RFC 1035).
+// Names are kept as written in the message (with trailing dot),
+// so compare them with `name_eq`.
+
+
+pub mod rtype {
+	pub const A: u16 = 1;
+	pub const NS: u16 = 2;
+	pub const SOA: u16 = 6;
+	pub const TXT: u16 = 16;
+	pub const AAAA: u16 = 28;
+	pub const OPT: u16 = 41;
+	pub const DS: u16 = 43;
+	pub const RRSIG: u16 = 46;
+	pub const NSEC: u16 = 47;
+	pub const DNSKEY: u16 = 48;
+	/// marks a non-existent name in compact denial (RFC 9824)
+	pub const NXNAME: u16 = 128;
+	pub const TSIG: u16 = 250;
+	pub const IXFR: u16 = 251;
+	pub const AXFR: u16 = 252;
+	pub const ANY: u16 = 255;
+}
+
+pub mod rcode {
+	pub const NOERROR: u8 = 0;
+	pub const FORMERR: u8 = 1;
+	pub const SERVFAIL: u8 = 2;
+	pub const NXDOMAIN: u8 = 3;
+	pub const NOTIMP: u8 = 4;
+	pub const REFUSED: u8 = 5;
+	pub const YXDOMAIN: u8 = 6;
+	pub const YXRRSET: u8 = 7;
+	pub const NXRRSET: u8 = 8;
+	pub const NOTAUTH: u8 = 9;
+}
+
+pub mod opcode {
+	pub const QUERY: u8 = 0;
+	pub const NOTIFY: u8 = 4;
+	pub const UPDATE: u8 = 5;
+}
+
+pub const CLASS_IN: u16 = 1;
+/// used by UPDATE messages to delete single records
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+/// DNSSEC OK bit in the ttl of the OPT record
+const EDNS_DO: u32 = 0x8000;
+
+const HEADER_SIZE: usize = 12;
+/// limits of RFC 1035 section 2.3.4, the name in wire format
+pub const MAX_LABEL_SIZE: usize = 63;
+pub const MAX_NAME_SIZE: usize = 255;
+const MAX_NAME_LENGTH: usize = 255;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError(&'static str);
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "malformed dns message: {}", self.0)
+	}
+}
+
+
+/// a name that does not fit into the wire format
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameError(String);
+
+impl fmt::Display for NameError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "cannot write name {}: labels have at most {} and names {} bytes", self.0, MAX_LABEL_SIZE, MAX_NAME_SIZE)
+	}
+}
+
+impl From<NameError> for String {
+	fn from(e: NameError) -> Self {
+		e.to_string()
+	}
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+	pub name: String,
+	pub qtype: u16,
+	pub qclass: u16,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Soa {
+	pub mname: String,
+	pub rname: String,
+	pub serial: u32,
+	pub refresh: u32,
+	pub retry: u32,
+	pub expire: u32,
+	pub minimum: u32,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dnskey {
+	pub flags: u16,
+	pub algorithm: u8,
+	pub public_key: Vec<u8>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rrsig {
+	pub type_covered: u16,
+	pub algorithm: u8,
+	pub labels: u8,
+	pub original_ttl: u32,
+	pub expiration: u32,
+	pub inception: u32,
+	pub key_tag: u16,
+	pub signer: String,
+	pub signature: Vec<u8>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsec {
+	pub next: String,
+	/// types present at the owner name, sorted
+	pub types: Vec<u16>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+	A(Ipv4Addr),
+	Aaaa(Ipv6Addr),
+	Ns(String),
+	Soa(Soa),
+	Txt(Vec<String>),
+	Dnskey(Dnskey),
+	Rrsig(Rrsig),
+	Nsec(Nsec),
+	/// everything we don't know, and empty rdata
+	Raw(Vec<u8>),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+	pub name: String,
+	pub rtype: u16,
+	pub class: u16,
+	pub ttl: u32,
+	pub data: RData,
+}
+
+impl Record {
+	pub fn new(name: &str, ttl: u32, data: RData) -> Self {
+		let rtype = match &data {
+			RData::A(_) => rtype::A,
+			RData::Aaaa(_) => rtype::AAAA,
+			RData::Ns(_) => rtype::NS,
+			RData::Soa(_) => rtype::SOA,
+			RData::Txt(_) => rtype::TXT,
+			RData::Dnskey(_) => rtype::DNSKEY,
+			RData::Rrsig(_) => rtype::RRSIG,
+			RData::Nsec(_) => rtype::NSEC,
+			RData::Raw(_) => panic!("raw records need an explicit type"),
+		};
+
+		Self {
+			name: name.to_string(),
+			rtype,
+			class: CLASS_IN,
+			ttl,
+			data,
+		}
+	}
+
+	/// size of the record in uncompressed wire format
+	pub fn wire_size(&self) -> Result<usize, NameError> {
+		let mut out = Vec::new();
+		self.write(&mut out)?;
+		Ok(out.len())
+	}
+
+	fn write(&self, out: &mut Vec<u8>) -> Result<(), NameError> {
+		write_name(out, &self.name)?;
+		out.extend_from_slice(&self.rtype.to_be_bytes());
+		out.extend_from_slice(&self.class.to_be_bytes());
+		out.extend_from_slice(&self.ttl.to_be_bytes());
+
+		let rdata = self.data.to_vec()?;
+		out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+		out.extend_from_slice(&rdata);
+		Ok(())
+	}
+}
+
+
+/// master file format (RFC 1035 section 5), one record per line
+impl fmt::Display for Record {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {} ", escape_name(&self.name), self.ttl)?;
+		if self.class == CLASS_IN {
+			write!(f, "IN ")?;
+		} else {
+			write!(f, "CLASS{} ", self.class)?;
+		}
+
+		match &self.data {
+			RData::A(a) => write!(f, "A {}", a),
+			RData::Aaaa(a) => write!(f, "AAAA {}", a),
+			RData::Ns(n) => write!(f, "NS {}", escape_name(n)),
+			RData::Soa(s) => write!(f, "SOA {} {} {} {} {} {} {}",
+				escape_name(&s.mname), escape_name(&s.rname), s.serial, s.refresh, s.retry, s.expire, s.minimum),
+			RData::Txt(strings) => {
+				write!(f, "TXT")?;
+				strings.iter().try_for_each(|s| write!(f, " {}", quote_text(s)))
+			}
+			RData::Dnskey(k) => write!(f, "DNSKEY {} 3 {} {}", k.flags, k.algorithm, base64::encode(&k.public_key)),
+			RData::Rrsig(s) => write!(f, "RRSIG {} {} {} {} {} {} {} {} {}",
+				type_name(s.type_covered), s.algorithm, s.labels, s.original_ttl,
+				s.expiration, s.inception, s.key_tag, escape_name(&s.signer), base64::encode(&s.signature)),
+			RData::Nsec(n) => {
+				write!(f, "NSEC {}", escape_name(&n.next))?;
+				n.types.iter().try_for_each(|t| write!(f, " {}", type_name(*t)))
+			}
+			// unknown types in the generic format of RFC 3597
+			RData::Raw(r) => {
+				write!(f, "TYPE{} \\# {}", self.rtype, r.len())?;
+				if !r.is_empty() {
+					write!(f, " ")?;
+				}
+				r.iter().try_for_each(|b| write!(f, "{:02x}", b))
+			}
+		}
+	}
+}
+
+
+/// mnemonic of a record type for the master file format
+pub fn type_name(t: u16) -> String {
+	match t {
+		rtype::A => "A".to_string(),
+		rtype::NS => "NS".to_string(),
+		rtype::SOA => "SOA".to_string(),
+		rtype::TXT => "TXT".to_string(),
+		rtype::AAAA => "AAAA".to_string(),
+		rtype::DS => "DS".to_string(),
+		rtype::RRSIG => "RRSIG".to_string(),
+		rtype::NSEC => "NSEC".to_string(),
+		rtype::DNSKEY => "DNSKEY".to_string(),
+		rtype::NXNAME => "NXNAME".to_string(),
+		_ => format!("TYPE{}", t),
+	}
+}
+
+
+/// a name in the master file format. Dots inside a label become `\.`,
+/// whitespace and other bytes that would end or change the record `\DDD`.
+pub fn escape_name(name: &str) -> String {
+	let mut out = String::new();
+	for label in labels(name) {
+		for b in label {
+			match b {
+				b'.' | b';' | b'(' | b')' | b'"' | b'\\' | b'$' | b'@' => {
+					out.push('\\');
+					out.push(b as char);
+				}
+				0x21..=0x7e => out.push(b as char),
+				_ => out.push_str(&format!("\\{:03}", b)),
+			}
+		}
+		out.push('.');
+	}
+
+	if out.is_empty() {
+		out.push('.');
+	}
+	out
+}
+
+
+/// a text in the master file format, quoted and split into
+/// character strings of at most 255 bytes
+pub fn quote_text(s: &str) -> String {
+	s.as_bytes()
+		.chunks(255)
+		.map(|chunk| format!("\"{}\"", escape_text(chunk)))
+		.collect::<Vec<String>>()
+		.join(" ")
+}
+
+
+/// escapes a character string for the master file format
+fn escape_text(s: &[u8]) -> String {
+	let mut out = String::new();
+	for b in s {
+		match b {
+			b'"' | b'\\' => {
+				out.push('\\');
+				out.push(*b as char);
+			}
+			0x20..=0x7e => out.push(*b as char),
+			_ => out.push_str(&format!("\\{:03}", b)),
+		}
+	}
+	out
+}
+
+
+impl RData {
+	/// uncompressed wire format of the rdata
+	pub fn to_vec(&self) -> Result<Vec<u8>, NameError> {
+		let mut out = Vec::new();
+		match self {
+			Self::A(a) => out.extend_from_slice(&a.octets()),
+			Self::Aaaa(a) => out.extend_from_slice(&a.octets()),
+			Self::Ns(n) => write_name(&mut out, n)?,
+			Self::Soa(s) => {
+				write_name(&mut out, &s.mname)?;
+				write_name(&mut out, &s.rname)?;
+				for v in &[s.serial, s.refresh, s.retry, s.expire, s.minimum] {
+					out.extend_from_slice(&v.to_be_bytes());
+				}
+			}
+			Self::Txt(strings) => {
+				for s in strings {
+					// character strings are limited to 255 bytes
+					for chunk in s.as_bytes().chunks(255) {
+						out.push(chunk.len() as u8);
+						out.extend_from_slice(chunk);
+					}
+				}
+			}
+			Self::Dnskey(k) => {
+				out.extend_from_slice(&k.flags.to_be_bytes());
+				// protocol is always 3 (RFC 4034)
+				out.push(3);
+				out.push(k.algorithm);
+				out.extend_from_slice(&k.public_key);
+			}
+			Self::Rrsig(s) => {
+				out.extend_from_slice(&s.type_covered.to_be_bytes());
+				out.push(s.algorithm);
+				out.push(s.labels);
+				for v in &[s.original_ttl, s.expiration, s.inception] {
+					out.extend_from_slice(&v.to_be_bytes());
+				}
+				out.extend_from_slice(&s.key_tag.to_be_bytes());
+				write_name(&mut out, &s.signer)?;
+				out.extend_from_slice(&s.signature);
+			}
+			Self::Nsec(n) => {
+				write_name(&mut out, &n.next)?;
+				write_type_bitmap(&mut out, &n.types);
+			}
+			Self::Raw(r) => out.extend_from_slice(r),
+		}
+		Ok(out)
+	}
+
+	/// wire format in the canonical form of RFC 4034 section 6.2,
+	/// the names in the rdata are lowercase
+	pub fn to_canonical_vec(&self) -> Result<Vec<u8>, NameError> {
+		match self {
+			Self::Ns(n) => Self::Ns(n.to_lowercase()).to_vec(),
+			Self::Soa(s) => Self::Soa(Soa {
+				mname: s.mname.to_lowercase(),
+				rname: s.rname.to_lowercase(),
+				..s.clone()
+			}).to_vec(),
+			Self::Rrsig(s) => Self::Rrsig(Rrsig {
+				signer: s.signer.to_lowercase(),
+				..s.clone()
+			}).to_vec(),
+			_ => self.to_vec(),
+		}
+	}
+
+	fn parse(buf: &[u8], rtype: u16, start: usize, len: usize) -> Result<Self, ParseError> {
+		let end = start + len;
+		let rdata = buf.get(start..end).ok_or(ParseError("rdata exceeds message"))?;
+
+		// empty rdata is used by UPDATE messages to delete records
+		if len == 0 {
+			return Ok(Self::Raw(Vec::new()));
+		}
+
+		Ok(match rtype {
+			rtype::A if len == 4 => {
+				Self::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+			}
+			rtype::AAAA if len == 16 => {
+				let mut octets = [0u8; 16];
+				octets.copy_from_slice(rdata);
+				Self::Aaaa(Ipv6Addr::from(octets))
+			}
+			rtype::NS => Self::Ns(read_name(buf, start)?.0),
+			rtype::SOA => {
+				let (mname, pos) = read_name(buf, start)?;
+				let (rname, pos) = read_name(buf, pos)?;
+				let mut values = [0u32; 5];
+				for (i, v) in values.iter_mut().enumerate() {
+					*v = read_u32(buf, pos + i * 4)?;
+				}
+				Self::Soa(Soa {
+					mname,
+					rname,
+					serial: values[0],
+					refresh: values[1],
+					retry: values[2],
+					expire: values[3],
+					minimum: values[4],
+				})
+			}
+			rtype::TXT => {
+				let mut strings = Vec::new();
+				let mut pos = 0;
+				while pos < len {
+					let l = rdata[pos] as usize;
+					let s = rdata.get(pos + 1..pos + 1 + l).ok_or(ParseError("txt string exceeds rdata"))?;
+					strings.push(String::from_utf8_lossy(s).to_string());
+					pos += 1 + l;
+				}
+				Self::Txt(strings)
+			}
+			_ => Self::Raw(rdata.to_vec()),
+		})
+	}
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+	pub id: u16,
+	pub flags: u16,
+	pub questions: Vec<Question>,
+	pub answers: Vec<Record>,
+	pub authority: Vec<Record>,
+	pub additional: Vec<Record>,
+}
+
+impl Message {
+	pub fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+		Self::parse_with_tsig_offset(buf).map(|(msg, _)| msg)
+	}
+
+	/// parses a message and returns the position of its TSIG record,
+	/// which has to be the last record of the message
+	pub fn parse_with_tsig_offset(buf: &[u8]) -> Result<(Self, Option<usize>), ParseError> {
+		if buf.len() < HEADER_SIZE {
+			return Err(ParseError("message shorter than header"));
+		}
+
+		let mut msg = Self {
+			id: read_u16(buf, 0)?,
+			flags: read_u16(buf, 2)?,
+			questions: Vec::new(),
+			answers: Vec::new(),
+			authority: Vec::new(),
+			additional: Vec::new(),
+		};
+
+		let qdcount = read_u16(buf, 4)?;
+		let ancount = read_u16(buf, 6)?;
+		let nscount = read_u16(buf, 8)?;
+		let arcount = read_u16(buf, 10)?;
+
+		let mut pos = HEADER_SIZE;
+		let mut last_record = pos;
+		for _ in 0..qdcount {
+			let (name, p) = read_name(buf, pos)?;
+			msg.questions.push(Question {
+				name,
+				qtype: read_u16(buf, p)?,
+				qclass: read_u16(buf, p + 2)?,
+			});
+			pos = p + 4;
+		}
+
+		for (count, section) in [
+			(ancount, &mut msg.answers),
+			(nscount, &mut msg.authority),
+			(arcount, &mut msg.additional),
+		] {
+			for _ in 0..count {
+				let (record, p) = read_record(buf, pos)?;
+				section.push(record);
+				last_record = pos;
+				pos = p;
+			}
+		}
+
+		let tsig_offset = match msg.additional.last() {
+			Some(r) if r.rtype == rtype::TSIG => Some(last_record),
+			_ => None,
+		};
+		let tsig_count = msg.answers.iter()
+			.chain(&msg.authority)
+			.chain(&msg.additional)
+			.filter(|r| r.rtype == rtype::TSIG)
+			.count();
+		if tsig_count > tsig_offset.iter().count() {
+			return Err(ParseError("tsig record is not the last record"));
+		}
+
+		Ok((msg, tsig_offset))
+	}
+
+	/// uncompressed wire format of the message. Fails if
+	/// a name is too long for it.
+	pub fn to_vec(&self) -> Result<Vec<u8>, NameError> {
+		let mut out = Vec::with_capacity(512);
+		out.extend_from_slice(&self.id.to_be_bytes());
+		out.extend_from_slice(&self.flags.to_be_bytes());
+		for count in &[
+			self.questions.len(),
+			self.answers.len(),
+			self.authority.len(),
+			self.additional.len(),
+		] {
+			out.extend_from_slice(&(*count as u16).to_be_bytes());
+		}
+
+		for q in &self.questions {
+			write_name(&mut out, &q.name)?;
+			out.extend_from_slice(&q.qtype.to_be_bytes());
+			out.extend_from_slice(&q.qclass.to_be_bytes());
+		}
+
+		for r in self.answers.iter().chain(&self.authority).chain(&self.additional) {
+			r.write(&mut out)?;
+		}
+
+		Ok(out)
+	}
+
+	/// creates a request with a single question
+	pub fn request(id: u16, opcode: u8, question: Question) -> Self {
+		Self {
+			id,
+			flags: (opcode as u16) << 11,
+			questions: vec![question],
+			answers: Vec::new(),
+			authority: Vec::new(),
+			additional: Vec::new(),
+		}
+	}
+
+	/// creates an authoritative response with id, opcode, rd flag and question copied
+	pub fn response(&self, rcode: u8) -> Self {
+		Self {
+			id: self.id,
+			flags: FLAG_QR | FLAG_AA | (self.flags & (0x7800 | FLAG_RD)) | rcode as u16,
+			questions: self.questions.clone(),
+			answers: Vec::new(),
+			authority: Vec::new(),
+			additional: Vec::new(),
+		}
+	}
+
+	/// response to a message we could not even parse
+	pub fn error_response(buf: &[u8], rcode: u8) -> Option<Vec<u8>> {
+		let id = read_u16(buf, 0).ok()?;
+		let flags = read_u16(buf, 2).ok()?;
+		if flags & FLAG_QR != 0 {
+			return None;
+		}
+
+		let msg = Self {
+			id,
+			flags: FLAG_QR | (flags & (0x7800 | FLAG_RD)) | rcode as u16,
+			questions: Vec::new(),
+			answers: Vec::new(),
+			authority: Vec::new(),
+			additional: Vec::new(),
+		};
+		msg.to_vec().ok()
+	}
+
+	pub fn opcode(&self) -> u8 {
+		((self.flags >> 11) & 0xf) as u8
+	}
+
+	pub fn rcode(&self) -> u8 {
+		(self.flags & 0xf) as u8
+	}
+
+	pub fn set_rcode(&mut self, rcode: u8) {
+		self.flags = (self.flags & !0xf) | rcode as u16;
+	}
+
+	pub fn is_response(&self) -> bool {
+		self.flags & FLAG_QR != 0
+	}
+
+	/// udp payload size and DNSSEC OK bit of the OPT record, if the message has one
+	pub fn edns(&self) -> Option<(u16, bool)> {
+		self.additional
+			.iter()
+			.find(|r| r.rtype == rtype::OPT)
+			.map(|r| (r.class, r.ttl & EDNS_DO != 0))
+	}
+
+	/// adds an OPT record (RFC 6891) to the additional section
+	pub fn set_edns(&mut self, udp_size: u16, dnssec_ok: bool) {
+		self.additional.retain(|r| r.rtype != rtype::OPT);
+		self.additional.push(Record {
+			name: ".".to_string(),
+			rtype: rtype::OPT,
+			class: udp_size,
+			ttl: if dnssec_ok { EDNS_DO } else { 0 },
+			data: RData::Raw(Vec::new()),
+		});
+	}
+
+	pub fn set_authoritative(&mut self, aa: bool) {
+		if aa {
+			self.flags |= FLAG_AA;
+		} else {
+			self.flags &= !FLAG_AA;
+		}
+	}
+
+	/// if the message is larger than `max` only the header
+	/// and question are kept and the TC flag is set
+	pub fn truncate(self, max: usize) -> Self {
+		if self.to_vec().map_or(true, |m| m.len() <= max) {
+			return self;
+		}
+
+		let mut tc = self.response(self.rcode());
+		tc.flags = self.flags | FLAG_TC;
+		tc.additional = self.additional.into_iter().filter(|r| r.rtype == rtype::OPT).collect();
+		tc
+	}
+}
+
+
+/// case insensitive comparison of domain names
+pub fn name_eq(a: &str, b: &str) -> bool {
+	a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+
+/// true if name is zone or below zone
+pub fn name_in_zone(name: &str, zone: &str) -> bool {
+	let name = name.trim_end_matches('.').to_ascii_lowercase();
+	let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+	zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16, ParseError> {
+	buf.get(pos..pos + 2)
+		.map(|b| u16::from_be_bytes([b[0], b[1]]))
+		.ok_or(ParseError("unexpected end of message"))
+}
+
+
+fn read_u32(buf: &[u8], pos: usize) -> Result<u32, ParseError> {
+	buf.get(pos..pos + 4)
+		.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+		.ok_or(ParseError("unexpected end of message"))
+}
+
+
+/// reads a possibly compressed name, returns the name and
+/// the position after the name
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize), ParseError> {
+	let mut name = String::new();
+	let mut end = None;
+	let mut jumps = 0;
+
+	loop {
+		let len = *buf.get(pos).ok_or(ParseError("unexpected end of name"))? as usize;
+
+		if len & 0xc0 == 0xc0 {
+			let ptr = ((len & 0x3f) << 8) | *buf.get(pos + 1).ok_or(ParseError("unexpected end of name"))? as usize;
+			if end.is_none() {
+				end = Some(pos + 2);
+			}
+			jumps += 1;
+			if jumps > 64 {
+				return Err(ParseError("compression loop"));
+			}
+			pos = ptr;
+			continue;
+		}
+
+		if len & 0xc0 != 0 {
+			return Err(ParseError("unknown label type"));
+		}
+
+		pos += 1;
+		if len == 0 {
+			break;
+		}
+
+		let label = buf.get(pos..pos + len).ok_or(ParseError("label exceeds message"))?;
+		for c in String::from_utf8_lossy(label).chars() {
+			if c == '.' || c == '\\' {
+				name.push('\\');
+			}
+			name.push(c);
+		}
+		name.push('.');
+		pos += len;
+
+		if name.len() > MAX_NAME_LENGTH {
+			return Err(ParseError("name too long"));
+		}
+	}
+
+	if name.is_empty() {
+		name.push('.');
+	}
+
+	Ok((name, end.unwrap_or(pos)))
+}
+
+
+fn read_record(buf: &[u8], pos: usize) -> Result<(Record, usize), ParseError> {
+	let (name, pos) = read_name(buf, pos)?;
+	let rtype = read_u16(buf, pos)?;
+	let class = read_u16(buf, pos + 2)?;
+	let ttl = read_u32(buf, pos + 4)?;
+	let rdlen = read_u16(buf, pos + 8)? as usize;
+	let start = pos + 10;
+
+	let data = RData::parse(buf, rtype, start, rdlen)?;
+
+	Ok((Record { name, rtype, class, ttl, data }, start + rdlen))
+}
+
+
+/// appends a name in uncompressed wire format. Names with a label
+/// of more than 63 bytes or more than 255 bytes in all are refused.
+pub fn write_name(out: &mut Vec<u8>, name: &str) -> Result<(), NameError> {
+	let labels = labels(name);
+	let size = labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1;
+	if size > MAX_NAME_SIZE || labels.iter().any(|l| l.len() > MAX_LABEL_SIZE) {
+		return Err(NameError(name.to_string()));
+	}
+
+	for label in labels {
+		out.push(label.len() as u8);
+		out.extend_from_slice(&label);
+	}
+	out.push(0);
+	Ok(())
+}
+
+
+/// splits a name into its labels, `\.` is a dot inside
+/// a label and `\DDD` a byte in decimal
+pub fn labels(name: &str) -> Vec<Vec<u8>> {
+	let mut labels = Vec::new();
+	let mut label = Vec::new();
+	let bytes = name.as_bytes();
+	let mut i = 0;
+
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\\' => {
+				let decimal = bytes.get(i + 1..i + 4)
+					.filter(|d| d.iter().all(u8::is_ascii_digit))
+					.and_then(|d| String::from_utf8_lossy(d).parse::<u8>().ok());
+				match decimal {
+					Some(b) => {
+						label.push(b);
+						i += 4;
+					}
+					None => {
+						label.extend(bytes.get(i + 1));
+						i += 2;
+					}
+				}
+			}
+			b'.' => {
+				labels.push(std::mem::take(&mut label));
+				i += 1;
+			}
+			b => {
+				label.push(b);
+				i += 1;
+			}
+		}
+	}
+	labels.push(label);
+
+	labels.retain(|l| !l.is_empty());
+	labels
+}
+
+
+/// the type bit maps field of NSEC records (RFC 4034 section 4.1.2)
+fn write_type_bitmap(out: &mut Vec<u8>, types: &[u16]) {
+	let mut windows: Vec<(u8, Vec<u8>)> = Vec::new();
+
+	for t in types {
+		let (window, bit) = ((t >> 8) as u8, (t & 0xff) as usize);
+		if windows.last().map(|(w, _)| *w) != Some(window) {
+			windows.push((window, Vec::new()));
+		}
+
+		let bitmap = &mut windows.last_mut().unwrap().1;
+		if bitmap.len() <= bit / 8 {
+			bitmap.resize(bit / 8 + 1, 0);
+		}
+		bitmap[bit / 8] |= 0x80 >> (bit % 8);
+	}
+
+	for (window, bitmap) in windows {
+		out.push(window);
+		out.push(bitmap.len() as u8);
+		out.extend_from_slice(&bitmap);
+	}
+}
+
+
+
+#[test]
+fn parse_query() {
+	// dig foo.ffhl.de A, id 0x1234, rd
+	let query = [
+		0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		3, b'f', b'o', b'o', 4, b'f', b'f', b'h', b'l', 2, b'd', b'e', 0,
+		0x00, 0x01, 0x00, 0x01,
+	];
+
+	let msg = Message::parse(&query).unwrap();
+	assert_eq!(msg.id, 0x1234);
+	assert_eq!(msg.opcode(), opcode::QUERY);
+	assert!(!msg.is_response());
+	assert_eq!(msg.questions, vec![Question {
+		name: "foo.ffhl.de.".to_string(),
+		qtype: rtype::A,
+		qclass: CLASS_IN,
+	}]);
+	assert_eq!(msg.to_vec().unwrap(), query.to_vec());
+}
+
+
+#[test]
+fn response_roundtrip() {
+	let query = Message {
+		id: 7,
+		flags: FLAG_RD,
+		questions: vec![Question { name: "Foo.ffhl.de.".to_string(), qtype: rtype::ANY, qclass: CLASS_IN }],
+		answers: Vec::new(),
+		authority: Vec::new(),
+		additional: Vec::new(),
+	};
+
+	let mut resp = query.response(rcode::NOERROR);
+	resp.answers.push(Record::new("Foo.ffhl.de.", 60, RData::A("10.0.0.1".parse().unwrap())));
+	resp.answers.push(Record::new("Foo.ffhl.de.", 60, RData::Aaaa("fd00::1".parse().unwrap())));
+	resp.answers.push(Record::new("Foo.ffhl.de.", 60, RData::Txt(vec!["hello".to_string()])));
+	resp.authority.push(Record::new("ffhl.de.", 30, RData::Soa(Soa {
+		mname: "ns.ffhl.de.".to_string(),
+		rname: "hostmaster.ffhl.de.".to_string(),
+		serial: 1,
+		refresh: 3600,
+		retry: 600,
+		expire: 2600,
+		minimum: 30,
+	})));
+	resp.additional.push(Record::new("ffhl.de.", 30, RData::Ns("ns.ffhl.de.".to_string())));
+
+	let parsed = Message::parse(&resp.to_vec().unwrap()).unwrap();
+	assert!(parsed.is_response());
+	assert_eq!(parsed.opcode(), opcode::QUERY);
+	assert_eq!(parsed, resp);
+}
+
+
+#[test]
+fn compressed_names() {
+	// response with the answer name pointing to the question name
+	let msg = [
+		0x00, 0x01, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+		3, b'f', b'o', b'o', 4, b'f', b'f', b'h', b'l', 2, b'd', b'e', 0,
+		0x00, 0x02, 0x00, 0x01,
+		0xc0, 12, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x05,
+		2, b'n', b's', 0xc0, 16,
+	];
+
+	let parsed = Message::parse(&msg).unwrap();
+	assert_eq!(parsed.answers[0].name, "foo.ffhl.de.");
+	assert_eq!(parsed.answers[0].data, RData::Ns("ns.ffhl.de.".to_string()));
+
+	// pointer to itself
+	let mut looped = msg.to_vec();
+	looped[12] = 0xc0;
+	looped[13] = 12;
+	assert!(Message::parse(&looped).is_err());
+}
+
+
+#[test]
+fn zone_membership() {
+	assert!(name_in_zone("foo.ffhl.de.", "ffhl.de."));
+	assert!(name_in_zone("FOO.FFHL.DE", "ffhl.de."));
+	assert!(name_in_zone("ffhl.de.", "ffhl.de."));
+	assert!(!name_in_zone("fooffhl.de.", "ffhl.de."));
+	assert!(!name_in_zone("de.", "ffhl.de."));
+	assert!(name_eq("Foo.ffhl.de.", "foo.ffhl.de"));
+}
+
+
+#[test]
+fn presentation_format() {
+	let txt = Record::new("foo.ffhl.de.", 60, RData::Txt(vec!["say \"hi\"\n".to_string()]));
+	assert_eq!(txt.to_string(), "foo.ffhl.de. 60 IN TXT \"say \\\"hi\\\"\\010\"");
+
+	let soa = Record::new("ffhl.de.", 30, RData::Soa(Soa {
+		mname: "ns.ffhl.de.".to_string(),
+		rname: "hostmaster.ffhl.de.".to_string(),
+		serial: 1,
+		refresh: 3600,
+		retry: 600,
+		expire: 2600,
+		minimum: 30,
+	}));
+	assert_eq!(soa.to_string(), "ffhl.de. 30 IN SOA ns.ffhl.de. hostmaster.ffhl.de. 1 3600 600 2600 30");
+
+	// dots in the local part of the rname stay in the label
+	let mut wire = Vec::new();
+	write_name(&mut wire, "john\\.doe.ffhl.de.").unwrap();
+	assert_eq!(&wire[..9], b"\x08john.doe");
+	assert_eq!(read_name(&wire, 0).unwrap().0, "john\\.doe.ffhl.de.");
+	assert_eq!(labels("\\000.foo.ffhl.de."), vec![vec![0], b"foo".to_vec(), b"ffhl".to_vec(), b"de".to_vec()]);
+
+	// names cannot end the record or add another one
+	assert_eq!(escape_name("john\\.doe.ffhl.de."), "john\\.doe.ffhl.de.");
+	assert_eq!(escape_name("\\000.foo.ffhl.de"), "\\000.foo.ffhl.de.");
+	assert_eq!(escape_name("."), ".");
+	let evil = Record::new("x.ffhl.de. 60 IN A 192.0.2.1\n(y;z).ffhl.de.", 60, RData::A("10.0.0.1".parse().unwrap()));
+	assert_eq!(evil.to_string(), "x.ffhl.de.\\03260\\032IN\\032A\\032192.0.2.1\\010\\(y\\;z\\).ffhl.de. 60 IN A 10.0.0.1");
+
+	let raw = Record { name: "ffhl.de.".to_string(), rtype: 99, class: CLASS_IN, ttl: 0, data: RData::Raw(vec![1, 255]) };
+	assert_eq!(raw.to_string(), "ffhl.de. 0 IN TYPE99 \\# 2 01ff");
+}
+
+
+#[test]
+fn nsec_type_bitmap() {
+	// example from RFC 4034 section 4.3
+	let nsec = RData::Nsec(Nsec {
+		next: "host.example.com.".to_string(),
+		types: vec![rtype::A, 15, rtype::RRSIG, rtype::NSEC, 1234],
+	});
+	let mut expected = Vec::new();
+	write_name(&mut expected, "host.example.com.").unwrap();
+	expected.extend_from_slice(&[
+		0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03,
+		0x04, 0x1b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		0x00, 0x00, 0x00, 0x00, 0x20,
+	]);
+	assert_eq!(nsec.to_vec(), Ok(expected));
+}
+
+
+#[test]
+fn name_limits() {
+	let mut wire = Vec::new();
+	let label = "a".repeat(63);
+	write_name(&mut wire, &format!("{}.ffhl.de.", label)).unwrap();
+	assert_eq!(wire[0], 63);
+
+	// nothing is written for a name that does not fit
+	wire.clear();
+	assert!(write_name(&mut wire, &format!("a{}.ffhl.de.", label)).is_err());
+	assert!(write_name(&mut wire, &format!("{0}.{0}.{0}.{0}.", label)).is_err());
+	assert!(wire.is_empty());
+	write_name(&mut wire, &format!("{0}.{0}.{0}.{1}.", label, &label[..61])).unwrap();
+	assert_eq!(wire.len(), MAX_NAME_SIZE);
+
+	let mut resp = Message::request(1, opcode::QUERY, Question { name: "ffhl.de.".to_string(), qtype: rtype::A, qclass: CLASS_IN });
+	resp.answers.push(Record::new(&format!("a{}.ffhl.de.", label), 60, RData::A("10.0.0.1".parse().unwrap())));
+	assert!(resp.to_vec().is_err());
+}
@@ -0,0 +1,509 @@
+pub mod dnssec;
+pub mod message;
+pub mod notify;
+pub mod transfer;
+pub mod tsig;
+pub mod update;
+pub mod zone;
+
+use crate::config;
+use crate::db::{Database, ZoneRecord};
+use crate::nsupdate::{Backend, UpdateResult, nsupdate::UpdateMessage};
+use crate::{CONFIG, DNS_EDNS_UDP_SIZE, DNS_TCP_TIMEOUT, DNS_UDP_SIZE};
+#[allow(unused_imports)]
+use log::{error, warn, info, debug, trace};
+use chrono::Utc;
+use dnssec::KeyStore;
+use message::{rcode, rtype, opcode, Message, CLASS_IN, CLASS_ANY};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+
+/// backend for the built-in nameserver
+///
+/// Queries and transfers are answered from the records as they were
+/// last published, not from the domains. An update publishes the new
+/// records of the domains together with a new serial of the zone and
+/// a journal entry for IXFR, and announces it to the secondaries. So a
+/// serial always stands for the same records.
+pub struct Builtin {
+	db: Database,
+	zones: Vec<config::Domain>,
+	notify: Vec<SocketAddr>,
+}
+
+impl Builtin {
+	pub fn new(db: Database, zones: Vec<config::Domain>, conf: &config::BuiltinServer) -> Self {
+		Self {
+			db,
+			zones,
+			notify: conf.notify.clone(),
+		}
+	}
+
+	fn publish(&self, zone: &config::Domain, records: &[(String, Vec<ZoneRecord>)]) -> UpdateResult {
+		if let Some(entry) = self.db.publish(zone, records)? {
+			debug!("serial of {} is now {}", zone.name, entry.serial_to);
+			for addr in &self.notify {
+				tokio::spawn(notify::send_notify(zone.name.clone(), entry.serial_to, *addr));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[rocket::async_trait]
+impl Backend for Builtin {
+	/// publishes what changed in the database while the server was not running
+	async fn start(&self) -> UpdateResult {
+		for zone in &self.zones {
+			let mut records: Vec<(String, Vec<ZoneRecord>)> = self.db.get_by_suffix(&zone.name)?
+				.iter()
+				.map(|d| (d.domainname.to_lowercase(), d.zone_records()))
+				.collect();
+
+			// names that were removed meanwhile
+			let (_, published) = self.db.published_zone(zone)?;
+			for r in published {
+				if !records.iter().any(|(name, _)| name == r.name()) {
+					records.push((r.name().to_string(), Vec::new()));
+				}
+			}
+
+			self.publish(zone, &records)?;
+		}
+		Ok(())
+	}
+
+	async fn update(&self, msg: UpdateMessage) -> UpdateResult {
+		let name = match msg.zone() {
+			Some(z) => z,
+			None => return Ok(()),
+		};
+		let zone = zone::find_zone(&self.zones, &name)
+			.ok_or_else(|| format!("{} is not a configured domain", name))?;
+
+		let mut names: Vec<String> = msg.commands().iter().map(|c| c.domain().to_lowercase()).collect();
+		names.sort();
+		names.dedup();
+
+		let mut records = Vec::new();
+		for name in names {
+			let new = self.db.get_domain(&name)?.map(|d| d.zone_records()).unwrap_or_default();
+			records.push((name, new));
+		}
+
+		self.publish(zone, &records)
+	}
+
+	/// the signatures are made when the zone is transferred,
+	/// a new serial makes the secondaries fetch fresh ones
+	async fn resign(&self, zone: &config::Domain) -> UpdateResult {
+		let serial = self.db.bump_serial(zone)?;
+		for addr in &self.notify {
+			tokio::spawn(notify::send_notify(zone.name.clone(), serial, *addr));
+		}
+
+		Ok(())
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+	Udp,
+	Tcp,
+}
+
+
+/// answers DNS queries on UDP and TCP in the background.
+/// Fails if the sockets cannot be bound.
+pub async fn serve(db: Database, keys: Arc<KeyStore>, conf: config::BuiltinServer) -> Result<(), String> {
+	let listen = conf.listen;
+	let udp = UdpSocket::bind(listen).await
+		.map_err(|e| format!("cannot bind udp socket {}: {}", listen, e))?;
+	let tcp = TcpListener::bind(listen).await
+		.map_err(|e| format!("cannot bind tcp socket {}: {}", listen, e))?;
+	info!("serving dns on {}", listen);
+
+	let conf = Arc::new(conf);
+	tokio::spawn(serve_tcp(db.clone(), keys.clone(), conf.clone(), tcp));
+	tokio::spawn(serve_udp(db, keys, conf, udp));
+	Ok(())
+}
+
+
+async fn serve_udp(db: Database, keys: Arc<KeyStore>, conf: Arc<config::BuiltinServer>, sock: UdpSocket) {
+	let mut buf = [0u8; 4096];
+
+	loop {
+		let (len, peer) = match sock.recv_from(&mut buf).await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!("cannot receive dns query: {}", e);
+				continue;
+			}
+		};
+
+		for resp in handle(&db, &keys, &conf, &buf[..len], peer.ip(), Transport::Udp) {
+			if let Err(e) = sock.send_to(&resp, peer).await {
+				warn!("cannot send dns response to {}: {}", peer, e);
+			}
+		}
+	}
+}
+
+
+async fn serve_tcp(db: Database, keys: Arc<KeyStore>, conf: Arc<config::BuiltinServer>, listener: TcpListener) {
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!("cannot accept dns connection: {}", e);
+				continue;
+			}
+		};
+
+		let db = db.clone();
+		let keys = keys.clone();
+		let conf = conf.clone();
+		tokio::spawn(async move {
+			if let Err(e) = handle_tcp(db, keys, conf, stream, peer.ip()).await {
+				debug!("dns connection from {} closed: {}", peer, e);
+			}
+		});
+	}
+}
+
+
+/// answers length prefixed messages until the client closes
+/// the connection or stays idle for `DNS_TCP_TIMEOUT` seconds
+async fn handle_tcp(db: Database, keys: Arc<KeyStore>, conf: Arc<config::BuiltinServer>, mut stream: TcpStream, peer: IpAddr) -> io::Result<()> {
+	let timeout = Duration::from_secs(DNS_TCP_TIMEOUT);
+
+	loop {
+		let len = match tokio::time::timeout(timeout, stream.read_u16()).await {
+			Ok(Ok(len)) => len,
+			Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+			Ok(Err(e)) => return Err(e),
+			Err(_) => return Ok(()),
+		};
+
+		let mut buf = vec![0u8; len as usize];
+		tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await??;
+
+		for resp in handle(&db, &keys, &conf, &buf, peer, Transport::Tcp) {
+			stream.write_u16(resp.len() as u16).await?;
+			stream.write_all(&resp).await?;
+		}
+	}
+}
+
+
+/// parses a request and builds the wire format of the responses.
+/// Zone transfers can have more than one response, ignored
+/// messages have none.
+fn handle(db: &Database, keys: &KeyStore, conf: &config::BuiltinServer, buf: &[u8], peer: IpAddr, transport: Transport) -> Vec<Vec<u8>> {
+	let (mut query, tsig_offset) = match Message::parse_with_tsig_offset(buf) {
+		Ok(q) => q,
+		Err(e) => {
+			debug!("{}", e);
+			return Message::error_response(buf, rcode::FORMERR).into_iter().collect();
+		}
+	};
+
+	if query.is_response() {
+		return Vec::new();
+	}
+
+	let mut signer = match tsig_offset {
+		None => None,
+		Some(offset) => match tsig::verify(buf, offset, &mut query, |name| transfer_key(conf, name)) {
+			Ok(s) => Some(s),
+			Err(e) => {
+				debug!("tsig of request from {} is invalid, error {}", peer, e);
+				let mut resp = query.response(rcode::NOTAUTH);
+				resp.set_authoritative(false);
+				return resp.to_vec().into_iter().collect();
+			}
+		},
+	};
+
+	let is_transfer = query.opcode() == opcode::QUERY && query.questions
+		.first()
+		.is_some_and(|q| q.qtype == rtype::AXFR || q.qtype == rtype::IXFR);
+
+	let responses = if is_transfer {
+		let allowed = signer.is_some() || config::ip_in_networks(peer, &conf.allow_transfer);
+		if !allowed {
+			warn!("refused zone transfer to {}", peer);
+		}
+		transfer::transfer(db, keys, &CONFIG.domain, &query, allowed, transport == Transport::Tcp)
+	} else {
+		let max = match (transport, query.edns()) {
+			(Transport::Udp, None) => DNS_UDP_SIZE,
+			(Transport::Udp, Some((size, _))) => (size as usize).clamp(DNS_UDP_SIZE, DNS_EDNS_UDP_SIZE),
+			(Transport::Tcp, _) => u16::MAX as usize,
+		};
+		vec![answer(db, keys, &CONFIG.domain, &query).truncate(max)]
+	};
+
+	let wire = responses
+		.into_iter()
+		.map(|mut resp| {
+			if let Some(signer) = &mut signer {
+				signer.sign(&mut resp)?;
+			}
+			resp.to_vec()
+		})
+		.collect();
+
+	// a name that does not fit into the message
+	// must not turn into a malformed answer
+	match wire {
+		Ok(wire) => wire,
+		Err(e) => {
+			error!("cannot answer {} from {}: {}", query.questions.first().map_or("", |q| &q.name), peer, e);
+			Message::error_response(buf, rcode::SERVFAIL).into_iter().collect()
+		}
+	}
+}
+
+
+fn transfer_key(conf: &config::BuiltinServer, name: &str) -> Option<tsig::Key> {
+	conf.transfer_keys
+		.iter()
+		.find(|k| message::name_eq(&k.name, name))
+		.and_then(tsig::Key::from_config)
+}
+
+
+/// looks up the answer to a query in the published records. Answers
+/// of signed zones are signed if the client asked for DNSSEC.
+pub fn answer(db: &Database, keys: &KeyStore, zones: &[config::Domain], query: &Message) -> Message {
+	let mut resp = query.response(rcode::NOERROR);
+	let dnssec_ok = match query.edns() {
+		Some((_, dnssec_ok)) => {
+			resp.set_edns(DNS_EDNS_UDP_SIZE as u16, dnssec_ok);
+			dnssec_ok
+		}
+		None => false,
+	};
+
+	if query.opcode() != opcode::QUERY {
+		resp.set_rcode(rcode::NOTIMP);
+		resp.set_authoritative(false);
+		return resp;
+	}
+
+	let q = match query.questions.as_slice() {
+		[q] => q,
+		_ => {
+			resp.set_rcode(rcode::FORMERR);
+			resp.set_authoritative(false);
+			return resp;
+		}
+	};
+
+	let zone = match zone::find_zone(zones, &q.name) {
+		Some(z) if q.qclass == CLASS_IN || q.qclass == CLASS_ANY => z,
+		_ => {
+			resp.set_rcode(rcode::REFUSED);
+			resp.set_authoritative(false);
+			return resp;
+		}
+	};
+
+	trace!("dns query for {} type {}", q.name, q.qtype);
+	let (serial, published) = match db.published_name(zone, &q.name) {
+		Ok(p) => p,
+		Err(e) => return server_failure(resp, e),
+	};
+	let now = Utc::now();
+	let zone_keys = match zone.dnssec {
+		Some(_) => match keys.get(zone) {
+			Ok(k) => k,
+			Err(e) => return server_failure(resp, e),
+		},
+		None => Vec::new(),
+	};
+	let signed = dnssec_ok && !zone_keys.is_empty();
+
+	let records = if zone::is_apex(zone, &q.name) {
+		let mut records = zone::apex_records(zone, serial);
+		records.extend(dnssec::dnskey_records(zone, &zone_keys, now));
+		Some(records)
+	} else if published.is_empty() {
+		None
+	} else {
+		Some(published.iter().map(zone::record).collect())
+	};
+
+	match records {
+		// without a NSEC chain there is no proof for NXDOMAIN
+		None if signed => {
+			resp.authority.push(zone::negative_soa_record(zone, serial));
+			resp.authority.push(dnssec::compact_denial(zone, &q.name, &[rtype::NXNAME]));
+		}
+		None => {
+			resp.set_rcode(rcode::NXDOMAIN);
+			resp.authority.push(zone::negative_soa_record(zone, serial));
+		}
+		Some(records) => {
+			let types: Vec<u16> = records.iter().map(|r| r.rtype).collect();
+			resp.answers = records
+				.into_iter()
+				.filter(|r| q.qtype == rtype::ANY || r.rtype == q.qtype)
+				.map(|mut r| {
+					// answer with the spelling of the question
+					r.name = q.name.clone();
+					r
+				})
+				.collect();
+
+			if resp.answers.is_empty() {
+				resp.authority.push(zone::negative_soa_record(zone, serial));
+				if signed {
+					resp.authority.push(dnssec::compact_denial(zone, &q.name, &types));
+				}
+			}
+		}
+	}
+
+	if signed {
+		let sigs = dnssec::sign(zone, &zone_keys, &resp.answers, now);
+		resp.answers.extend(sigs);
+		let sigs = dnssec::sign(zone, &zone_keys, &resp.authority, now);
+		resp.authority.extend(sigs);
+	}
+
+	resp
+}
+
+
+/// the database or the keys failed, the client should ask again or another server
+fn server_failure(mut resp: Message, e: impl fmt::Display) -> Message {
+	error!("cannot answer dns query: {}", e);
+	resp.set_rcode(rcode::SERVFAIL);
+	resp.set_authoritative(false);
+	resp
+}
+
+
+
+#[test]
+fn answer_queries() {
+	use crate::db;
+
+	let test_query = |name: &str, qtype: u16| Message {
+		id: 1,
+		flags: 0,
+		questions: vec![message::Question { name: name.to_string(), qtype, qclass: CLASS_IN }],
+		answers: Vec::new(),
+		authority: Vec::new(),
+		additional: Vec::new(),
+	};
+
+	let zones: Vec<config::Domain> = vec![toml::from_str(r#"
+		name = "ffhl.de."
+		description = "test"
+		allowed_ips = []
+		validity = 1
+		nameservers = ["ns1.ffhl.de.", "ns2.ffhl.de."]
+	"#).unwrap()];
+
+	let (tmp, db) = db::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let path = tmp.path();
+	let keys = KeyStore::new(path.join("keys"));
+
+	let d = db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.1".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+	db.publish(&zones[0], &[(d.domainname.clone(), d.zone_records())]).unwrap();
+
+	let resp = answer(&db, &keys, &zones, &test_query("FOO.ffhl.de.", rtype::A));
+	assert_eq!(resp.rcode(), rcode::NOERROR);
+	assert_eq!(resp.answers.len(), 1);
+	assert_eq!(resp.answers[0].name, "FOO.ffhl.de.");
+	assert_eq!(resp.answers[0].data, message::RData::A("10.0.0.1".parse().unwrap()));
+
+	// no AAAA record
+	let resp = answer(&db, &keys, &zones, &test_query("foo.ffhl.de.", rtype::AAAA));
+	assert_eq!(resp.rcode(), rcode::NOERROR);
+	assert!(resp.answers.is_empty());
+	assert_eq!(resp.authority[0].rtype, rtype::SOA);
+
+	let resp = answer(&db, &keys, &zones, &test_query("bar.ffhl.de.", rtype::A));
+	assert_eq!(resp.rcode(), rcode::NXDOMAIN);
+	assert_eq!(resp.authority[0].rtype, rtype::SOA);
+	assert_eq!(resp.authority[0].ttl, 30);
+
+	let resp = answer(&db, &keys, &zones, &test_query("ffhl.de.", rtype::NS));
+	assert_eq!(resp.answers.len(), 2);
+
+	let resp = answer(&db, &keys, &zones, &test_query("example.org.", rtype::A));
+	assert_eq!(resp.rcode(), rcode::REFUSED);
+
+	// changes are answered once they are published with a new serial
+	let serial = db.get_serial(&zones[0]).unwrap();
+	db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.2".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+	let resp = answer(&db, &keys, &zones, &test_query("foo.ffhl.de.", rtype::A));
+	assert_eq!(resp.answers[0].data, message::RData::A("10.0.0.1".parse().unwrap()));
+	let d = db.get_domain(&d.domainname).unwrap().unwrap();
+	db.publish(&zones[0], &[(d.domainname.clone(), d.zone_records())]).unwrap();
+	let resp = answer(&db, &keys, &zones, &test_query("foo.ffhl.de.", rtype::A));
+	assert_eq!(resp.answers[0].data, message::RData::A("10.0.0.2".parse().unwrap()));
+	let resp = answer(&db, &keys, &zones, &test_query("ffhl.de.", rtype::SOA));
+	assert!(matches!(&resp.answers[0].data, message::RData::Soa(soa) if soa.serial > serial));
+
+	// serial changes with every update
+	let before = db.get_serial(&zones[0]).unwrap();
+	assert_eq!(db.bump_serial(&zones[0]).unwrap(), before + 1);
+
+	// signed answers for clients asking for DNSSEC
+	let mut zones = zones;
+	zones[0].dnssec = Some(config::Dnssec::default());
+	let signed_query = |name: &str, qtype: u16| {
+		let mut q = test_query(name, qtype);
+		q.set_edns(4096, true);
+		q
+	};
+
+	let resp = answer(&db, &keys, &zones, &test_query("foo.ffhl.de.", rtype::A));
+	assert_eq!(resp.answers.len(), 1);
+
+	let resp = answer(&db, &keys, &zones, &signed_query("foo.ffhl.de.", rtype::A));
+	assert_eq!(resp.edns(), Some((DNS_EDNS_UDP_SIZE as u16, true)));
+	assert_eq!(resp.answers.iter().map(|r| r.rtype).collect::<Vec<_>>(), vec![rtype::A, rtype::RRSIG]);
+
+	let resp = answer(&db, &keys, &zones, &signed_query("ffhl.de.", rtype::DNSKEY));
+	assert_eq!(resp.answers.iter().map(|r| r.rtype).collect::<Vec<_>>(), vec![rtype::DNSKEY, rtype::DNSKEY, rtype::RRSIG]);
+
+	// nonexistent names are denied with NODATA and NXNAME
+	let resp = answer(&db, &keys, &zones, &signed_query("bar.ffhl.de.", rtype::A));
+	assert_eq!(resp.rcode(), rcode::NOERROR);
+	let types: Vec<u16> = resp.authority.iter().map(|r| r.rtype).collect();
+	assert_eq!(types, vec![rtype::SOA, rtype::NSEC, rtype::RRSIG, rtype::RRSIG]);
+	match &resp.authority[1].data {
+		message::RData::Nsec(n) => assert_eq!(n.types, vec![rtype::RRSIG, rtype::NSEC, rtype::NXNAME]),
+		_ => panic!("no nsec record"),
+	}
+
+	// without its keys a signed zone is not served unsigned
+	std::fs::create_dir_all(path.join("broken")).unwrap();
+	std::fs::write(path.join("broken").join("ffhl.de.json"), "garbage").unwrap();
+	let broken = KeyStore::new(path.join("broken"));
+	let resp = answer(&db, &broken, &zones, &test_query("foo.ffhl.de.", rtype::A));
+	assert_eq!(resp.rcode(), rcode::SERVFAIL);
+	assert!(zone::published_records(&db, &broken, &zones[0]).is_err());
+}
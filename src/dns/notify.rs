@@ -0,0 +1,61 @@
+use super::message::{opcode, rtype, Message, Question, CLASS_IN};
+use crate::{NOTIFY_RETRIES, NOTIFY_TIMEOUT};
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+
+/// tells a secondary that a zone changed (RFC 1996), retries
+/// until the secondary acknowledges the NOTIFY
+pub async fn send_notify(zone: String, serial: u32, addr: SocketAddr) {
+	let id = rand::random::<u16>();
+	let mut msg = Message::request(id, opcode::NOTIFY, Question {
+		name: zone.clone(),
+		qtype: rtype::SOA,
+		qclass: CLASS_IN,
+	});
+	msg.set_authoritative(true);
+	let msg = match msg.to_vec() {
+		Ok(m) => m,
+		Err(e) => {
+			error!("cannot notify {} of {}: {}", addr, zone, e);
+			return;
+		}
+	};
+
+	let bind: SocketAddr = if addr.is_ipv4() {
+		"0.0.0.0:0".parse().unwrap()
+	} else {
+		"[::]:0".parse().unwrap()
+	};
+	let sock = match UdpSocket::bind(bind).await {
+		Ok(s) => s,
+		Err(e) => {
+			error!("cannot open socket for notify: {}", e);
+			return;
+		}
+	};
+
+	let mut buf = [0u8; 512];
+	for _ in 0..NOTIFY_RETRIES {
+		if let Err(e) = sock.send_to(&msg, addr).await {
+			warn!("cannot send notify to {}: {}", addr, e);
+			continue;
+		}
+
+		let answer = tokio::time::timeout(Duration::from_secs(NOTIFY_TIMEOUT), sock.recv_from(&mut buf)).await;
+		if let Ok(Ok((len, from))) = answer {
+			match Message::parse(&buf[..len]) {
+				Ok(resp) if from == addr && resp.id == id && resp.is_response() => {
+					debug!("{} acknowledged notify for {} serial {}", addr, zone, serial);
+					return;
+				}
+				_ => debug!("unexpected answer to notify from {}", from),
+			}
+		}
+	}
+
+	warn!("{} did not acknowledge notify for {} serial {}", addr, zone, serial);
+}
@@ -0,0 +1,247 @@
+use super::dnssec::KeyStore;
+use super::message::{rcode, rtype, Message, NameError, RData, Record};
+use super::zone;
+use crate::config;
+use crate::db::Database;
+use crate::DNS_TRANSFER_MESSAGE_SIZE;
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+
+
+/// answers an AXFR or IXFR request
+///
+/// `allowed` tells if the client may transfer zones at all. AXFR is
+/// only possible over TCP, an IXFR over UDP is answered with the current
+/// SOA record so the client retries over TCP if it is behind.
+pub fn transfer(db: &Database, keys: &KeyStore, zones: &[config::Domain], query: &Message, allowed: bool, tcp: bool) -> Vec<Message> {
+	let refuse = |rcode| {
+		let mut resp = query.response(rcode);
+		resp.set_authoritative(false);
+		vec![resp]
+	};
+
+	let q = match query.questions.as_slice() {
+		[q] => q,
+		_ => return refuse(rcode::FORMERR),
+	};
+
+	let zone = match zone::find_zone(zones, &q.name) {
+		Some(z) if zone::is_apex(z, &q.name) => z,
+		_ => return refuse(rcode::NOTAUTH),
+	};
+
+	if !allowed {
+		return refuse(rcode::REFUSED);
+	}
+
+	let serial = match db.get_serial(zone) {
+		Ok(s) => s,
+		Err(e) => {
+			error!("cannot read serial of {}: {}", zone.name, e);
+			return refuse(rcode::SERVFAIL);
+		}
+	};
+
+	if q.qtype == rtype::AXFR {
+		if !tcp {
+			return refuse(rcode::REFUSED);
+		}
+
+		return match axfr_records(db, keys, zone).and_then(|r| Ok(messages(query, r)?)) {
+			Ok(msgs) => msgs,
+			Err(e) => {
+				error!("AXFR of {} failed: {}", zone.name, e);
+				refuse(rcode::SERVFAIL)
+			}
+		};
+	}
+
+	let client_serial = query.authority.iter().find_map(|r| match &r.data {
+		RData::Soa(soa) => Some(soa.serial),
+		_ => None,
+	});
+	let client_serial = match client_serial {
+		Some(s) => s,
+		None => return refuse(rcode::FORMERR),
+	};
+
+	if !tcp || client_serial == serial {
+		let mut resp = query.response(rcode::NOERROR);
+		resp.answers.push(zone::soa_record(zone, serial));
+		return vec![resp];
+	}
+
+	info!("sending IXFR of {} from serial {} to {}", zone.name, client_serial, serial);
+	match ixfr_records(db, keys, zone, serial, client_serial).and_then(|r| Ok(messages(query, r)?)) {
+		Ok(msgs) => msgs,
+		Err(e) => {
+			error!("IXFR of {} failed: {}", zone.name, e);
+			refuse(rcode::SERVFAIL)
+		}
+	}
+}
+
+
+/// all published records of the zone, framed by the SOA record
+pub fn axfr_records(db: &Database, keys: &KeyStore, zone: &config::Domain) -> Result<Vec<Record>, String> {
+	let (serial, mut records) = zone::published_records(db, keys, zone)?;
+	info!("sending AXFR of {} serial {}", zone.name, serial);
+	records.push(zone::soa_record(zone, serial));
+	Ok(records)
+}
+
+
+/// the changes since `client_serial` up to `serial` in the format of
+/// RFC 1995. Falls back to the full zone if the journal does not reach
+/// back that far or the serial changed in the meantime. The journal has
+/// no signatures, so signed zones are always sent in full.
+pub fn ixfr_records(db: &Database, keys: &KeyStore, zone: &config::Domain, serial: u32, client_serial: u32) -> Result<Vec<Record>, String> {
+	let entries = match db.journal_since(&zone.name, client_serial)? {
+		Some(e) if zone.dnssec.is_none() && e.last().map(|e| e.serial_to) == Some(serial) => e,
+		_ => return axfr_records(db, keys, zone),
+	};
+
+	let mut records = vec![zone::soa_record(zone, serial)];
+	for e in entries {
+		records.push(zone::soa_record(zone, e.serial_from));
+		records.extend(e.removed.iter().map(zone::record));
+		records.push(zone::soa_record(zone, e.serial_to));
+		records.extend(e.added.iter().map(zone::record));
+	}
+	records.push(zone::soa_record(zone, serial));
+
+	Ok(records)
+}
+
+
+/// splits the records of a transfer into messages of about
+/// `DNS_TRANSFER_MESSAGE_SIZE` bytes. Fails if a record cannot
+/// be written, the transfer would be incomplete without it.
+fn messages(query: &Message, records: Vec<Record>) -> Result<Vec<Message>, NameError> {
+	let mut msgs = Vec::new();
+	let mut current = query.response(rcode::NOERROR);
+	let mut size = current.to_vec()?.len();
+
+	for r in records {
+		let len = r.wire_size()?;
+		if !current.answers.is_empty() && size + len > DNS_TRANSFER_MESSAGE_SIZE {
+			msgs.push(current);
+			// only the first message carries the question
+			current = query.response(rcode::NOERROR);
+			current.questions.clear();
+			size = current.to_vec()?.len();
+		}
+
+		size += len;
+		current.answers.push(r);
+	}
+
+	msgs.push(current);
+	Ok(msgs)
+}
+
+
+
+#[tokio::test]
+async fn transfers() {
+	use super::message::{opcode, Question, CLASS_IN};
+	use super::Builtin;
+	use crate::db;
+	use crate::nsupdate::{Backend, nsupdate::{UpdateCommand, UpdateMessage}};
+
+	let zones: Vec<config::Domain> = vec![toml::from_str(r#"
+		name = "ffhl.de."
+		description = "test"
+		allowed_ips = []
+		validity = 1
+		nameservers = ["ns1.ffhl.de."]
+	"#).unwrap()];
+	let zone = &zones[0];
+
+	let names: Vec<String> = (0..1000).map(|i| format!("host{}.ffhl.de.", i)).collect();
+	let (tmp, db) = db::test_database(&names.iter().map(String::as_str).collect::<Vec<_>>(), chrono::Duration::hours(1));
+	let keys = KeyStore::new(tmp.path().join("keys"));
+
+	for name in &names {
+		db.modify_domain(name, |d| {
+			d.ipv4 = Some("10.0.0.1".parse().unwrap());
+			Ok::<_, db::Error>(())
+		}).unwrap();
+	}
+
+	// the domains are published when the server starts
+	let backend = Builtin::new(db.clone(), zones.clone(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	backend.start().await.unwrap();
+	let serial = db.get_serial(zone).unwrap();
+	let axfr = Message::request(1, opcode::QUERY, Question { name: "ffhl.de.".to_string(), qtype: rtype::AXFR, qclass: CLASS_IN });
+
+	// not allowed, and not over udp
+	assert_eq!(transfer(&db, &keys, &zones, &axfr, false, true)[0].rcode(), rcode::REFUSED);
+	assert_eq!(transfer(&db, &keys, &zones, &axfr, true, false)[0].rcode(), rcode::REFUSED);
+
+	let msgs = transfer(&db, &keys, &zones, &axfr, true, true);
+	assert!(msgs.len() > 1);
+	assert!(msgs.iter().all(|m| m.to_vec().unwrap().len() <= DNS_TRANSFER_MESSAGE_SIZE));
+	let records: Vec<&Record> = msgs.iter().flat_map(|m| &m.answers).collect();
+	// SOA, NS, 1000 A, SOA
+	assert_eq!(records.len(), 1003);
+	assert_eq!(records[0].rtype, rtype::SOA);
+	assert_eq!(records[1002].rtype, rtype::SOA);
+
+	let soa_serial = |r: &Record| match &r.data {
+		RData::Soa(soa) => soa.serial,
+		_ => panic!("no soa record"),
+	};
+	let host1 = |records: &[Record]| -> Vec<RData> {
+		records.iter().filter(|r| r.name == "host1.ffhl.de.").map(|r| r.data.clone()).collect()
+	};
+	let old = RData::A("10.0.0.1".parse().unwrap());
+	let new = RData::A("10.0.0.2".parse().unwrap());
+
+	// a change is not transferred before it is published with a new serial
+	db.modify_domain("host1.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.2".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+	let records = axfr_records(&db, &keys, zone).unwrap();
+	assert_eq!(soa_serial(&records[0]), serial);
+	assert_eq!(host1(&records), vec![old.clone()]);
+
+	let mut msg = UpdateMessage::new();
+	msg.add_command(UpdateCommand::add(&"host1.ffhl.de.".to_string(), "10.0.0.2".parse().unwrap()));
+	backend.update(msg).await.unwrap();
+	let to = db.get_serial(zone).unwrap();
+	assert!(to > serial);
+
+	let records = axfr_records(&db, &keys, zone).unwrap();
+	assert_eq!(soa_serial(&records[0]), to);
+	assert_eq!(host1(&records), vec![new.clone()]);
+
+	// the journal removes exactly what the secondary got with the old serial
+	let ixfr = ixfr_records(&db, &keys, zone, to, serial).unwrap();
+	let types: Vec<u16> = ixfr.iter().map(|r| r.rtype).collect();
+	assert_eq!(types, vec![rtype::SOA, rtype::SOA, rtype::A, rtype::SOA, rtype::A, rtype::SOA]);
+	assert_eq!(ixfr[2].data, old);
+	assert_eq!(ixfr[4].data, new);
+
+	// publishing the same records again changes nothing
+	let mut msg = UpdateMessage::new();
+	msg.add_command(UpdateCommand::add(&"host1.ffhl.de.".to_string(), "10.0.0.2".parse().unwrap()));
+	backend.update(msg).await.unwrap();
+	assert_eq!(db.get_serial(zone).unwrap(), to);
+
+	// unknown serial gives the full zone
+	assert_eq!(ixfr_records(&db, &keys, zone, to, serial - 10).unwrap().len(), 1003);
+
+	// signed zones are always sent in full, with a NSEC for every name
+	let mut zone = zone.clone();
+	zone.dnssec = Some(config::Dnssec::default());
+	let records = ixfr_records(&db, &keys, &zone, to, serial).unwrap();
+	let count = |t| records.iter().filter(|r| r.rtype == t).count();
+	assert_eq!(count(rtype::NSEC), 1001);
+	assert_eq!(count(rtype::DNSKEY), 2);
+	// one per RRset: SOA, NS, DNSKEY, 1000 A, 1001 NSEC
+	assert_eq!(count(rtype::RRSIG), 2004);
+	assert_eq!(records.first().unwrap().rtype, rtype::SOA);
+	assert_eq!(records.last().unwrap().rtype, rtype::SOA);
+}
@@ -0,0 +1,330 @@
+use super::message::{rtype, write_name, Message, NameError, RData, Record, CLASS_ANY};
+use crate::config;
+use crate::db;
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::{Sha256, Sha512};
+use crypto::util::fixed_time_eq;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// transaction signatures (RFC 8945)
+
+
+/// allowed time difference in seconds between signer and us
+const FUDGE: u16 = 300;
+
+pub mod error {
+	pub const BADSIG: u16 = 16;
+	pub const BADKEY: u16 = 17;
+	pub const BADTIME: u16 = 18;
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+	HmacSha256,
+	HmacSha512,
+}
+
+impl Algorithm {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.trim_end_matches('.').to_lowercase().as_str() {
+			"hmac-sha256" => Some(Self::HmacSha256),
+			"hmac-sha512" => Some(Self::HmacSha512),
+			_ => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::HmacSha256 => "hmac-sha256.",
+			Self::HmacSha512 => "hmac-sha512.",
+		}
+	}
+
+	fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+		match self {
+			Self::HmacSha256 => {
+				let mut h = Hmac::new(Sha256::new(), secret);
+				h.input(data);
+				h.result().code().to_vec()
+			}
+			Self::HmacSha512 => {
+				let mut h = Hmac::new(Sha512::new(), secret);
+				h.input(data);
+				h.result().code().to_vec()
+			}
+		}
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Key {
+	pub name: String,
+	pub algorithm: Algorithm,
+	pub secret: Vec<u8>,
+}
+
+impl Key {
+	pub fn from_config(conf: &config::TsigKey) -> Option<Self> {
+		let algorithm = Algorithm::from_name(&conf.algorithm);
+		let secret = base64::decode(&conf.secret).ok();
+		if algorithm.is_none() || secret.is_none() {
+			error!("tsig key {} is invalid", conf.name);
+		}
+
+		Some(Self {
+			name: conf.name.clone(),
+			algorithm: algorithm?,
+			secret: secret?,
+		})
+	}
+
+	/// the key a domain signs its updates with, named like the domain
+	pub fn for_domain(d: &db::Domain) -> Self {
+		Self {
+			name: d.domainname.clone(),
+			algorithm: Algorithm::HmacSha256,
+			secret: domain_secret(&d.token),
+		}
+	}
+}
+
+
+/// the TSIG secret of a domain is derived from its token,
+/// so a new token also gives a new key
+pub fn domain_secret(token: &str) -> Vec<u8> {
+	let mut sum = Sha256::new();
+	sum.input_str(token);
+	let mut secret = vec![0u8; sum.output_bytes()];
+	sum.result(&mut secret);
+	secret
+}
+
+
+/// rdata of a TSIG record
+#[derive(Debug, Clone, PartialEq)]
+struct Tsig {
+	algorithm: String,
+	time_signed: u64,
+	fudge: u16,
+	mac: Vec<u8>,
+	original_id: u16,
+	error: u16,
+	other: Vec<u8>,
+}
+
+impl Tsig {
+	fn parse(rdata: &[u8]) -> Option<Self> {
+		// the algorithm name is never compressed
+		let mut algorithm = String::new();
+		let mut pos = 0;
+		loop {
+			let len = *rdata.get(pos)? as usize;
+			pos += 1;
+			if len == 0 {
+				break;
+			}
+			algorithm.push_str(&String::from_utf8_lossy(rdata.get(pos..pos + len)?));
+			algorithm.push('.');
+			pos += len;
+		}
+
+		let u16_at = |p: usize| rdata.get(p..p + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+		let time = rdata.get(pos..pos + 6)?;
+		let time_signed = time.iter().fold(0u64, |t, b| (t << 8) | *b as u64);
+		let fudge = u16_at(pos + 6)?;
+		let mac_len = u16_at(pos + 8)? as usize;
+		let mac = rdata.get(pos + 10..pos + 10 + mac_len)?.to_vec();
+		let pos = pos + 10 + mac_len;
+		let original_id = u16_at(pos)?;
+		let error = u16_at(pos + 2)?;
+		let other_len = u16_at(pos + 4)? as usize;
+		let other = rdata.get(pos + 6..pos + 6 + other_len)?.to_vec();
+
+		Some(Self { algorithm, time_signed, fudge, mac, original_id, error, other })
+	}
+
+	fn to_vec(&self) -> Result<Vec<u8>, NameError> {
+		let mut out = Vec::new();
+		write_name(&mut out, &self.algorithm)?;
+		out.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+		out.extend_from_slice(&self.fudge.to_be_bytes());
+		out.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+		out.extend_from_slice(&self.mac);
+		out.extend_from_slice(&self.original_id.to_be_bytes());
+		out.extend_from_slice(&self.error.to_be_bytes());
+		out.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+		out.extend_from_slice(&self.other);
+		Ok(out)
+	}
+
+	/// the TSIG variables that are part of the digest
+	fn variables(&self, key_name: &str, timers_only: bool) -> Result<Vec<u8>, NameError> {
+		let mut out = Vec::new();
+		if !timers_only {
+			write_name(&mut out, &key_name.to_lowercase())?;
+			out.extend_from_slice(&CLASS_ANY.to_be_bytes());
+			out.extend_from_slice(&0u32.to_be_bytes());
+			write_name(&mut out, &self.algorithm.to_lowercase())?;
+		}
+		out.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+		out.extend_from_slice(&self.fudge.to_be_bytes());
+		if !timers_only {
+			out.extend_from_slice(&self.error.to_be_bytes());
+			out.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+			out.extend_from_slice(&self.other);
+		}
+		Ok(out)
+	}
+}
+
+
+/// signs the responses to a verified request
+pub struct Signer {
+	key: Key,
+	prior_mac: Vec<u8>,
+	first: bool,
+}
+
+impl Signer {
+	/// signer for a new request, responses are signed
+	/// by `verify` with the request MAC
+	#[cfg(test)]
+	pub fn new(key: Key) -> Self {
+		Self { key, prior_mac: Vec::new(), first: true }
+	}
+
+	/// appends a TSIG record to the message. Every message of a
+	/// zone transfer has to be signed in order. Fails if a
+	/// name of the message cannot be written.
+	pub fn sign(&mut self, msg: &mut Message) -> Result<(), NameError> {
+		let mut tsig = Tsig {
+			algorithm: self.key.algorithm.name().to_string(),
+			time_signed: now(),
+			fudge: FUDGE,
+			mac: Vec::new(),
+			original_id: msg.id,
+			error: 0,
+			other: Vec::new(),
+		};
+
+		let mut data = Vec::new();
+		if !self.prior_mac.is_empty() {
+			data.extend_from_slice(&(self.prior_mac.len() as u16).to_be_bytes());
+			data.extend_from_slice(&self.prior_mac);
+		}
+		data.extend_from_slice(&msg.to_vec()?);
+		data.extend_from_slice(&tsig.variables(&self.key.name, !self.first)?);
+
+		tsig.mac = self.key.algorithm.mac(&self.key.secret, &data);
+		self.prior_mac = tsig.mac.clone();
+		self.first = false;
+
+		msg.additional.push(tsig_record(&self.key.name, &tsig)?);
+		Ok(())
+	}
+}
+
+
+fn tsig_record(key_name: &str, tsig: &Tsig) -> Result<Record, NameError> {
+	Ok(Record {
+		name: key_name.to_string(),
+		rtype: rtype::TSIG,
+		class: CLASS_ANY,
+		ttl: 0,
+		data: RData::Raw(tsig.to_vec()?),
+	})
+}
+
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+
+/// verifies the TSIG record of a request
+///
+/// `buf` is the request as received and `offset` the position of the TSIG
+/// record in it, `lookup` finds the key for a key name. On success the
+/// TSIG record is removed from `msg` and a signer for the response is
+/// returned, otherwise the TSIG error code.
+pub fn verify<F>(buf: &[u8], offset: usize, msg: &mut Message, lookup: F) -> Result<Signer, u16>
+where
+	F: FnOnce(&str) -> Option<Key>,
+{
+	let record = msg.additional.pop().ok_or(error::BADSIG)?;
+	let tsig = match &record.data {
+		RData::Raw(r) if record.rtype == rtype::TSIG => Tsig::parse(r).ok_or(error::BADSIG)?,
+		_ => return Err(error::BADSIG),
+	};
+
+	let key = lookup(&record.name)
+		.filter(|k| Algorithm::from_name(&tsig.algorithm) == Some(k.algorithm))
+		.ok_or(error::BADKEY)?;
+
+	// the message as it was before signing: original id, one record less
+	let mut data = buf[..offset].to_vec();
+	data[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+	let arcount = u16::from_be_bytes([data[10], data[11]]) - 1;
+	data[10..12].copy_from_slice(&arcount.to_be_bytes());
+	data.extend_from_slice(&tsig.variables(&record.name, false).map_err(|_| error::BADKEY)?);
+
+	let expected = key.algorithm.mac(&key.secret, &data);
+	if tsig.mac.is_empty() || !fixed_time_eq(&expected, &tsig.mac) {
+		return Err(error::BADSIG);
+	}
+
+	if (now() as i64 - tsig.time_signed as i64).abs() > tsig.fudge as i64 {
+		return Err(error::BADTIME);
+	}
+
+	Ok(Signer {
+		key,
+		prior_mac: tsig.mac,
+		first: true,
+	})
+}
+
+
+
+#[test]
+fn sign_and_verify() {
+	use super::message::{Question, rtype, CLASS_IN};
+
+	let key = Key {
+		name: "transfer.".to_string(),
+		algorithm: Algorithm::HmacSha256,
+		secret: b"0123456789abcdef".to_vec(),
+	};
+
+	let mut msg = Message {
+		id: 42,
+		flags: 0,
+		questions: vec![Question { name: "ffhl.de.".to_string(), qtype: rtype::AXFR, qclass: CLASS_IN }],
+		answers: Vec::new(),
+		authority: Vec::new(),
+		additional: Vec::new(),
+	};
+	Signer { key: key.clone(), prior_mac: Vec::new(), first: true }.sign(&mut msg).unwrap();
+	let buf = msg.to_vec().unwrap();
+
+	let (mut parsed, offset) = Message::parse_with_tsig_offset(&buf).unwrap();
+	let offset = offset.unwrap();
+	assert!(verify(&buf, offset, &mut parsed.clone(), |_| Some(key.clone())).is_ok());
+	assert!(parsed.additional.len() == 1);
+
+	// wrong secret
+	let mut wrong = key.clone();
+	wrong.secret = b"fedcba9876543210".to_vec();
+	assert_eq!(verify(&buf, offset, &mut parsed.clone(), |_| Some(wrong)).err(), Some(error::BADSIG));
+
+	// unknown key
+	assert_eq!(verify(&buf, offset, &mut parsed, |_| None).err(), Some(error::BADKEY));
+}
@@ -0,0 +1,429 @@
+use super::message::{name_eq, name_in_zone, opcode, rcode, rtype, Message, RData, Record, CLASS_ANY, CLASS_IN, CLASS_NONE};
+use super::{tsig, zone};
+use crate::config;
+use crate::db::Domain;
+use crate::ffdyndns::{Error, RecordChange, Service, UpdateRequest};
+use crate::{CONFIG, DNS_TCP_TIMEOUT, MAX_UDP_UPDATES};
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+
+
+// dynamic updates (RFC 2136) sent by the users, eg. with `nsupdate -k`
+//
+// Every domain has its own TSIG key, named like the domain. A key can
+// only change the A, AAAA and TXT records of its own domain.
+
+
+/// takes updates on UDP and TCP in the background.
+/// Fails if the sockets cannot be bound.
+pub async fn serve(service: Service, listen: SocketAddr) -> Result<(), String> {
+	let udp = UdpSocket::bind(listen).await
+		.map_err(|e| format!("cannot bind udp socket {}: {}", listen, e))?;
+	let tcp = TcpListener::bind(listen).await
+		.map_err(|e| format!("cannot bind tcp socket {}: {}", listen, e))?;
+	info!("taking dns updates on {}", listen);
+
+	tokio::spawn(serve_tcp(service.clone(), tcp));
+	tokio::spawn(serve_udp(service, udp));
+	Ok(())
+}
+
+
+async fn serve_udp(service: Service, sock: UdpSocket) {
+	let sock = Arc::new(sock);
+	let running = Arc::new(Semaphore::new(MAX_UDP_UPDATES));
+	let mut buf = [0u8; 4096];
+
+	loop {
+		// the packets wait in the socket while all updates are running
+		let permit = match running.clone().acquire_owned().await {
+			Ok(p) => p,
+			Err(_) => return,
+		};
+		let (len, peer) = match sock.recv_from(&mut buf).await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!("cannot receive dns update: {}", e);
+				continue;
+			}
+		};
+
+		// the response waits for the DNS update, other clients must not
+		let service = service.clone();
+		let sock = sock.clone();
+		let req = buf[..len].to_vec();
+		tokio::spawn(async move {
+			if let Some(resp) = handle(&service, &CONFIG.domain, &req, peer.ip()).await {
+				if let Err(e) = sock.send_to(&resp, peer).await {
+					warn!("cannot send dns response to {}: {}", peer, e);
+				}
+			}
+			drop(permit);
+		});
+	}
+}
+
+
+async fn serve_tcp(service: Service, listener: TcpListener) {
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(r) => r,
+			Err(e) => {
+				warn!("cannot accept dns connection: {}", e);
+				continue;
+			}
+		};
+
+		let service = service.clone();
+		tokio::spawn(async move {
+			if let Err(e) = handle_tcp(service, stream, peer.ip()).await {
+				debug!("dns connection from {} closed: {}", peer, e);
+			}
+		});
+	}
+}
+
+
+async fn handle_tcp(service: Service, mut stream: TcpStream, peer: IpAddr) -> io::Result<()> {
+	let timeout = Duration::from_secs(DNS_TCP_TIMEOUT);
+
+	loop {
+		let len = match tokio::time::timeout(timeout, stream.read_u16()).await {
+			Ok(Ok(len)) => len,
+			Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+			Ok(Err(e)) => return Err(e),
+			Err(_) => return Ok(()),
+		};
+
+		let mut buf = vec![0u8; len as usize];
+		tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await??;
+
+		if let Some(resp) = handle(&service, &CONFIG.domain, &buf, peer).await {
+			stream.write_u16(resp.len() as u16).await?;
+			stream.write_all(&resp).await?;
+		}
+	}
+}
+
+
+/// verifies and applies an update, the response is sent
+/// once the DNS update is done
+async fn handle(service: &Service, zones: &[config::Domain], buf: &[u8], peer: IpAddr) -> Option<Vec<u8>> {
+	let (mut msg, tsig_offset) = match Message::parse_with_tsig_offset(buf) {
+		Ok(m) => m,
+		Err(e) => {
+			debug!("{}", e);
+			return Message::error_response(buf, rcode::FORMERR);
+		}
+	};
+
+	if msg.is_response() {
+		return None;
+	}
+
+	let refuse = |msg: &Message, rcode| {
+		let mut resp = msg.response(rcode);
+		resp.set_authoritative(false);
+		resp.to_vec().ok()
+	};
+
+	if msg.opcode() != opcode::UPDATE {
+		return refuse(&msg, rcode::NOTIMP);
+	}
+
+	let offset = match tsig_offset {
+		Some(o) => o,
+		None => {
+			debug!("unsigned update from {}", peer);
+			return refuse(&msg, rcode::REFUSED);
+		}
+	};
+
+	let mut domain = Ok(None);
+	let verified = tsig::verify(buf, offset, &mut msg, |name| {
+		domain = service.get_domain(&name.to_string());
+		domain.as_ref().ok().and_then(|d| d.as_ref()).map(tsig::Key::for_domain)
+	});
+	let (mut signer, domain) = match (verified, domain) {
+		(Ok(s), Ok(Some(d))) => (s, d),
+		(_, Err(e)) => {
+			error!("cannot look up key of update from {}: {}", peer, e);
+			return refuse(&msg, rcode::SERVFAIL);
+		}
+		(verified, _) => {
+			debug!("tsig of update from {} is invalid, error {}", peer, verified.err().unwrap_or(tsig::error::BADKEY));
+			return refuse(&msg, rcode::NOTAUTH);
+		}
+	};
+
+	let rcode = match update(service, zones, &msg, &domain, peer).await {
+		Ok(()) => {
+			info!("{} updated {} over dns", peer, domain.domainname);
+			rcode::NOERROR
+		}
+		Err(rcode) => {
+			debug!("update of {} from {} failed with rcode {}", domain.domainname, peer, rcode);
+			rcode
+		}
+	};
+
+	let mut resp = msg.response(rcode);
+	resp.set_authoritative(false);
+	match signer.sign(&mut resp).and_then(|()| resp.to_vec()) {
+		Ok(resp) => Some(resp),
+		Err(e) => {
+			error!("cannot answer the update from {}: {}", peer, e);
+			None
+		}
+	}
+}
+
+
+/// checks the zone section and hands the changes to the service, which
+/// checks the prerequisites in the same step as it makes the changes.
+/// Errors are the rcode of the response.
+async fn update(service: &Service, zones: &[config::Domain], msg: &Message, d: &Domain, source: IpAddr) -> Result<(), u8> {
+	let zone = match msg.questions.as_slice() {
+		[q] if q.qtype == rtype::SOA && q.qclass == CLASS_IN => &q.name,
+		_ => return Err(rcode::FORMERR),
+	};
+
+	match zone::find_zone(zones, zone) {
+		Some(z) if zone::is_apex(z, zone) && name_in_zone(&d.domainname, &z.name) => (),
+		_ => return Err(rcode::NOTAUTH),
+	}
+
+	let prereqs = msg.answers.clone();
+	let handle = service
+		.update_domain(UpdateRequest {
+			domain: d.domainname.clone(),
+			token: d.token.clone(),
+			changes: changes(&msg.authority, d)?,
+			source: Some(source),
+			check: Some(Box::new(move |d| check_prerequisites(&prereqs, d).map_err(Error::PrerequisiteFailed))),
+		})
+		.await
+		.map_err(error_rcode)?;
+
+	service.wait_for_update(handle).await.map_err(error_rcode)
+}
+
+
+/// checks the prerequisites (RFC 2136 section 2.4) against
+/// the records the domain has at the time of the change
+fn check_prerequisites(prereqs: &[Record], d: &Domain) -> Result<(), u8> {
+	let records: Vec<Record> = d.zone_records().iter().map(zone::record).collect();
+	let has_type = |t| records.iter().any(|r| r.rtype == t);
+
+	for p in prereqs {
+		if !name_eq(&p.name, &d.domainname) {
+			return Err(rcode::REFUSED);
+		}
+
+		match (p.class, p.rtype) {
+			(CLASS_ANY, rtype::ANY) if records.is_empty() => return Err(rcode::NXDOMAIN),
+			(CLASS_ANY, t) if t != rtype::ANY && !has_type(t) => return Err(rcode::NXRRSET),
+			(CLASS_NONE, rtype::ANY) if !records.is_empty() => return Err(rcode::YXDOMAIN),
+			(CLASS_NONE, t) if t != rtype::ANY && has_type(t) => return Err(rcode::YXRRSET),
+			(CLASS_ANY, _) | (CLASS_NONE, _) => (),
+			// the RRset has to be exactly the records of this type in the prerequisites
+			(CLASS_IN, t) => {
+				let mut expected: Vec<Vec<u8>> = prereqs.iter()
+					.filter(|p| p.class == CLASS_IN && p.rtype == t)
+					.map(|p| p.data.to_vec())
+					.collect::<Result<_, _>>()
+					.map_err(|_| rcode::FORMERR)?;
+				let mut actual: Vec<Vec<u8>> = records.iter()
+					.filter(|r| r.rtype == t)
+					.map(|r| r.data.to_vec())
+					.collect::<Result<_, _>>()
+					.map_err(|_| rcode::SERVFAIL)?;
+				expected.sort();
+				expected.dedup();
+				actual.sort();
+				if expected != actual {
+					return Err(rcode::NXRRSET);
+				}
+			}
+			_ => return Err(rcode::FORMERR),
+		}
+	}
+
+	Ok(())
+}
+
+
+/// translates the update section into changes of the domain. Only the
+/// A, AAAA and TXT records of the domain itself can be changed, a domain
+/// has at most one address of each family, so adding one replaces the old.
+/// TXT records are kept as one string, so records with several strings
+/// are refused rather than joined.
+fn changes(updates: &[Record], d: &Domain) -> Result<Vec<RecordChange>, u8> {
+	let mut changes = Vec::new();
+
+	for r in updates {
+		if !name_eq(&r.name, &d.domainname) {
+			return Err(rcode::REFUSED);
+		}
+
+		match (r.class, &r.data) {
+			(CLASS_IN, RData::A(a)) => changes.push(RecordChange::SetAddr(IpAddr::V4(*a))),
+			(CLASS_IN, RData::Aaaa(a)) => changes.push(RecordChange::SetAddr(IpAddr::V6(*a))),
+			(CLASS_IN, RData::Txt(t)) => changes.push(RecordChange::AddTxt(single_string(t)?)),
+			(CLASS_NONE, RData::A(a)) => changes.push(RecordChange::RemoveAddr(IpAddr::V4(*a))),
+			(CLASS_NONE, RData::Aaaa(a)) => changes.push(RecordChange::RemoveAddr(IpAddr::V6(*a))),
+			(CLASS_NONE, RData::Txt(t)) => changes.push(RecordChange::RemoveTxt(single_string(t)?)),
+			(CLASS_ANY, RData::Raw(raw)) if raw.is_empty() => match r.rtype {
+				rtype::A => changes.push(RecordChange::RemoveIpv4),
+				rtype::AAAA => changes.push(RecordChange::RemoveIpv6),
+				rtype::TXT => changes.push(RecordChange::RemoveAllTxt),
+				rtype::ANY => changes.extend(vec![
+					RecordChange::RemoveIpv4,
+					RecordChange::RemoveIpv6,
+					RecordChange::RemoveAllTxt,
+				]),
+				_ => return Err(rcode::REFUSED),
+			},
+			_ => return Err(rcode::REFUSED),
+		}
+	}
+
+	Ok(changes)
+}
+
+
+fn single_string(txt: &[String]) -> Result<String, u8> {
+	match txt {
+		[s] => Ok(s.clone()),
+		_ => Err(rcode::FORMERR),
+	}
+}
+
+
+fn error_rcode(e: Error) -> u8 {
+	match e {
+		Error::PrerequisiteFailed(rcode) => rcode,
+		Error::DomainNotFound | Error::InvalidToken => rcode::NOTAUTH,
+		Error::TooManyRecords | Error::DomainLocked | Error::DomainSuspended(_) | Error::DomainExpired => rcode::REFUSED,
+		_ => rcode::SERVFAIL,
+	}
+}
+
+
+
+#[test]
+fn update_changes() {
+	use super::message::Question;
+
+	let mut d = Domain::new("foo.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	d.ipv4 = Some("10.0.0.1".parse().unwrap());
+	d.txt = vec!["hello".to_string()];
+
+	let record = |name: &str, class, rtype, data| Record { name: name.to_string(), rtype, class, ttl: 0, data };
+	let empty = || RData::Raw(Vec::new());
+
+	// a signed update as nsupdate sends it
+	let mut msg = Message::request(1, opcode::UPDATE, Question { name: "ffhl.de.".to_string(), qtype: rtype::SOA, qclass: CLASS_IN });
+	msg.answers.push(record("foo.ffhl.de.", CLASS_ANY, rtype::A, empty()));
+	msg.authority.push(record("foo.ffhl.de.", CLASS_ANY, rtype::A, empty()));
+	msg.authority.push(record("Foo.ffhl.de.", CLASS_IN, rtype::AAAA, RData::Aaaa("fd00::1".parse().unwrap())));
+	msg.authority.push(record("foo.ffhl.de.", CLASS_NONE, rtype::TXT, RData::Txt(vec!["hello".to_string()])));
+	msg.authority.push(record("foo.ffhl.de.", CLASS_IN, rtype::TXT, RData::Txt(vec!["a b".to_string()])));
+	tsig::Signer::new(tsig::Key::for_domain(&d)).sign(&mut msg).unwrap();
+
+	let buf = msg.to_vec().unwrap();
+	let (mut parsed, offset) = Message::parse_with_tsig_offset(&buf).unwrap();
+	assert!(tsig::verify(&buf, offset.unwrap(), &mut parsed, |name| {
+		assert_eq!(name, "foo.ffhl.de.");
+		Some(tsig::Key::for_domain(&d))
+	}).is_ok());
+
+	// a new token gives a new key
+	let mut other = d.clone();
+	other.token = "other".to_string();
+	let (mut parsed_other, _) = Message::parse_with_tsig_offset(&buf).unwrap();
+	assert_eq!(tsig::verify(&buf, offset.unwrap(), &mut parsed_other, |_| Some(tsig::Key::for_domain(&other))).err(), Some(tsig::error::BADSIG));
+
+	assert_eq!(check_prerequisites(&parsed.answers, &d), Ok(()));
+	assert_eq!(changes(&parsed.authority, &d), Ok(vec![
+		RecordChange::RemoveIpv4,
+		RecordChange::SetAddr("fd00::1".parse().unwrap()),
+		RecordChange::RemoveTxt("hello".to_string()),
+		RecordChange::AddTxt("a b".to_string()),
+	]));
+
+	// TXT records with several strings cannot be stored as they are
+	let txt = RData::Txt(vec!["a".to_string(), "b".to_string()]);
+	assert_eq!(changes(&[record("foo.ffhl.de.", CLASS_IN, rtype::TXT, txt.clone())], &d), Err(rcode::FORMERR));
+	assert_eq!(changes(&[record("foo.ffhl.de.", CLASS_NONE, rtype::TXT, txt)], &d), Err(rcode::FORMERR));
+
+	// prerequisites
+	let aaaa = [record("foo.ffhl.de.", CLASS_ANY, rtype::AAAA, empty())];
+	assert_eq!(check_prerequisites(&aaaa, &d), Err(rcode::NXRRSET));
+	let no_a = [record("foo.ffhl.de.", CLASS_NONE, rtype::A, empty())];
+	assert_eq!(check_prerequisites(&no_a, &d), Err(rcode::YXRRSET));
+	let a_value = [record("foo.ffhl.de.", CLASS_IN, rtype::A, RData::A("10.0.0.1".parse().unwrap()))];
+	assert_eq!(check_prerequisites(&a_value, &d), Ok(()));
+	let a_other = [record("foo.ffhl.de.", CLASS_IN, rtype::A, RData::A("10.0.0.2".parse().unwrap()))];
+	assert_eq!(check_prerequisites(&a_other, &d), Err(rcode::NXRRSET));
+
+	// other names and types are not allowed
+	let other_name = [record("bar.ffhl.de.", CLASS_IN, rtype::A, RData::A("10.0.0.1".parse().unwrap()))];
+	assert_eq!(changes(&other_name, &d), Err(rcode::REFUSED));
+	let ns = [record("foo.ffhl.de.", CLASS_IN, rtype::NS, RData::Ns("ns.example.com.".to_string()))];
+	assert_eq!(changes(&ns, &d), Err(rcode::REFUSED));
+	let delete_ns = [record("foo.ffhl.de.", CLASS_ANY, rtype::NS, empty())];
+	assert_eq!(changes(&delete_ns, &d), Err(rcode::REFUSED));
+}
+
+
+
+#[tokio::test]
+async fn atomic_prerequisites() {
+	use super::message::Question;
+	use super::Builtin;
+	use crate::db;
+
+	let zones: Vec<config::Domain> = vec![toml::from_str(r#"
+		name = "ffhl.de."
+		description = "test"
+		allowed_ips = []
+		validity = 1
+		nameservers = ["ns1.ffhl.de."]
+	"#).unwrap()];
+
+	let (_tmp, db) = db::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let backend = Builtin::new(db.clone(), zones.clone(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	let service = Service::for_test(db.clone(), Arc::new(backend));
+
+	let d = db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.1".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+
+	// adds a TXT record if the address is still the same
+	let record = |class, rtype, data| Record { name: "foo.ffhl.de.".to_string(), rtype, class, ttl: 0, data };
+	let mut msg = Message::request(1, opcode::UPDATE, Question { name: "ffhl.de.".to_string(), qtype: rtype::SOA, qclass: CLASS_IN });
+	msg.answers.push(record(CLASS_IN, rtype::A, RData::A("10.0.0.1".parse().unwrap())));
+	msg.authority.push(record(CLASS_IN, rtype::TXT, RData::Txt(vec!["hello".to_string()])));
+	let peer: IpAddr = "192.0.2.1".parse().unwrap();
+
+	// the address changes after the key of the update was looked up
+	db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.2".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+	assert_eq!(update(&service, &zones, &msg, &d, peer).await, Err(rcode::NXRRSET));
+	assert!(db.get_domain(&d.domainname).unwrap().unwrap().txt.is_empty());
+
+	msg.answers[0].data = RData::A("10.0.0.2".parse().unwrap());
+	assert_eq!(update(&service, &zones, &msg, &d, peer).await, Ok(()));
+	assert_eq!(db.get_domain(&d.domainname).unwrap().unwrap().txt, vec!["hello".to_string()]);
+}
@@ -0,0 +1,134 @@
+use super::dnssec::{self, KeyStore};
+use super::message::{name_in_zone, name_eq, RData, Record, Soa};
+use crate::config;
+use crate::db::{self, Database, ZoneRecord};
+use crate::DNSTTL;
+use chrono::Utc;
+
+
+/// the configured domain a name belongs to
+pub fn find_zone<'a>(zones: &'a [config::Domain], name: &str) -> Option<&'a config::Domain> {
+	zones.iter()
+		.filter(|z| name_in_zone(name, &z.name))
+		// the most specific zone wins
+		.max_by_key(|z| z.name.len())
+}
+
+
+pub fn is_apex(zone: &config::Domain, name: &str) -> bool {
+	name_eq(&zone.name, name)
+}
+
+
+pub fn soa_record(zone: &config::Domain, serial: u32) -> Record {
+	Record::new(&zone.name, zone.soa.ttl, RData::Soa(Soa {
+		mname: zone.mname(),
+		rname: zone.rname(),
+		serial,
+		refresh: zone.soa.refresh,
+		retry: zone.soa.retry,
+		expire: zone.soa.expire,
+		minimum: zone.soa.minimum,
+	}))
+}
+
+
+/// SOA record for the authority section of negative answers (RFC 2308)
+pub fn negative_soa_record(zone: &config::Domain, serial: u32) -> Record {
+	let mut soa = soa_record(zone, serial);
+	soa.ttl = zone.soa.ttl.min(zone.soa.minimum);
+	soa
+}
+
+
+pub fn ns_records(zone: &config::Domain) -> Vec<Record> {
+	zone.nameservers
+		.iter()
+		.map(|ns| Record::new(&zone.name, zone.soa.ttl, RData::Ns(ns.clone())))
+		.collect()
+}
+
+
+/// all records at the apex of the zone
+pub fn apex_records(zone: &config::Domain, serial: u32) -> Vec<Record> {
+	let mut records = vec![soa_record(zone, serial)];
+	records.extend(ns_records(zone));
+	records
+}
+
+
+/// A, AAAA and TXT records of a dynamic domain
+pub fn domain_records(d: &db::Domain) -> Vec<Record> {
+	d.zone_records().iter().map(record).collect()
+}
+
+
+pub fn record(r: &ZoneRecord) -> Record {
+	let ttl = DNSTTL as u32;
+	match r {
+		ZoneRecord::A(name, a) => Record::new(name, ttl, RData::A(*a)),
+		ZoneRecord::Aaaa(name, a) => Record::new(name, ttl, RData::Aaaa(*a)),
+		ZoneRecord::Txt(name, txt) => Record::new(name, ttl, RData::Txt(vec![txt.clone()])),
+	}
+}
+
+
+/// all records of the zone, starting with the SOA record
+pub fn all_records(db: &Database, zone: &config::Domain, serial: u32) -> Result<Vec<Record>, db::Error> {
+	let mut records = apex_records(zone, serial);
+
+	for d in db.get_by_suffix(&zone.name)? {
+		records.extend(domain_records(&d));
+	}
+
+	Ok(records)
+}
+
+
+/// all records of the zone with the DNSSEC records if it is signed
+pub fn export_records(db: &Database, keys: &KeyStore, zone: &config::Domain, serial: u32) -> Result<Vec<Record>, String> {
+	signed(keys, zone, all_records(db, zone, serial)?)
+}
+
+
+/// the records the built-in nameserver serves, starting with the SOA
+/// record. The serial is read together with them, so it always
+/// matches the records and the journal.
+pub fn published_records(db: &Database, keys: &KeyStore, zone: &config::Domain) -> Result<(u32, Vec<Record>), String> {
+	let (serial, published) = db.published_zone(zone)?;
+	let mut records = apex_records(zone, serial);
+	records.extend(published.iter().map(record));
+
+	Ok((serial, signed(keys, zone, records)?))
+}
+
+
+/// adds the DNSSEC records to the records of a signed zone.
+/// Fails if the zone should be signed but there are no keys.
+fn signed(keys: &KeyStore, zone: &config::Domain, records: Vec<Record>) -> Result<Vec<Record>, String> {
+	if zone.dnssec.is_none() {
+		return Ok(records);
+	}
+
+	let keys = keys.get(zone)?;
+	Ok(dnssec::sign_zone(zone, &keys, records, Utc::now()))
+}
+
+
+/// the zone in master file format, ready for nsd or bind
+pub fn zone_file(db: &Database, keys: &KeyStore, zone: &config::Domain, serial: u32) -> Result<String, String> {
+	Ok(master_file(zone, &export_records(db, keys, zone, serial)?))
+}
+
+
+/// records in master file format (RFC 1035 section 5)
+pub fn master_file(zone: &config::Domain, records: &[Record]) -> String {
+	let mut lines = vec![
+		"$ORIGIN .".to_string(),
+		format!("$TTL {}", zone.soa.ttl),
+	];
+	lines.extend(records.iter().map(|r| r.to_string()));
+	lines.push(String::new());
+
+	lines.join("\n")
+}
@@ -3,6 +3,7 @@ use rocket::http::RawStr;
 use std::fmt;
 use rocket::request::FromParam;
 use rocket::form::{self, FromFormField, ValueField};
+use crate::dns::message::{MAX_LABEL_SIZE, MAX_NAME_SIZE};
 
 
 #[derive(Clone)]
@@ -51,14 +52,35 @@ impl Dname {
 	pub fn is_subdomain_of(&self, other: &Dname) -> bool {
 		self.parts.len() > other.parts.len() && self.ends_with(other)
 	}
+
+	/// whether all labels have 1 to 63 letters, digits or dashes
+	/// (RFC 1123 section 2.1) and the name fits into 255 bytes
+	pub fn is_valid(&self) -> bool {
+		// every label has its length in front, the root an empty label
+		let size = self.parts.iter().map(|p| p.len()).sum::<usize>() + 1;
+		size <= MAX_NAME_SIZE && self.parts.iter().all(|p| is_ldh_label(p.trim_end_matches('.')))
+	}
+}
+
+
+fn is_ldh_label(label: &str) -> bool {
+	(1..=MAX_LABEL_SIZE).contains(&label.len())
+		&& label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+		&& !label.starts_with('-')
+		&& !label.ends_with('-')
 }
 
 
+/// only valid names, see `Dname::is_valid`
 impl FromStr for Dname {
 	type Err = String;
 
 	fn from_str(a: &str) -> Result<Self, Self::Err> {
-		Ok(Self::new(a.to_string()))
+		let name = Self::new(a.to_string());
+		match name.is_valid() {
+			true => Ok(name),
+			false => Err(format!("{} is not a valid domain name", a)),
+		}
 	}
 }
 
@@ -67,14 +89,14 @@ impl<'r> FromParam<'r> for Dname {
 	type Error = &'r RawStr;
 
 	fn from_param(param: &'r str) -> Result<Self, Self::Error> {
-		Ok(Dname::new(param.to_string()))
+		param.parse().map_err(|_| param.into())
 	}
 }
 
 
 impl<'v> FromFormField<'v> for Dname {
-	fn from_value(form_value: ValueField) -> form::Result<'v, Self> {
-		Ok(Self::new(form_value.value.to_string()))
+	fn from_value(form_value: ValueField<'v>) -> form::Result<'v, Self> {
+		Ok(form_value.value.parse().map_err(form::Error::validation)?)
 	}
 }
 
@@ -141,3 +163,22 @@ fn subdomnain_stripping() {
 	assert_eq!(domain1.strip_subdomain(), "ffhl.de.");
 	assert_eq!(domain2.strip_subdomain(), "bar.ffhl.de.");
 }
+
+
+#[test]
+fn valid_names() {
+	assert!("foo-1.ffhl.de".parse::<Dname>().is_ok());
+	assert!(format!("{}.ffhl.de.", "a".repeat(63)).parse::<Dname>().is_ok());
+
+	assert!(format!("{}.ffhl.de.", "a".repeat(64)).parse::<Dname>().is_err());
+	assert!("foo bar.ffhl.de.".parse::<Dname>().is_err());
+	assert!("foo.ffhl.de. 60 IN A 10.0.0.1\n".parse::<Dname>().is_err());
+	for name in &["foo;.ffhl.de.", "(foo.ffhl.de.", "foo\n.ffhl.de.", "foo..ffhl.de.", "-foo.ffhl.de.", "foo_bar.ffhl.de.", ".", ""] {
+		assert!(name.parse::<Dname>().is_err(), "{}", name);
+	}
+
+	// 255 bytes in wire format are the limit
+	let label = "a".repeat(63);
+	assert!(format!("{0}.{0}.{0}.{1}.", label, &label[..61]).parse::<Dname>().is_ok());
+	assert!(format!("{0}.{0}.{0}.{1}.", label, &label[..62]).parse::<Dname>().is_err());
+}
@@ -1,27 +1,86 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use crate::Database;
-use crate::db::Domain;
+use crate::db::{self, Account, Domain, Email, HistoryEntry, Suspension, UpdateStatus};
+use crate::dns::dnssec::KeyStore;
 use crate::domain::Dname;
+use crate::mail::{self, Mail, Mailer};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
 #[allow(unused_imports)]
 use log::{error, warn, info, debug, trace};
 use serde::{Serialize};
 use std::fmt::{self, Display};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use crate::CONFIG;
-use std::sync::mpsc;
-use crate::nsupdate::{self, nsupdate::UpdateMessage};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::CONFIG;
+use crate::{HISTORY_LIMIT, MAX_TXT_RECORDS, UPDATE_WAIT_TIMEOUT};
+use crate::{RECOVERY_LINK_VALIDITY, RECOVERY_MAIL_INTERVAL, VERIFICATION_MAIL_INTERVAL};
+use crate::nsupdate::{self, Updater, UpdateHandle, nsupdate::UpdateMessage};
 
 /// token length in bytes
 /// The hex length will be double the length
 const TOKEN_LENGTH: usize = 8;
+const ACCOUNT_NAME_LENGTH: std::ops::RangeInclusive<usize> = 3..=32;
 
 pub type Token = String;
 
 pub struct UpdateRequest {
 	pub domain: String,
-	pub addr: IpAddr,
 	pub token: String,
+	pub changes: Vec<RecordChange>,
+	/// address the request came from, for the history
+	pub source: Option<IpAddr>,
+	/// checks the records the domain has right before the change, in
+	/// the same transaction. For the prerequisites of DNS updates.
+	pub check: Option<Box<dyn Fn(&Domain) -> Result<(), Error> + Send + Sync>>,
+}
+
+
+/// a change to the records of a domain
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordChange {
+	/// sets the A or AAAA record
+	SetAddr(IpAddr),
+	/// removes the A or AAAA record if it has this address
+	RemoveAddr(IpAddr),
+	RemoveIpv4,
+	RemoveIpv6,
+	AddTxt(String),
+	RemoveTxt(String),
+	RemoveAllTxt,
+}
+
+impl RecordChange {
+	fn apply(&self, d: &mut Domain) {
+		match self {
+			Self::SetAddr(IpAddr::V4(a)) => d.ipv4 = Some(*a),
+			Self::SetAddr(IpAddr::V6(a)) => d.ipv6 = Some(*a),
+			Self::RemoveAddr(IpAddr::V4(a)) => if d.ipv4 == Some(*a) { d.ipv4 = None },
+			Self::RemoveAddr(IpAddr::V6(a)) => if d.ipv6 == Some(*a) { d.ipv6 = None },
+			Self::RemoveIpv4 => d.ipv4 = None,
+			Self::RemoveIpv6 => d.ipv6 = None,
+			Self::AddTxt(t) => if !d.txt.contains(t) { d.txt.push(t.clone()) },
+			Self::RemoveTxt(t) => d.txt.retain(|x| x != t),
+			Self::RemoveAllTxt => d.txt.clear(),
+		}
+	}
+}
+
+
+/// which domains `Service::find_domains` returns, empty parts match everything
+#[derive(Clone, Debug, Default)]
+pub struct DomainFilter {
+	pub suffix: Option<String>,
+	/// part of the name
+	pub text: Option<String>,
+	pub expires_before: Option<DateTime<Utc>>,
+	pub updated_before: Option<DateTime<Utc>>,
+	pub locked: Option<bool>,
+	pub suspended: Option<bool>,
 }
 
 
@@ -31,7 +90,30 @@ pub enum Error {
 	DomainNotFound,
 	InvalidToken,
 	InvalidDomain,
+	/// a label is not made of letters, digits and dashes or the name is too long
+	InvalidDomainName,
 	DomainExists,
+	/// an admin locked the domain
+	DomainLocked,
+	/// an admin suspended the domain, with the reason
+	DomainSuspended(String),
+	/// the domain expired, the owner can reclaim it
+	DomainExpired,
+	/// the name expired recently and is free again after this time
+	DomainInQuarantine(DateTime<Utc>),
+	AccountExists,
+	/// the name is too short or has other characters than `a-z0-9._-`
+	InvalidAccountName,
+	/// more than `MAX_TXT_RECORDS` TXT records
+	TooManyRecords,
+	/// the DNS server rejected the update
+	DnsUpdateFailed(String),
+	/// the DNS server did not answer in time
+	DnsUpdateTimeout,
+	/// a prerequisite of a DNS update is not met, with the rcode
+	PrerequisiteFailed(u8),
+	/// the database failed, the request may succeed later
+	Database(db::Error),
 	#[allow(dead_code)]
 	RecordTypeNotMatching,
 }
@@ -42,103 +124,731 @@ impl From<String> for Error {
 	}
 }
 
+impl From<db::Error> for Error {
+	fn from(e: db::Error) -> Self {
+		match e {
+			db::Error::NotFound(_) => Self::DomainNotFound,
+			db::Error::Exists(_) => Self::DomainExists,
+			e => Self::Database(e),
+		}
+	}
+}
+
 impl Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "{}", match self {
 			Self::InvalidToken => "the provided token is invalid",
 			Self::DomainNotFound => "the domain was not found",
+			Self::DomainExists => "the domain is already registered",
+			Self::InvalidDomain => "domains with this suffix are not served here",
+			Self::InvalidDomainName => "domain names have labels of 1 to 63 letters, digits or dashes",
+			Self::DomainLocked => "the domain is locked, contact the admins",
+			Self::DomainSuspended(r) => return write!(f, "the domain is suspended: {}", r),
+			Self::DomainExpired => "the domain expired, it can be reclaimed with its token",
+			Self::DomainInQuarantine(t) => return write!(
+				f, "the domain expired recently, its owner can reclaim it, others can register it after {}",
+				t.format("%Y-%m-%d %H:%M UTC")
+			),
+			Self::AccountExists => "the account name is already taken",
+			Self::InvalidAccountName => return write!(
+				f, "account names have {} to {} letters, digits, dots, dashes or underscores",
+				ACCOUNT_NAME_LENGTH.start(), ACCOUNT_NAME_LENGTH.end()
+			),
 			Self::UpdateError(s) => &s,
+			Self::DnsUpdateFailed(s) => return write!(f, "the dns server rejected the update: {}", s),
+			Self::DnsUpdateTimeout => "the dns server did not answer in time",
+			Self::PrerequisiteFailed(_) => "the prerequisites of the update are not met",
+			Self::TooManyRecords => return write!(f, "a domain can have at most {} txt records", MAX_TXT_RECORDS),
+			Self::Database(db::Error::Unavailable(_)) => "the database is temporarily unavailable",
+			Self::Database(_) => "internal database error",
 			_ => "unknown or undocumented error"
 		})
 	}
 }
 
 
+/// allows something once per interval for the same key
+#[derive(Clone, Default)]
+pub struct RateLimit {
+	last: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl RateLimit {
+	/// whether `key` was not allowed within the last `interval`, then
+	/// it is not allowed again for `interval`
+	pub fn allow(&self, key: &str, interval: chrono::Duration, now: DateTime<Utc>) -> bool {
+		let mut last = self.last.lock().unwrap();
+		last.retain(|_, t| *t + interval > now);
+		if last.contains_key(key) {
+			return false;
+		}
+
+		last.insert(key.to_string(), now);
+		true
+	}
+
+	/// whether `key` was recorded within the last `interval`
+	pub fn limited(&self, key: &str, interval: chrono::Duration, now: DateTime<Utc>) -> bool {
+		let mut last = self.last.lock().unwrap();
+		last.retain(|_, t| *t + interval > now);
+		last.contains_key(key)
+	}
+
+	/// counts `key` as done at `now`, without checking the limit
+	pub fn record(&self, key: &str, now: DateTime<Utc>) {
+		self.last.lock().unwrap().insert(key.to_string(), now);
+	}
+}
+
+
 #[derive(Clone)]
 pub struct Service {
 	db: Database,
-	updater: Arc<Mutex<mpsc::Sender<UpdateMessage>>>,
+	updater: Updater,
+	/// `None` if no smtp relay is configured
+	mailer: Option<Arc<Mailer>>,
+	/// of the recovery mails, by domain and by address
+	recovery_limit: RateLimit,
+	/// of the verification mails, by domain and by address
+	verification_limit: RateLimit,
+	/// key of the codes in the links of the mails
+	link_key: Arc<Vec<u8>>,
 }
 
 impl Service {
-	pub fn new(db: Database) -> Self {
+	/// creates the service and starts the updater task. Fails if the
+	/// smtp relay or the `secret_key` in the config cannot be used.
+	/// Must be called from within the tokio runtime.
+	pub fn new(db: Database, keys: Arc<KeyStore>) -> Result<Self, String> {
+		let mailer = CONFIG.smtp.as_ref().map(Mailer::new).transpose()?;
+		let link_key = link_key()?;
+
+		Ok(Self{
+			updater: nsupdate::start_nsupdater(db.clone(), keys, true),
+			db,
+			mailer: mailer.map(Arc::new),
+			recovery_limit: RateLimit::default(),
+			verification_limit: RateLimit::default(),
+			link_key: Arc::new(link_key),
+		})
+	}
+
+	/// for the admin commands, the signatures are
+	/// only renewed by the server
+	pub fn for_command(db: Database, keys: Arc<KeyStore>) -> Self {
+		Self{
+			updater: nsupdate::start_nsupdater(db.clone(), keys, false),
+			db,
+			mailer: None,
+			recovery_limit: RateLimit::default(),
+			verification_limit: RateLimit::default(),
+			link_key: Arc::new(random_key()),
+		}
+	}
+
+	/// without the config, the updates go to `backend`.
+	/// Must be called from within the tokio runtime.
+	#[cfg(test)]
+	pub fn for_test(db: Database, backend: Arc<dyn nsupdate::Backend>) -> Self {
 		Self{
+			updater: nsupdate::start_updater(backend),
 			db,
-			updater: Arc::new(Mutex::new(nsupdate::start_nsupdater())),
+			mailer: None,
+			recovery_limit: RateLimit::default(),
+			verification_limit: RateLimit::default(),
+			link_key: Arc::new(random_key()),
 		}
 	}
 
-	pub fn update_domain(&self, update: UpdateRequest) -> Result<(), Error> {
+	/// sends the mails through `mailer`
+	#[cfg(test)]
+	pub fn with_mailer(mut self, mailer: Mailer) -> Self {
+		self.mailer = Some(Arc::new(mailer));
+		self
+	}
+
+	/// changes the records of a domain. The returned handle
+	/// can be awaited to get the result of the DNS update.
+	pub async fn update_domain(&self, update: UpdateRequest) -> Result<UpdateHandle, Error> {
+		self.change_records(&update.domain, Some(&update.token), &update.changes, update.source, update.check.as_deref()).await
+	}
+
+	/// changes the records of a domain without its token
+	pub async fn set_records(&self, domain: &str, changes: &[RecordChange]) -> Result<UpdateHandle, Error> {
+		self.change_records(domain, None, changes, None, None).await
+	}
+
+	async fn change_records(
+		&self,
+		domain: &str,
+		token: Option<&str>,
+		changes: &[RecordChange],
+		source: Option<IpAddr>,
+		check: Option<&(dyn Fn(&Domain) -> Result<(), Error> + Send + Sync)>,
+	) -> Result<UpdateHandle, Error> {
 		let db = &self.db;
 
-		if !db.exists(&update.domain) {
-			return Err(Error::DomainNotFound);
-		}
+		info!("updating records of {}: {:?}", domain, changes);
+		// the check and the change happen in one step, so concurrent
+		// updates and the cleanup cannot get in between
+		let mut old = Vec::new();
+		let d = db.modify_domain(domain, |d| {
+			if let Some(token) = token {
+				if token != d.token {
+					return Err(Error::InvalidToken);
+				}
+				if d.locked {
+					return Err(Error::DomainLocked);
+				}
+			}
+			if let Some(s) = &d.suspended {
+				return Err(Error::DomainSuspended(s.reason.clone()));
+			}
+			if token.is_some() && d.valid_until < Utc::now() {
+				return Err(Error::DomainExpired);
+			}
+			if let Some(check) = check {
+				check(d)?;
+			}
+			old = d.zone_records();
+
+			for change in changes {
+				change.apply(d);
+			}
+
+			if d.txt.len() > MAX_TXT_RECORDS {
+				return Err(Error::TooManyRecords);
+			}
 
-		let d = db.get_domain(&update.domain).unwrap();
+			d.lastupdate = Utc::now();
+			Ok(())
+		})?;
 		info!("{:#?}", d);
 
-		if d.token != update.token {
+		info!("updating domain: {}", domain);
+
+		let handle = self.updater
+			.send(UpdateMessage::from_domain(&d))
+			.await;
+
+		Ok(self.record_history(HistoryEntry {
+			domainname: d.domainname.clone(),
+			time: d.lastupdate,
+			source,
+			token: token.map(token_fingerprint),
+			old,
+			new: d.zone_records(),
+			status: UpdateStatus::Pending,
+		}, handle))
+	}
+
+	/// adds the change to the history and sets its status once the DNS
+	/// server answered. A broken history does not stop the update.
+	fn record_history(&self, entry: HistoryEntry, handle: UpdateHandle) -> UpdateHandle {
+		if entry.old == entry.new {
+			return handle;
+		}
+
+		let id = match self.db.history_add(&entry) {
+			Ok(id) => id,
+			Err(e) => {
+				error!("cannot record the change of {}: {}", entry.domainname, e);
+				return handle;
+			}
+		};
+
+		let db = self.db.clone();
+		handle.inspect(move |r| {
+			let status = match r {
+				Ok(()) => UpdateStatus::Ok,
+				Err(e) => UpdateStatus::Failed(e.clone()),
+			};
+			if let Err(e) = db.history_set_status(id, &status) {
+				error!("cannot record the result of the change of {}: {}", entry.domainname, e);
+			}
+		})
+	}
+
+	/// removes the records of a removed domain from the DNS
+	async fn announce_removal(&self, d: Domain) -> UpdateHandle {
+		let handle = self.updater
+			.send(UpdateMessage::new_remove_message(d.domainname.clone()))
+			.await;
+
+		self.record_history(HistoryEntry {
+			domainname: d.domainname.clone(),
+			time: Utc::now(),
+			source: None,
+			token: None,
+			old: d.zone_records(),
+			new: Vec::new(),
+			status: UpdateStatus::Pending,
+		}, handle)
+	}
+
+	/// the last changes of a domain, only for the owner of the token
+	pub fn history(&self, domain: &str, token: &str) -> Result<Vec<HistoryEntry>, Error> {
+		let d = self.db.get_domain(&domain.to_string())?.ok_or(Error::DomainNotFound)?;
+		if d.token != token {
 			return Err(Error::InvalidToken);
 		}
 
-		info!("updating ip for {} to {:?}", update.domain, update.addr);
-		match update.addr {
-			IpAddr::V4(addr) => db.update_ipv4(&update.domain, addr),
-			IpAddr::V6(addr) => db.update_ipv6(&update.domain, addr),
+		Ok(self.db.history(&d.domainname, HISTORY_LIMIT)?)
+	}
+
+	/// the last changes of a domain, for the admins
+	pub fn domain_history(&self, domain: &str) -> Result<Vec<HistoryEntry>, Error> {
+		Ok(self.db.history(domain, HISTORY_LIMIT)?)
+	}
+
+	/// waits for the result of an update, at most `UPDATE_WAIT_TIMEOUT` seconds
+	pub async fn wait_for_update(&self, handle: UpdateHandle) -> Result<(), Error> {
+		match tokio::time::timeout(Duration::from_secs(UPDATE_WAIT_TIMEOUT), handle.wait()).await {
+			Err(_) => Err(Error::DnsUpdateTimeout),
+			Ok(Err(e)) => Err(Error::DnsUpdateFailed(e)),
+			Ok(Ok(())) => Ok(()),
 		}
+	}
 
-		db.update_lastupdate(&update.domain, Utc::now());
+	/// registers a domain with a new token, for an account if `owner` is
+	/// given. If an address is given, the owner gets a mail to verify it.
+	pub fn new_domain(&self, d: Dname, email: Option<&str>, owner: Option<&str>) -> Result<Token, Error> {
+		let email = self.check_address(email)?;
+		let token = self.register_domain(d.clone(), None, owner)?;
 
-		info!("updating domain: {}", update.domain);
+		if email.is_some() {
+			self.set_email(&d.to_string(), &token, email.as_deref())?;
+		}
+		Ok(token)
+	}
 
-		self.updater
-			.lock()
-			.unwrap()
-			.send(UpdateMessage::from_updaterequest(update))
-			.unwrap();
+	/// the trimmed address, `None` for an empty one
+	fn check_address(&self, address: Option<&str>) -> Result<Option<String>, Error> {
+		let address = match address.map(str::trim).filter(|a| !a.is_empty()) {
+			Some(a) => a,
+			None => return Ok(None),
+		};
+		if self.mailer.is_none() {
+			return Err(Error::UpdateError("this server does not send mails".to_string()));
+		}
 
-		Ok(())
+		Ok(Some(mail::parse_address(address)?.to_string()))
 	}
 
-	pub fn new_domain(&self, d: Dname) -> Result<Token, Error> {
+	/// sets or removes the address of the owner. An unverified address
+	/// gets the mail with the link to verify it (again), unless the domain
+	/// or the address got one within the last `VERIFICATION_MAIL_INTERVAL`.
+	pub fn set_email(&self, domain: &str, token: &str, address: Option<&str>) -> Result<Domain, Error> {
+		let address = self.check_address(address)?;
+		let d = self.db.modify_domain(domain, |d| {
+			if d.token != token {
+				return Err(Error::InvalidToken);
+			}
+			if d.email.as_ref().map(|e| &e.address) != address.as_ref() {
+				d.email = address.clone().map(|address| Email {
+					address,
+					verified: false,
+					reminded: None,
+				});
+			}
+			Ok(())
+		})?;
 
-		if CONFIG.get_domain_config(&d.strip_subdomain()).is_none() {
-			error!("domain suffix not configured: {}", d);
-			return Err(Error::InvalidDomain);
+		if let Some(e) = d.email.as_ref().filter(|e| !e.verified) {
+			// the link of the last mail is still valid
+			let now = Utc::now();
+			let interval = chrono::Duration::minutes(VERIFICATION_MAIL_INTERVAL);
+			if !self.verification_limit.allow(&e.address.to_lowercase(), interval, now)
+				|| !self.verification_limit.allow(&d.domainname.to_lowercase(), interval, now) {
+				warn!("too many verification mails for {}", d.domainname);
+				return Ok(d);
+			}
+			info!("sending verification mail for {}", d.domainname);
+			let link = mail::web_url(&format!("/verify?domain={}&code={}", d.domainname, email_code(&self.link_key, &d, &e.address)));
+			self.send_mail(&e.address, mail::verification(&d, &link));
 		}
+		Ok(d)
+	}
 
-		if self.db.exists(&d.to_string()) {
-			return Err(Error::DomainExists);
+	/// marks the address as verified if the code of the link is right
+	pub fn verify_email(&self, domain: &str, code: &str) -> Result<Domain, Error> {
+		self.db.modify_domain(domain, |d| {
+			let valid = d.email.as_ref()
+				.is_some_and(|e| fixed_time_eq(email_code(&self.link_key, d, &e.address).as_bytes(), code.as_bytes()));
+			match &mut d.email {
+				Some(e) if valid => e.verified = true,
+				_ => return Err(Error::UpdateError("the link is invalid or outdated".to_string())),
+			}
+			Ok(())
+		})
+	}
+
+	/// mails a link to set a new token to the verified address of the
+	/// domain. Nobody learns whether the domain exists or has an address.
+	pub fn request_recovery(&self, domain: &str) -> Result<(), Error> {
+		if self.mailer.is_none() {
+			return Err(Error::UpdateError("this server does not send mails".to_string()));
 		}
+		let d = match self.db.get_domain(&domain.to_string())? {
+			Some(d) => d,
+			None => {
+				debug!("token recovery for unknown domain {}", domain);
+				return Ok(());
+			}
+		};
+		let email = match d.email.as_ref().filter(|e| e.verified) {
+			Some(e) => e,
+			None => {
+				debug!("token recovery for {} without verified address", d.domainname);
+				return Ok(());
+			}
+		};
 
-		let token = generate_token();
-		let domain = Domain::new_with_token(&d, token.clone(), CONFIG.get_domain_config(&d.strip_subdomain()).unwrap().validity);
-		self.db.insert_new_domain(&domain);
+		let now = Utc::now();
+		let interval = chrono::Duration::minutes(RECOVERY_MAIL_INTERVAL);
+		if !self.recovery_limit.allow(&d.domainname.to_lowercase(), interval, now)
+			|| !self.recovery_limit.allow(&email.address.to_lowercase(), interval, now) {
+			warn!("too many token recovery requests for {}", d.domainname);
+			return Ok(());
+		}
+
+		info!("sending token recovery mail for {}", d.domainname);
+		let expires = (now + chrono::Duration::minutes(RECOVERY_LINK_VALIDITY)).timestamp();
+		let link = mail::web_url(&format!(
+			"/recover/token?domain={}&expires={}&code={}",
+			d.domainname, expires, recovery_code(&self.link_key, &d, expires)
+		));
+		self.send_mail(&email.address, mail::recovery(&d, &link));
+		Ok(())
+	}
+
+	/// sets the given or a new token with the code of a recovery link.
+	/// The link stops working with the old token.
+	pub fn recover_token(&self, domain: &str, expires: i64, code: &str, token: Option<&str>) -> Result<Token, Error> {
+		let token = match token.map(str::trim).filter(|t| !t.is_empty()) {
+			Some(t) if t.len() < TOKEN_LENGTH || t.contains(char::is_whitespace) => {
+				return Err(Error::UpdateError(format!(
+					"the token must have at least {} characters and no spaces", TOKEN_LENGTH
+				)));
+			}
+			Some(t) => t.to_string(),
+			None => generate_token(),
+		};
+
+		let invalid = || Error::UpdateError("the link is invalid or outdated".to_string());
+		let d = self.db.modify_domain(domain, |d| {
+			if expires < Utc::now().timestamp() || !fixed_time_eq(recovery_code(&self.link_key, d, expires).as_bytes(), code.as_bytes()) {
+				return Err(invalid());
+			}
+			if let Some(s) = &d.suspended {
+				return Err(Error::DomainSuspended(s.reason.clone()));
+			}
+			d.token = token.clone();
+			Ok(())
+		}).map_err(|e| match e {
+			Error::DomainNotFound => invalid(),
+			e => e,
+		})?;
+		info!("recovered the token of {}", d.domainname);
 
 		Ok(token)
 	}
 
-	pub fn get_domain(&self, domain: &String) -> Option<Domain> {
-		self.db.get_domain(domain)
+	/// sends the mail in the background, failures are only logged
+	fn send_mail(&self, to: &str, mail: Mail) {
+		if let Some(mailer) = self.mailer.clone() {
+			let to = to.to_string();
+			tokio::spawn(async move {
+				if let Err(e) = mailer.send(&to, mail).await {
+					error!("{}", e);
+				}
+			});
+		}
 	}
 
+	/// mails the owner of a domain with a verified address
+	fn notify(&self, d: &Domain, mail: Mail) {
+		if let Some(e) = d.email.as_ref().filter(|e| e.verified) {
+			self.send_mail(&e.address, mail);
+		}
+	}
 
-	pub fn clean_domains(&self) {
-		trace!("start domain cleanup");
-		let domains = self.db.get_all();
+	/// mails the owners whose domains expire soon
+	async fn send_reminders(&self) {
+		let mailer = match &self.mailer {
+			Some(m) => m,
+			None => return,
+		};
+		let now = Utc::now();
+		let domains = match self.db.get_all() {
+			Ok(d) => d,
+			Err(e) => return error!("cannot read the domains for the reminders: {}", e),
+		};
+
+		for d in domains.iter().filter(|d| reminder_due(d, &CONFIG.reminder_days, now)) {
+			let address = &d.email.as_ref().unwrap().address;
+			// not marked as sent, so it is tried again
+			if let Err(e) = mailer.send(address, mail::reminder(d, &(d.valid_until + quarantine()))).await {
+				error!("{}", e);
+				continue;
+			}
+			debug!("sent expiry reminder for {}", d.domainname);
+
+			let r = self.db.modify_domain(&d.domainname, |d| {
+				if let Some(e) = &mut d.email {
+					e.reminded = Some(now);
+				}
+				Ok::<_, db::Error>(())
+			});
+			if let Err(e) = r {
+				error!("cannot record the reminder for {}: {}", d.domainname, e);
+			}
+		}
+	}
+
+	/// registers a domain with the given or a new token
+	pub fn add_domain(&self, d: Dname, token: Option<Token>) -> Result<Token, Error> {
+		self.register_domain(d, token, None)
+	}
 
-		for d in domains {
-			if d.valid_until < Utc::now() {
-				debug!("removing domain: {}", d.domainname);
-				self.db.remove_domain(&d.domainname);
+	fn register_domain(&self, d: Dname, token: Option<Token>, owner: Option<&str>) -> Result<Token, Error> {
+		if !d.is_valid() {
+			return Err(Error::InvalidDomainName);
+		}
 
-				self.updater
-					.lock()
-					.unwrap()
-					.send(UpdateMessage::new_remove_message(d.domainname))
-					.unwrap();
+		let conf = match CONFIG.get_domain_config(&d.strip_subdomain()) {
+			Some(c) => c,
+			None => {
+				error!("domain suffix not configured: {}", d);
+				return Err(Error::InvalidDomain);
 			}
+		};
+
+		let token = token.unwrap_or_else(generate_token);
+		let mut domain = Domain::new_with_token(&d, token.clone(), conf.validity);
+		domain.owner = owner.map(str::to_string);
+		match self.db.insert_new_domain(&domain) {
+			Ok(()) => Ok(token),
+			Err(db::Error::Exists(name)) => match self.db.get_domain(&name)? {
+				Some(d) if d.valid_until < Utc::now() && d.suspended.is_none() => {
+					Err(Error::DomainInQuarantine(d.valid_until + quarantine()))
+				}
+				_ => Err(Error::DomainExists),
+			},
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// gives an expired domain back to its owner for the validity of its
+	/// suffix. It has no records until the next update.
+	pub fn reclaim_domain(&self, domain: &str, token: &str) -> Result<Domain, Error> {
+		let d = self.db.modify_domain(domain, |d| {
+			if d.token != token {
+				return Err(Error::InvalidToken);
+			}
+			if let Some(s) = &d.suspended {
+				return Err(Error::DomainSuspended(s.reason.clone()));
+			}
+			if d.valid_until >= Utc::now() {
+				return Err(Error::UpdateError("the domain has not expired".to_string()));
+			}
+
+			let conf = CONFIG.get_domain_config(&Dname::new(d.domainname.clone()).strip_subdomain())
+				.ok_or(Error::InvalidDomain)?;
+			d.valid_until = Utc::now() + conf.validity;
+			Ok(())
+		})?;
+		info!("reclaimed domain {}", d.domainname);
+
+		Ok(d)
+	}
+
+	/// creates an account, `password` is the hash
+	pub fn create_account(&self, name: &str, password: String) -> Result<Account, Error> {
+		let valid = ACCOUNT_NAME_LENGTH.contains(&name.len())
+			&& name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+		if !valid {
+			return Err(Error::InvalidAccountName);
+		}
+
+		let account = Account {
+			name: name.to_string(),
+			password,
+			created: Utc::now(),
+		};
+		match self.db.insert_account(&account) {
+			Ok(()) => Ok(account),
+			Err(db::Error::Exists(_)) => Err(Error::AccountExists),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	pub fn get_account(&self, name: &str) -> Result<Option<Account>, Error> {
+		Ok(self.db.get_account(name)?)
+	}
+
+	/// the domains of an account, by name
+	pub fn account_domains(&self, name: &str) -> Result<Vec<Domain>, Error> {
+		Ok(self.db.get_by_owner(name)?)
+	}
+
+	/// moves a domain into an account, for the owner of its token
+	pub fn adopt_domain(&self, owner: &str, domain: &str, token: &str) -> Result<Domain, Error> {
+		let d = self.db.modify_domain(domain, |d| {
+			if d.token != token {
+				return Err(Error::InvalidToken);
+			}
+			d.owner = Some(owner.to_string());
+			Ok(())
+		})?;
+		info!("account {} adopted domain {}", owner, d.domainname);
+
+		Ok(d)
+	}
+
+	pub fn get_domain(&self, domain: &String) -> Result<Option<Domain>, Error> {
+		Ok(self.db.get_domain(domain)?)
+	}
+
+	/// the domains matching all parts of the filter
+	pub fn find_domains(&self, filter: &DomainFilter) -> Result<Vec<Domain>, Error> {
+		let domains = match &filter.suffix {
+			Some(s) => self.db.get_by_suffix(s)?,
+			None => self.db.get_all()?,
+		};
+		let text = filter.text.as_ref().map(|t| t.to_lowercase());
+
+		Ok(domains.into_iter()
+			.filter(|d| text.as_ref().is_none_or(|t| d.domainname.to_lowercase().contains(t)))
+			.filter(|d| filter.expires_before.is_none_or(|t| d.valid_until < t))
+			.filter(|d| filter.updated_before.is_none_or(|t| d.lastupdate < t))
+			.filter(|d| filter.locked.is_none_or(|l| d.locked == l))
+			.filter(|d| filter.suspended.is_none_or(|s| d.suspended.is_some() == s))
+			.collect())
+	}
+
+	/// removes a domain with its records and its history
+	pub async fn remove_domain(&self, domain: &str) -> Result<UpdateHandle, Error> {
+		let d = self.db.remove_domain(domain)?.ok_or(Error::DomainNotFound)?;
+		info!("removed domain: {}", d.domainname);
+		// not in the history, it went with the domain
+		// and the next owner of the name must not see it
+		Ok(self.updater.send(UpdateMessage::new_remove_message(d.domainname)).await)
+	}
+
+	/// makes the domain valid for `validity` from now on
+	pub fn renew_domain(&self, domain: &str, validity: chrono::Duration) -> Result<Domain, Error> {
+		self.db.modify_domain(domain, |d| {
+			d.valid_until = Utc::now() + validity;
+			Ok(())
+		})
+	}
+
+	/// a locked domain keeps its records, but
+	/// they cannot be changed with the token anymore
+	pub fn lock_domain(&self, domain: &str, locked: bool) -> Result<Domain, Error> {
+		self.db.modify_domain(domain, |d| {
+			d.locked = locked;
+			Ok(())
+		})
+	}
+
+	/// takes the records of a domain out of the DNS. Until it is unsuspended
+	/// the domain cannot be updated, does not expire and the name cannot
+	/// be registered again.
+	pub async fn suspend_domain(&self, domain: &str, reason: &str) -> Result<UpdateHandle, Error> {
+		if reason.trim().is_empty() {
+			return Err(Error::UpdateError("a reason is required".to_string()));
+		}
+
+		let mut old = Vec::new();
+		let d = self.db.modify_domain(domain, |d| {
+			old = d.zone_records();
+			d.ipv4 = None;
+			d.ipv6 = None;
+			d.txt.clear();
+			d.suspended = Some(Suspension {
+				reason: reason.trim().to_string(),
+				since: Utc::now(),
+			});
+			Ok::<_, Error>(())
+		})?;
+		info!("suspended domain {}: {}", d.domainname, reason);
+
+		let handle = self.updater
+			.send(UpdateMessage::from_domain(&d))
+			.await;
+
+		Ok(self.record_history(HistoryEntry {
+			domainname: d.domainname.clone(),
+			time: Utc::now(),
+			source: None,
+			token: None,
+			old,
+			new: Vec::new(),
+			status: UpdateStatus::Pending,
+		}, handle))
+	}
+
+	/// the owner can set the records again, the domain expires
+	/// at its old date or after the validity of its suffix,
+	/// whichever is later
+	pub fn unsuspend_domain(&self, domain: &str) -> Result<Domain, Error> {
+		let d = self.db.modify_domain(domain, |d| {
+			if d.suspended.take().is_none() {
+				return Err(Error::UpdateError("the domain is not suspended".to_string()));
+			}
+			let validity = CONFIG.get_domain_config(&Dname::new(d.domainname.clone()).strip_subdomain())
+				.map_or(chrono::Duration::zero(), |c| c.validity);
+			d.valid_until = d.valid_until.max(Utc::now() + validity);
+			Ok(())
+		})?;
+		info!("unsuspended domain {}", d.domainname);
+
+		Ok(d)
+	}
+
+	/// gives the domain a new token, the old one stops working
+	pub fn reset_token(&self, domain: &str) -> Result<Token, Error> {
+		let token = generate_token();
+		self.db.modify_domain(domain, |d| {
+			d.token = token.clone();
+			Ok::<_, Error>(())
+		})?;
+
+		Ok(token)
+	}
+
+
+	pub async fn clean_domains(&self) {
+		trace!("start domain cleanup");
+		let expired = match self.db.clear_expired() {
+			Ok(r) => r,
+			Err(e) => return error!("domain cleanup failed: {}", e),
+		};
+
+		for d in expired {
+			let until = d.valid_until + quarantine();
+			debug!("domain expired, in quarantine until {}: {}", until, d.domainname);
+			self.notify(&d, mail::expired(&d, &until));
+			self.announce_removal(d).await;
+		}
+
+		// their records are gone already
+		match self.db.remove_expired(quarantine()) {
+			Ok(removed) => for d in removed {
+				debug!("removed domain: {}", d.domainname);
+				self.notify(&d, mail::removed(&d));
+			},
+			Err(e) => error!("domain cleanup failed: {}", e),
+		}
+
+		self.send_reminders().await;
+
+		match self.db.expire_history(CONFIG.history_days) {
+			Ok(0) => (),
+			Ok(n) => debug!("removed {} old history entries", n),
+			Err(e) => error!("cannot remove old history: {}", e),
 		}
 	}
 }
@@ -152,3 +862,258 @@ pub fn generate_token() -> Token {
 	}
 	token
 }
+
+
+/// made from `secret_key`, so the links keep working over a
+/// restart. Without one a new key is made on every start.
+fn link_key() -> Result<Vec<u8>, String> {
+	Ok(match CONFIG.secret_key()? {
+		Some(secret) => derive_link_key(&secret),
+		None => random_key(),
+	})
+}
+
+
+/// the links must not be signed with the key of the cookies itself
+fn derive_link_key(secret: &[u8]) -> Vec<u8> {
+	let mut mac = Hmac::new(Sha256::new(), secret);
+	mac.input(b"ffdyndns-links");
+	mac.result().code().to_vec()
+}
+
+
+fn random_key() -> Vec<u8> {
+	(0..32).map(|_| rand::random::<u8>()).collect()
+}
+
+
+/// HMAC-SHA256 of `data` in hex, only the server can make it
+fn link_code(key: &[u8], data: &str) -> String {
+	let mut mac = Hmac::new(Sha256::new(), key);
+	mac.input(data.as_bytes());
+	mac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+/// how long an expired name is kept for its owner
+fn quarantine() -> chrono::Duration {
+	chrono::Duration::days(CONFIG.quarantine_days.max(0))
+}
+
+
+/// the code in the link to verify an address. It changes
+/// with the address and the token of the domain.
+fn email_code(key: &[u8], d: &Domain, address: &str) -> String {
+	link_code(key, &format!("verify:{}:{}:{}", d.domainname.to_lowercase(), address, d.token))
+}
+
+
+/// the code in a recovery link. Only the owner of a verified address gets
+/// it, it stops working when the token or the address changes.
+fn recovery_code(key: &[u8], d: &Domain, expires: i64) -> String {
+	let address = d.email.as_ref().filter(|e| e.verified).map_or("", |e| e.address.as_str());
+	link_code(key, &format!("recover:{}:{}:{}:{}", d.domainname.to_lowercase(), address, expires, d.token))
+}
+
+
+/// whether the last of the reminders `days` before the expiry
+/// that is due already has not been sent yet
+fn reminder_due(d: &Domain, days: &[i64], now: DateTime<Utc>) -> bool {
+	let email = match &d.email {
+		Some(e) if e.verified => e,
+		_ => return false,
+	};
+	if d.suspended.is_some() || d.valid_until < now {
+		return false;
+	}
+
+	days.iter()
+		.map(|days| d.valid_until - chrono::Duration::days(*days))
+		.filter(|t| *t <= now)
+		.max()
+		.is_some_and(|t| email.reminded.is_none_or(|r| r < t))
+}
+
+
+/// identifies a token in the history without revealing it
+pub fn token_fingerprint(token: &str) -> String {
+	crate::sha256!(token)[..8].to_string()
+}
+
+
+
+#[test]
+fn reminders() {
+	let now = Utc::now();
+	let days = [14, 3];
+	let mut d = Domain::new("foo.ffhl.de.".to_string(), chrono::Duration::days(10));
+
+	// only verified addresses get reminders
+	assert!(!reminder_due(&d, &days, now));
+	d.email = Some(Email { address: "owner@example.org".to_string(), verified: false, reminded: None });
+	assert!(!reminder_due(&d, &days, now));
+	d.email.as_mut().unwrap().verified = true;
+	assert!(reminder_due(&d, &days, now));
+
+	// the 14 day reminder was sent, the 3 day one is not due yet
+	d.email.as_mut().unwrap().reminded = Some(now - chrono::Duration::days(2));
+	assert!(!reminder_due(&d, &days, now));
+	assert!(reminder_due(&d, &days, now + chrono::Duration::days(8)));
+
+	// a renewed domain gets the reminders again
+	d.valid_until = now + chrono::Duration::days(2);
+	assert!(reminder_due(&d, &days, now));
+	d.valid_until = now + chrono::Duration::days(20);
+	assert!(!reminder_due(&d, &days, now));
+
+	d.valid_until = now - chrono::Duration::days(1);
+	assert!(!reminder_due(&d, &days, now));
+
+	// the code needs the key of the server, which is not the secret_key
+	let secret = random_key();
+	assert_eq!(derive_link_key(&secret), derive_link_key(&secret));
+	assert_ne!(derive_link_key(&secret), secret);
+	assert_ne!(derive_link_key(&secret), derive_link_key(&random_key()));
+	let key = random_key();
+	assert_ne!(email_code(&key, &d, "owner@example.org"), email_code(&key, &d, "other@example.org"));
+	assert_ne!(email_code(&key, &d, "owner@example.org"), email_code(&random_key(), &d, "owner@example.org"));
+	assert_eq!(email_code(&key, &d, "owner@example.org"), email_code(&key, &d, "owner@example.org"));
+}
+
+
+
+#[test]
+fn recovery() {
+	let now = Utc::now();
+	let mut d = Domain::new("foo.ffhl.de.".to_string(), chrono::Duration::days(10));
+	d.email = Some(Email { address: "owner@example.org".to_string(), verified: true, reminded: None });
+	let key = random_key();
+	let code = recovery_code(&key, &d, 1000);
+
+	// the link is bound to the key of the server, its expiry, the token and the address
+	assert_eq!(recovery_code(&key, &d, 1000), code);
+	assert_ne!(recovery_code(&random_key(), &d, 1000), code);
+	assert_ne!(recovery_code(&key, &d, 1001), code);
+	let mut other = d.clone();
+	other.token = generate_token();
+	assert_ne!(recovery_code(&key, &other, 1000), code);
+	d.email.as_mut().unwrap().address = "other@example.org".to_string();
+	assert_ne!(recovery_code(&key, &d, 1000), code);
+
+	let limit = RateLimit::default();
+	let interval = chrono::Duration::minutes(15);
+	assert!(limit.allow("foo.ffhl.de.", interval, now));
+	assert!(!limit.allow("foo.ffhl.de.", interval, now + chrono::Duration::minutes(14)));
+	assert!(limit.allow("bar.ffhl.de.", interval, now));
+	assert!(limit.allow("foo.ffhl.de.", interval, now + chrono::Duration::minutes(15)));
+
+	assert!(!limit.limited("baz.ffhl.de.", interval, now));
+	limit.record("baz.ffhl.de.", now);
+	assert!(limit.limited("baz.ffhl.de.", interval, now + chrono::Duration::minutes(14)));
+	assert!(!limit.limited("baz.ffhl.de.", interval, now + chrono::Duration::minutes(15)));
+}
+
+
+
+#[tokio::test]
+async fn accounts() {
+	let (_tmp, db) = db::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let backend = crate::dns::Builtin::new(db.clone(), Vec::new(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	let service = Service::for_test(db.clone(), Arc::new(backend));
+
+	for name in &["al", "alice bob", "alice/", &"a".repeat(33)] {
+		assert!(matches!(service.create_account(name, "hash".to_string()), Err(Error::InvalidAccountName)));
+	}
+	let account = service.create_account("Alice", "hash".to_string()).unwrap();
+	assert_eq!(service.get_account("alice").unwrap().map(|a| a.name), Some(account.name));
+	assert!(matches!(service.create_account("alice", "other".to_string()), Err(Error::AccountExists)));
+
+	// only with the token, and the domain moves on to another account
+	let token = db.get_domain(&"foo.ffhl.de.".to_string()).unwrap().unwrap().token;
+	assert!(matches!(service.adopt_domain("Alice", "foo.ffhl.de.", "x"), Err(Error::InvalidToken)));
+	assert!(service.account_domains("alice").unwrap().is_empty());
+	assert_eq!(service.adopt_domain("Alice", "foo.ffhl.de.", &token).unwrap().owner, Some("Alice".to_string()));
+	assert_eq!(service.account_domains("alice").unwrap().len(), 1);
+	assert!(service.adopt_domain("Alice", "bar.ffhl.de.", &token).is_err());
+
+	service.create_account("bob", "hash".to_string()).unwrap();
+	service.adopt_domain("bob", "foo.ffhl.de.", &token).unwrap();
+	assert!(service.account_domains("alice").unwrap().is_empty());
+	assert_eq!(service.account_domains("bob").unwrap().len(), 1);
+}
+
+
+
+#[tokio::test]
+async fn recover_once() {
+	let (_tmp, db) = db::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let backend = crate::dns::Builtin::new(db.clone(), Vec::new(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	let service = Service::for_test(db.clone(), Arc::new(backend));
+
+	let d = db.modify_domain("foo.ffhl.de.", |d| {
+		d.email = Some(Email { address: "owner@example.org".to_string(), verified: true, reminded: None });
+		Ok::<_, db::Error>(())
+	}).unwrap();
+
+	let expires = (Utc::now() + chrono::Duration::minutes(5)).timestamp();
+	let forged = crate::sha256!(&format!("recover:foo.ffhl.de.:owner@example.org:{}:{}", expires, d.token));
+	assert!(service.recover_token("foo.ffhl.de.", expires, &forged, None).is_err());
+
+	let code = recovery_code(&service.link_key, &d, expires);
+	assert!(service.recover_token("foo.ffhl.de.", expires - 3600, &code, None).is_err());
+	let token = service.recover_token("foo.ffhl.de.", expires, &code, Some("new-token")).unwrap();
+	assert_eq!(token, "new-token");
+	assert_eq!(db.get_domain(&d.domainname).unwrap().unwrap().token, "new-token");
+	assert!(service.recover_token("foo.ffhl.de.", expires, &code, None).is_err());
+}
+
+
+
+#[tokio::test]
+async fn verification_limit() {
+	let (_tmp, db) = db::test_database(&["foo.ffhl.de.", "bar.ffhl.de."], chrono::Duration::hours(1));
+	let backend = crate::dns::Builtin::new(db.clone(), Vec::new(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	let (mailer, stub) = Mailer::stub();
+	let service = Service::for_test(db.clone(), Arc::new(backend)).with_mailer(mailer);
+	let foo = db.get_domain(&"foo.ffhl.de.".to_string()).unwrap().unwrap().token;
+	let bar = db.get_domain(&"bar.ffhl.de.".to_string()).unwrap().unwrap().token;
+
+	// only the first mail within the interval, also for another
+	// domain with the same address or another address of the domain
+	service.set_email("foo.ffhl.de.", &foo, Some("owner@example.org")).unwrap();
+	service.set_email("foo.ffhl.de.", &foo, Some("owner@example.org")).unwrap();
+	service.set_email("bar.ffhl.de.", &bar, Some("Owner@example.org")).unwrap();
+	let d = service.set_email("foo.ffhl.de.", &foo, Some("other@example.org")).unwrap();
+	assert_eq!(d.email.unwrap().address, "other@example.org");
+
+	tokio::time::sleep(Duration::from_millis(100)).await;
+	let messages = stub.messages().await;
+	assert_eq!(messages.len(), 1);
+	assert_eq!(messages[0].0.to(), &["owner@example.org".parse::<lettre::Address>().unwrap()]);
+}
+
+
+
+#[tokio::test]
+async fn history_of_removed_domain() {
+	let (_tmp, db) = db::test_database(&[], chrono::Duration::hours(1));
+	let backend = crate::dns::Builtin::new(db.clone(), Vec::new(), &toml::from_str(r#"listen = "127.0.0.1:0""#).unwrap());
+	let service = Service::for_test(db.clone(), Arc::new(backend));
+	let name = "foo.ffdyn.net.".parse::<Dname>().unwrap();
+	let update = |token: &str| UpdateRequest {
+		domain: name.to_string(),
+		token: token.to_string(),
+		changes: vec![RecordChange::SetAddr("10.0.0.1".parse().unwrap())],
+		source: Some("192.0.2.1".parse().unwrap()),
+		check: None,
+	};
+
+	let token = service.new_domain(name.clone(), None, None).unwrap();
+	service.update_domain(update(&token)).await.unwrap();
+	assert_eq!(service.history(&name.to_string(), &token).unwrap().len(), 1);
+	service.remove_domain(&name.to_string()).await.unwrap();
+
+	// the next owner of the name does not see the addresses of the last one
+	let token = service.new_domain(name.clone(), None, None).unwrap();
+	assert!(service.history(&name.to_string(), &token).unwrap().is_empty());
+}
@@ -1,7 +1,45 @@
 CREATE TABLE IF NOT EXISTS domains (
-	domainname TEXT PRIMARY KEY,
+	domainname TEXT PRIMARY KEY COLLATE NOCASE,
+	-- the configured domain the name is registered under
+	suffix TEXT NOT NULL COLLATE NOCASE,
 	token TEXT NOT NULL,
-	lastupdate TEXT,
+	lastupdate TEXT NOT NULL,
+	valid_until TEXT NOT NULL,
 	ipv4 TEXT,
 	ipv6 TEXT
 );
+CREATE INDEX IF NOT EXISTS domains_suffix ON domains (suffix);
+CREATE INDEX IF NOT EXISTS domains_valid_until ON domains (valid_until);
+
+CREATE TABLE IF NOT EXISTS txt_records (
+	domainname TEXT NOT NULL COLLATE NOCASE REFERENCES domains (domainname) ON DELETE CASCADE,
+	position INTEGER NOT NULL,
+	content TEXT NOT NULL,
+	PRIMARY KEY (domainname, position)
+);
+
+-- SOA serial of every zone
+CREATE TABLE IF NOT EXISTS serials (
+	zone TEXT PRIMARY KEY,
+	serial INTEGER NOT NULL
+);
+
+-- records of every name as last announced to the secondaries
+CREATE TABLE IF NOT EXISTS published (
+	name TEXT PRIMARY KEY,
+	-- the zone the name belongs to
+	suffix TEXT NOT NULL,
+	records TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS published_suffix ON published (suffix);
+
+-- changes of every zone for IXFR
+CREATE TABLE IF NOT EXISTS journal (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	zone TEXT NOT NULL,
+	serial_from INTEGER NOT NULL,
+	serial_to INTEGER NOT NULL,
+	removed TEXT NOT NULL,
+	added TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS journal_zone ON journal (zone, id);
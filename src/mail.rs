@@ -0,0 +1,193 @@
+use crate::config::{Smtp, SmtpSecurity};
+use crate::db::Domain;
+use crate::CONFIG;
+use crate::{MAIL_TIMEOUT, RECOVERY_LINK_VALIDITY};
+use chrono::{DateTime, Utc};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use std::time::Duration;
+
+
+/// sends the mails to the owners of the domains through the configured relay
+pub struct Mailer {
+	transport: Transport,
+	from: Mailbox,
+}
+
+
+enum Transport {
+	Smtp(AsyncSmtpTransport<Tokio1Executor>),
+	/// keeps the mails instead of sending them
+	#[cfg(test)]
+	Stub(lettre::transport::stub::AsyncStubTransport),
+}
+
+
+/// a mail to the owner of a domain
+pub struct Mail {
+	pub subject: String,
+	pub body: String,
+}
+
+
+impl Mailer {
+	pub fn new(conf: &Smtp) -> Result<Self, String> {
+		let from = conf.from.parse::<Mailbox>()
+			.map_err(|e| format!("invalid smtp sender {:?}: {}", conf.from, e))?;
+
+		let builder = match conf.security {
+			SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&conf.server),
+			SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&conf.server)
+				.map_err(|e| format!("invalid smtp server {}: {}", conf.server, e))?,
+			SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&conf.server)
+				.map_err(|e| format!("invalid smtp server {}: {}", conf.server, e))?,
+		};
+		let builder = match conf.port {
+			Some(port) => builder.port(port),
+			None => builder,
+		};
+		let builder = match (&conf.username, &conf.password) {
+			(Some(user), Some(password)) => builder.credentials(Credentials::new(user.clone(), password.clone())),
+			_ => builder,
+		};
+
+		Ok(Self {
+			transport: Transport::Smtp(builder.timeout(Some(Duration::from_secs(MAIL_TIMEOUT))).build()),
+			from,
+		})
+	}
+
+	/// a mailer that only keeps the mails in the returned stub
+	#[cfg(test)]
+	pub fn stub() -> (Self, lettre::transport::stub::AsyncStubTransport) {
+		let stub = lettre::transport::stub::AsyncStubTransport::new_ok();
+		let mailer = Self {
+			transport: Transport::Stub(stub.clone()),
+			from: "ffdyndns <ffdyndns@example.org>".parse().unwrap(),
+		};
+		(mailer, stub)
+	}
+
+	pub async fn send(&self, to: &str, mail: Mail) -> Result<(), String> {
+		let message = Message::builder()
+			.from(self.from.clone())
+			.to(Mailbox::new(None, parse_address(to)?))
+			.subject(mail.subject)
+			.header(ContentType::TEXT_PLAIN)
+			.body(mail.body)
+			.map_err(|e| e.to_string())?;
+
+		let sent = match &self.transport {
+			Transport::Smtp(t) => t.send(message).await.map(|_| ()).map_err(|e| e.to_string()),
+			#[cfg(test)]
+			Transport::Stub(t) => t.send(message).await.map(|_| ()).map_err(|e| e.to_string()),
+		};
+		sent.map_err(|e| format!("cannot send mail to {}: {}", to, e))?;
+		debug!("sent mail to {}", to);
+		Ok(())
+	}
+}
+
+
+/// checks a mail address given by a user
+pub fn parse_address(address: &str) -> Result<Address, String> {
+	address.trim().parse::<Address>().map_err(|_| format!("invalid mail address {:?}", address))
+}
+
+
+/// a link to a page of the web interface
+pub fn web_url(path: &str) -> String {
+	let base = CONFIG.server_web_url.trim_end_matches('/');
+	if base.contains("://") {
+		format!("{}{}", base, path)
+	} else {
+		format!("http://{}{}", base, path)
+	}
+}
+
+
+fn name(d: &Domain) -> &str {
+	d.domainname.trim_end_matches('.')
+}
+
+
+fn date(t: &DateTime<Utc>) -> String {
+	t.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+
+pub fn verification(d: &Domain, link: &str) -> Mail {
+	Mail {
+		subject: format!("Confirm your address for {}", name(d)),
+		body: format!(
+			"Hello,\n\n\
+			this address was given for the domain {} at {}.\n\
+			Open this link to get a reminder before the domain expires\n\
+			and to be able to set a new token if you lose it:\n\n\
+			{}\n\n\
+			If you did not register the domain, ignore this mail.\n",
+			name(d), CONFIG.name, link
+		),
+	}
+}
+
+
+pub fn recovery(d: &Domain, link: &str) -> Mail {
+	Mail {
+		subject: format!("New token for {}", name(d)),
+		body: format!(
+			"Hello,\n\n\
+			somebody asked for a new token for your domain {} at {}.\n\
+			Within {} minutes you can set it with this link:\n\n\
+			{}\n\n\
+			If it was not you, ignore this mail, the token stays as it is.\n",
+			name(d), CONFIG.name, RECOVERY_LINK_VALIDITY, link
+		),
+	}
+}
+
+
+pub fn reminder(d: &Domain, quarantine_until: &DateTime<Utc>) -> Mail {
+	Mail {
+		subject: format!("{} expires on {}", name(d), d.valid_until.format("%Y-%m-%d")),
+		body: format!(
+			"Hello,\n\n\
+			your domain {} at {} expires on {}, its records are removed then.\n\
+			Until {} you can reclaim it with its token on\n\n\
+			{}\n\n\
+			afterwards it is deleted and anybody can register the name.\n",
+			name(d), CONFIG.name, date(&d.valid_until), date(quarantine_until), web_url("/reclaim")
+		),
+	}
+}
+
+
+pub fn expired(d: &Domain, quarantine_until: &DateTime<Utc>) -> Mail {
+	Mail {
+		subject: format!("{} expired", name(d)),
+		body: format!(
+			"Hello,\n\n\
+			your domain {} at {} expired and its records were removed.\n\
+			Until {} you can reclaim it with its token on\n\n\
+			{}\n\n\
+			afterwards it is deleted.\n",
+			name(d), CONFIG.name, date(quarantine_until), web_url("/reclaim")
+		),
+	}
+}
+
+
+pub fn removed(d: &Domain) -> Mail {
+	Mail {
+		subject: format!("{} was deleted", name(d)),
+		body: format!(
+			"Hello,\n\n\
+			your domain {} at {} expired on {} and was deleted.\n\
+			Anybody can register the name again now.\n",
+			name(d), CONFIG.name, date(&d.valid_until)
+		),
+	}
+}
@@ -1,10 +1,13 @@
-#![feature(proc_macro_hygiene, decl_macro, exit_status_error)]
+#![feature(proc_macro_hygiene, decl_macro)]
 
+mod admin;
 mod config;
 mod db;
+mod dns;
 mod domain;
 mod web;
 mod ffdyndns;
+mod mail;
 mod nsupdate;
 
 #[allow(unused_imports)]
@@ -12,14 +15,15 @@ use log::{debug, error, info, trace};
 use clap::{self, App, Arg, ArgMatches};
 use config::Config;
 use crate::db::Database;
+use crate::dns::dnssec::KeyStore;
 use lazy_static::lazy_static;
 use pretty_env_logger;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::path;
 use std::process;
 use std::process::exit;
-use std::thread;
+use std::sync::Arc;
 use std::time;
 use tokio;
 use toml;
@@ -36,11 +40,66 @@ pub const WEB_TEMPLATES_DIR: &str = "/usr/lib/ffdyndns/templates";
 pub const DNSTTL: usize = 60;
 pub const NSUPDATE_BIN: &str = "/usr/bin/nsupdate";
 pub const NSUPDATE_TIMEOUT: u32 = 3;
+/// time in milliseconds the updater waits for more messages
+/// before sending them as one batch
+pub const NSUPDATE_BATCH_WINDOW: u64 = 500;
+/// maximum size of a single UPDATE message in bytes.
+/// nsupdate switches to TCP for messages that do not fit into UDP.
+pub const NSUPDATE_MAX_MESSAGE_SIZE: usize = 65535;
+/// number of update messages that can be queued before
+/// senders have to wait for the updater
+pub const NSUPDATE_QUEUE_SIZE: usize = 1024;
 pub const CLEAN_INTERVAL: u64 = 30;
+/// maximum size of DNS responses over UDP
+pub const DNS_UDP_SIZE: usize = 512;
+/// maximum size of DNS responses over UDP for clients supporting EDNS
+pub const DNS_EDNS_UDP_SIZE: usize = 1232;
+/// seconds an idle DNS TCP connection is kept open
+pub const DNS_TCP_TIMEOUT: u64 = 10;
+/// at most this many DNS updates over UDP are handled at a time
+pub const MAX_UDP_UPDATES: usize = 64;
+/// size in bytes after which a zone transfer starts a new message
+pub const DNS_TRANSFER_MESSAGE_SIZE: usize = 16384;
+/// number of times a NOTIFY is sent to a secondary that does not answer
+pub const NOTIFY_RETRIES: u32 = 3;
+/// seconds to wait for a secondary to acknowledge a NOTIFY
+pub const NOTIFY_TIMEOUT: u64 = 2;
+/// seconds an api request waits for the result of a DNS update
+pub const UPDATE_WAIT_TIMEOUT: u64 = 10;
+/// maximum number of TXT records a domain can have
+pub const MAX_TXT_RECORDS: usize = 10;
+/// number of changes shown in the history of a domain
+pub const HISTORY_LIMIT: usize = 100;
+/// domains on a page of the admin API, unless the request asks for another number
+pub const ADMIN_API_PAGE_SIZE: usize = 100;
+/// maximum number of domains on a page of the admin API
+pub const ADMIN_API_MAX_PAGE_SIZE: usize = 1000;
+/// seconds to wait for the SMTP relay
+pub const MAIL_TIMEOUT: u64 = 30;
+/// minutes the link to set a new token is valid
+pub const RECOVERY_LINK_VALIDITY: i64 = 60;
+/// minutes between two recovery mails for the same domain or to the same address
+pub const RECOVERY_MAIL_INTERVAL: i64 = 15;
+/// minutes between two verification mails for the same domain or to the same address
+pub const VERIFICATION_MAIL_INTERVAL: i64 = 15;
+/// at most this many passwords are hashed or checked at a time, scrypt takes 32 MiB each
+pub const MAX_PASSWORD_CHECKS: usize = 4;
+/// seconds a name or address has to wait after a failed login
+pub const LOGIN_FAILURE_DELAY: i64 = 3;
+/// days a new zone signing key is published before it is used
+pub const DNSSEC_PREPUBLISH: i64 = 1;
+/// seconds between key rollover checks and re-signing of the signed zones
+pub const DNSSEC_RESIGN_INTERVAL: u64 = 86400;
 
 
 lazy_static! {
 	pub static ref CONFIG: Config = {
+		// the tests use the example config
+		if cfg!(test) {
+			return toml::from_str(include_str!("../assets/ffdyndns.toml"))
+				.expect("invalid example config");
+		}
+
 		let file = CONFIG_DIRS
 			.iter()
 			.map(|x| path::Path::new(x))
@@ -91,25 +150,63 @@ fn main() {
 		.subcommand(App::new("server"))
 		.subcommand(App::new("genzones")
 			.arg(Arg::with_name("out")
-				.required(true)
 				.long("out")
 				.takes_value(true)
 				.default_value("./"))
-			.arg(Arg::with_name("rname")
-				.takes_value(true)
-				.long("rname")
+			.arg(Arg::with_name("stdout")
+				.long("stdout"))
+			.arg(Arg::with_name("domain")
+				.multiple(true)))
+		.subcommand(App::new("ds")
+			.arg(Arg::with_name("domain")
+				.multiple(true)))
+		.subcommand(App::new("migrate-sled")
+			.about("copies the sled database of older versions into the database")
+			.arg(Arg::with_name("dir")
+				.required(true)))
+		.subcommand(App::new("export")
+			.about("writes all domains to a file, or stdout")
+			.arg(Arg::with_name("file")))
+		.subcommand(App::new("import")
+			.about("reads domains written by export, - reads stdin")
+			.arg(Arg::with_name("file")
 				.required(true))
-			.arg(Arg::with_name("ns")
-				.multiple(true)
-				.min_values(1)
+			.arg(Arg::with_name("conflict")
+				.long("conflict")
+				.help("what to do with domains that already exist")
 				.takes_value(true)
-				.long("ns")
-				.required(true)));
+				.possible_values(&["skip", "overwrite", "fail"])
+				.default_value("fail"))
+			.arg(Arg::with_name("dry-run")
+				.long("dry-run")
+				.help("only print what would be imported")))
+		.subcommand(App::new("backup")
+			.about("writes a snapshot of the database, also while the server is running")
+			.arg(Arg::with_name("dir")
+				.help("default is the directory of the backup config")))
+		.subcommand(App::new("restore")
+			.about("replaces the database with a snapshot, the server must be stopped")
+			.arg(Arg::with_name("snapshot")
+				.required(true))
+			.arg(Arg::with_name("check")
+				.long("check")
+				.help("only check the snapshot")))
+		.subcommand(admin::domain_command())
+		.subcommand(App::new("hash-password")
+			.about("reads a password from stdin and prints the hash for the admin config"));
 
 
 	match app.get_matches().subcommand() {
 		("server", Some(args)) => cmd_server(args),
 		("genzones", Some(args)) => cmd_genzones(args),
+		("ds", Some(args)) => cmd_ds(args),
+		("migrate-sled", Some(args)) => cmd_migrate_sled(args),
+		("export", Some(args)) => cmd_export(args),
+		("import", Some(args)) => cmd_import(args),
+		("backup", Some(args)) => cmd_backup(args),
+		("restore", Some(args)) => cmd_restore(args),
+		("domain", Some(args)) => cmd_domain(args),
+		("hash-password", Some(_)) => cmd_hash_password(),
 		_ => {
 			error!("try --help");
 			process::exit(1)
@@ -119,52 +216,308 @@ fn main() {
 	process::exit(0);
 }
 
+/// the domains given on the command line, or all
+fn selected_domains(args: &ArgMatches<'_>) -> Vec<&'static config::Domain> {
+	match args.values_of("domain") {
+		None => CONFIG.domain.iter().collect(),
+		Some(names) => names
+			.map(|n| {
+				CONFIG.domain.iter().find(|d| dns::message::name_eq(&d.name, n)).unwrap_or_else(|| {
+					error!("{} is not a configured domain", n);
+					exit(1);
+				})
+			})
+			.collect(),
+	}
+}
+
+
+/// writes the SOA and NS records of the configured domains,
+/// everything else is added by the dynamic updates. The serial is the
+/// one after the stored serial of the zone. It is only read, the stored
+/// serial belongs to the backend that serves the zone.
 pub fn cmd_genzones(args: &ArgMatches<'_>) {
 	let outdir = args.value_of("out").unwrap();
-	let rname = args.value_of("rname").unwrap();
-	let serial = time::SystemTime::now().duration_since(time::UNIX_EPOCH)
-		.unwrap()
-		.as_secs();
-
-	for domain in &CONFIG.domain {
-		let mut outstr = vec![];
-		outstr.push(format!("$ORIGIN ."));
-		outstr.push(format!("$TTL 30 ; 30 seconds"));
-		outstr.push(format!("{} IN SOA  {} {} (", domain.name, args.values_of("ns").unwrap().next().unwrap() , rname));
-		outstr.push(format!("	{} ; serial", serial));
-		outstr.push(format!("	3600 ; refresh (1 hour)"));
-		outstr.push(format!("	600 ; retry (10 minutes)"));
-		outstr.push(format!("	2600 ; expire (43 minutes 20 seconds)"));
-		outstr.push(format!("	30 ; minimum (30 seconds)"));
-		outstr.push(format!(")"));
-		for ns in args.values_of("ns").unwrap() {
-			outstr.push(format!("NS {}", ns));
-		}
-		outstr.push(format!(""));
-
-		let out = outstr.join("\n");
-
-		let mut outfile = fs::File::create(path::Path::new(&format!("{}/{}.zone", outdir, &domain.name))).unwrap();
+	let db = open_database();
+
+	for domain in selected_domains(args) {
+		if domain.nameservers.is_empty() {
+			error!("no nameservers configured for {}", domain.name);
+			exit(1);
+		}
+
+		let serial = db.get_serial(domain).map(|s| domain.soa.serial.next(s)).unwrap_or_else(|e| {
+			error!("cannot get the serial of {}: {}", domain.name, e);
+			exit(1);
+		});
+		let out = dns::zone::master_file(domain, &dns::zone::apex_records(domain, serial));
+
+		if args.is_present("stdout") {
+			print!("{}", out);
+			continue;
+		}
+
+		let path = format!("{}/{}.zone", outdir, &domain.name);
+		let mut outfile = fs::File::create(&path).unwrap_or_else(|e| {
+			error!("cannot create {}: {}", path, e);
+			exit(1);
+		});
 		outfile.write_all(out.as_bytes()).unwrap();
 	}
 }
 
 
+/// prints the DS records of the signed domains for the parent zone.
+/// Generates the keys if the domain has none yet.
+pub fn cmd_ds(args: &ArgMatches<'_>) {
+	let keys = KeyStore::new(&CONFIG.key_directory);
+
+	for domain in selected_domains(args) {
+		if domain.dnssec.is_none() {
+			if args.values_of("domain").is_some() {
+				error!("{} is not signed", domain.name);
+				exit(1);
+			}
+			continue;
+		}
+
+		let domain_keys = keys.get(domain).unwrap_or_else(|e| {
+			error!("{}", e);
+			exit(1);
+		});
+		let now = chrono::Utc::now();
+		for key in domain_keys.iter().filter(|k| k.ksk && k.is_published(now)) {
+			match key.ds(&domain.name) {
+				Ok(ds) => println!("{}", ds),
+				Err(e) => {
+					error!("{}", e);
+					exit(1);
+				}
+			}
+		}
+	}
+}
+
+
+/// opens the configured database. Older versions used a
+/// sled directory, it has to be migrated first.
+fn open_database() -> Database {
+	if path::Path::new(&CONFIG.database).is_dir() {
+		error!("{} is a sled database of an older version", CONFIG.database);
+		error!("set `database` to a file and run `ffdyndns migrate-sled {}`", CONFIG.database);
+		exit(1);
+	}
+
+	Database::open(&CONFIG.database).unwrap_or_else(|e| {
+		error!("{}", e);
+		exit(1);
+	})
+}
+
+
+/// imports a sled database into the configured database
+pub fn cmd_migrate_sled(args: &ArgMatches<'_>) {
+	let dir = path::Path::new(args.value_of("dir").unwrap());
+	let db = open_database();
+
+	match db::import_sled(dir, &db) {
+		Ok(n) => info!("imported {} domains from {}", n, dir.display()),
+		Err(e) => {
+			error!("{}", e);
+			exit(1);
+		}
+	}
+}
+
+
+/// writes the domains in the export format
+pub fn cmd_export(args: &ArgMatches<'_>) {
+	let db = open_database();
+
+	let out: Box<dyn Write> = match args.value_of("file") {
+		None | Some("-") => Box::new(std::io::stdout()),
+		Some(f) => Box::new(fs::File::create(f).unwrap_or_else(|e| {
+			error!("cannot create {}: {}", f, e);
+			exit(1);
+		})),
+	};
+
+	match db::export::export(&db, std::io::BufWriter::new(out)) {
+		Ok(n) => info!("exported {} domains", n),
+		Err(e) => {
+			error!("{}", e);
+			exit(1);
+		}
+	}
+}
+
+
+/// reads an export into the database. The server should not be
+/// running, the records of the imported domains are published on its start.
+pub fn cmd_import(args: &ArgMatches<'_>) {
+	let db = open_database();
+	let conflict = args.value_of("conflict").unwrap().parse().unwrap();
+	let dry_run = args.is_present("dry-run");
+
+	let input: Box<dyn BufRead> = match args.value_of("file").unwrap() {
+		"-" => Box::new(std::io::BufReader::new(std::io::stdin())),
+		f => Box::new(std::io::BufReader::new(fs::File::open(f).unwrap_or_else(|e| {
+			error!("cannot open {}: {}", f, e);
+			exit(1);
+		}))),
+	};
+
+	let report = db::export::import(&db, input, conflict, dry_run).unwrap_or_else(|e| {
+		error!("{}", e);
+		exit(1);
+	});
+
+	if dry_run {
+		report.added.iter().for_each(|d| println!("add {}", d));
+		report.overwritten.iter().for_each(|d| println!("overwrite {}", d));
+		report.skipped.iter().for_each(|d| println!("skip {}", d));
+	}
+	println!(
+		"{} added, {} overwritten, {} skipped{}",
+		report.added.len(), report.overwritten.len(), report.skipped.len(),
+		if dry_run { " (dry run, nothing was written)" } else { "" }
+	);
+}
+
+
+/// writes a snapshot like the scheduled backups do
+pub fn cmd_backup(args: &ArgMatches<'_>) {
+	let dir = match (args.value_of("dir"), &CONFIG.backup) {
+		(Some(d), _) => d,
+		(None, Some(conf)) => &conf.directory,
+		(None, None) => {
+			error!("no backup directory given or configured");
+			exit(1);
+		}
+	};
+	let keep = CONFIG.backup.as_ref().map_or(usize::MAX, |c| c.keep);
+	let db = open_database();
+
+	match db::backup::backup(&db, path::Path::new(dir), keep) {
+		Ok(p) => println!("{}", p.display()),
+		Err(e) => {
+			error!("{}", e);
+			exit(1);
+		}
+	}
+}
+
+
+/// checks a snapshot and replaces the database with it
+pub fn cmd_restore(args: &ArgMatches<'_>) {
+	let snapshot = path::Path::new(args.value_of("snapshot").unwrap());
+
+	let r = if args.is_present("check") {
+		db::backup::check(snapshot)
+	} else {
+		db::backup::restore(snapshot, path::Path::new(&CONFIG.database))
+	};
+
+	match r {
+		Ok(n) => println!("{}: {} domains", snapshot.display(), n),
+		Err(e) => {
+			error!("{}", e);
+			exit(1);
+		}
+	}
+}
+
+
+/// the admin commands, they go through the service like the requests of the users
+pub fn cmd_domain(args: &ArgMatches<'_>) {
+	let rt = tokio::runtime::Runtime::new().unwrap();
+	let db = open_database();
+	let keys = Arc::new(KeyStore::new(&CONFIG.key_directory));
+
+	rt.block_on(async {
+		let service = ffdyndns::Service::for_command(db, keys);
+		admin::cmd_domain(&service, args).await;
+	});
+}
+
+
+/// the password of an admin, only the hash goes into the config
+pub fn cmd_hash_password() {
+	let mut password = String::new();
+	std::io::stdin().read_line(&mut password).unwrap_or_else(|e| {
+		error!("cannot read the password: {}", e);
+		exit(1);
+	});
+
+	let password = password.trim_end_matches(&['\r', '\n'][..]);
+	if password.is_empty() {
+		error!("the password is empty");
+		exit(1);
+	}
+
+	println!("{}", web::admin::hash_password(password));
+}
+
+
 pub fn cmd_server(_: &ArgMatches<'_>) {
 	let rt = tokio::runtime::Runtime::new().unwrap();
-	let db = db::Database::new(CONFIG.database.clone().into());
+	let db = open_database();
+	let keys = Arc::new(KeyStore::new(&CONFIG.key_directory));
 
-	let app = ffdyndns::Service::new(db);
+	rt.block_on(async {
+		if let config::Backend::Builtin(conf) = &CONFIG.backend {
+			if let Err(e) = dns::serve(db.clone(), keys.clone(), conf.clone()).await {
+				error!("{}", e);
+				exit(1);
+			}
+		}
 
-	let app_cleaner = app.clone();
+		if let Some(conf) = &CONFIG.backup {
+			tokio::spawn(run_backups(db.clone(), conf));
+		}
+
+		let app = ffdyndns::Service::new(db, keys).unwrap_or_else(|e| {
+			error!("{}", e);
+			exit(1);
+		});
 
-	// start cleaning thread
-	std::thread::spawn(move || {
-		loop {
-			thread::sleep(time::Duration::from_secs(CLEAN_INTERVAL));
-			app_cleaner.clean_domains();
+		if let Some(listen) = CONFIG.update_listen {
+			if let Err(e) = dns::update::serve(app.clone(), listen).await {
+				error!("{}", e);
+				exit(1);
+			}
+		}
+
+		let app_cleaner = app.clone();
+
+		// start cleaning task
+		tokio::spawn(async move {
+			loop {
+				tokio::time::sleep(time::Duration::from_secs(CLEAN_INTERVAL)).await;
+				app_cleaner.clean_domains().await;
+			}
+		});
+
+		if let Err(e) = web::start_web(app).await {
+			error!("{}", e);
+			exit(1);
 		}
 	});
+}
+
+
+/// takes a snapshot every `interval` hours. Runs forever.
+async fn run_backups(db: Database, conf: &'static config::Backup) {
+	let dir = path::Path::new(&conf.directory);
 
-	rt.block_on(web::start_web(app));
+	loop {
+		tokio::time::sleep(time::Duration::from_secs(conf.interval.max(1) * 3600)).await;
+
+		let db = db.clone();
+		match tokio::task::spawn_blocking(move || db::backup::backup(&db, dir, conf.keep)).await {
+			Ok(Ok(p)) => info!("wrote database snapshot {}", p.display()),
+			Ok(Err(e)) => error!("backup failed: {}", e),
+			Err(e) => error!("backup failed: {}", e),
+		}
+	}
 }
@@ -0,0 +1,17 @@
+-- every change of a domain, removed after `history_days`
+CREATE TABLE history (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	domainname TEXT NOT NULL COLLATE NOCASE,
+	time TEXT NOT NULL,
+	-- address the change came from, NULL for changes by ffdyndns itself
+	source TEXT,
+	-- start of the sha256 of the token that was used
+	token TEXT,
+	old_records TEXT NOT NULL,
+	new_records TEXT NOT NULL,
+	-- pending, ok or failed
+	status TEXT NOT NULL,
+	error TEXT
+);
+CREATE INDEX history_domain ON history (domainname, id);
+CREATE INDEX history_time ON history (time);
@@ -0,0 +1,2 @@
+-- locked domains cannot be changed with their token
+ALTER TABLE domains ADD COLUMN locked INTEGER NOT NULL DEFAULT 0;
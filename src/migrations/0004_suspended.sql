@@ -0,0 +1,3 @@
+-- suspended domains have no records, cannot be updated and do not expire
+ALTER TABLE domains ADD COLUMN suspended_reason TEXT;
+ALTER TABLE domains ADD COLUMN suspended_since TEXT;
@@ -0,0 +1,4 @@
+-- the owner's address for the expiry reminders
+ALTER TABLE domains ADD COLUMN email TEXT;
+ALTER TABLE domains ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE domains ADD COLUMN email_reminded TEXT;
@@ -0,0 +1,10 @@
+-- user accounts that own domains
+CREATE TABLE accounts (
+	name TEXT PRIMARY KEY COLLATE NOCASE,
+	-- scrypt hash of the password
+	password TEXT NOT NULL,
+	created TEXT NOT NULL
+);
+-- domains of a removed account are left without owner
+ALTER TABLE domains ADD COLUMN owner TEXT COLLATE NOCASE REFERENCES accounts(name) ON DELETE SET NULL;
+CREATE INDEX domains_owner ON domains (owner);
@@ -1,62 +1,305 @@
 pub mod nsupdate;
+pub mod powerdns;
+pub mod zonefile;
 
+use crate::config;
+use crate::db::Database;
+use crate::dns::{self, dnssec::KeyStore};
+use crate::CONFIG;
 use crate::{
+	DNSSEC_RESIGN_INTERVAL,
 	NSUPDATE_BIN,
-	NSUPDATE_TIMEOUT
+	NSUPDATE_TIMEOUT,
+	NSUPDATE_BATCH_WINDOW,
+	NSUPDATE_QUEUE_SIZE,
 };
-use std::process::Command;
-use std::process::Stdio;
-use std::io::Write;
 use nsupdate::UpdateMessage;
 #[allow(unused_imports)]
 use log::{error, warn, info, debug};
-use std::io::Read;
-use std::thread;
-use std::sync::mpsc::{self, Sender};
+use chrono::Utc;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+
+/// outcome of a DNS update, the error contains the message of the DNS server
+pub type UpdateResult = Result<(), String>;
+
+
+/// a way to get updates into the DNS server
+#[rocket::async_trait]
+pub trait Backend: Send + Sync {
+	/// called once before the first update
+	async fn start(&self) -> UpdateResult {
+		Ok(())
+	}
+
+	/// applies all commands of the message at once.
+	/// All commands of a message belong to the same zone.
+	async fn update(&self, msg: UpdateMessage) -> UpdateResult;
+
+	/// renews the signatures of a signed zone before they expire
+	async fn resign(&self, _zone: &config::Domain) -> UpdateResult {
+		Ok(())
+	}
+}
+
+
+/// sends RFC 2136 updates through the nsupdate binary
+pub struct Nsupdate;
+
+#[rocket::async_trait]
+impl Backend for Nsupdate {
+	async fn update(&self, msg: UpdateMessage) -> UpdateResult {
+		run_nsupdate(msg).await
+	}
+}
+
+
+pub fn new_backend(conf: &config::Backend, db: Database, keys: Arc<KeyStore>) -> Arc<dyn Backend> {
+	match conf {
+		config::Backend::Nsupdate => Arc::new(Nsupdate),
+		config::Backend::PowerDns { url, api_key, server_id } => {
+			Arc::new(powerdns::PowerDns::new(url.clone(), api_key.clone(), server_id.clone()))
+		}
+		config::Backend::Builtin(conf) => Arc::new(dns::Builtin::new(db, CONFIG.domain.clone(), conf)),
+		config::Backend::ZoneFile(conf) => Arc::new(zonefile::ZoneFile::new(db, keys, CONFIG.domain.clone(), conf)),
+	}
+}
+
+
+/// a queued update message together with the channel its result is reported to
+struct UpdateJob {
+	msg: UpdateMessage,
+	done: oneshot::Sender<UpdateResult>,
+}
+
+
+/// handle to the updater task
+///
+/// The queue is bounded, so sending waits when the updater is lagging behind.
+#[derive(Clone)]
+pub struct Updater {
+	tx: mpsc::Sender<UpdateJob>,
+}
+
+impl Updater {
+	pub async fn send(&self, msg: UpdateMessage) -> UpdateHandle {
+		let (done, rx) = oneshot::channel();
+
+		if self.tx.send(UpdateJob { msg, done }).await.is_err() {
+			error!("nsupdater is not running, dropping update");
+		}
+
+		UpdateHandle(rx)
+	}
+}
+
+
+/// can be awaited to get the result of a specific update.
+/// Dropping it does not cancel the update.
+pub struct UpdateHandle(oneshot::Receiver<UpdateResult>);
+
+impl UpdateHandle {
+	pub async fn wait(self) -> UpdateResult {
+		self.0.await.unwrap_or_else(|_| Err("update was dropped by the updater".to_string()))
+	}
+
+	/// calls `f` with the result once it is there, whether or
+	/// not somebody waits for it. Must be called from within the tokio runtime.
+	pub fn inspect<F>(self, f: F) -> Self
+	where
+		F: FnOnce(&UpdateResult) + Send + 'static,
+	{
+		let (tx, rx) = oneshot::channel();
+		tokio::spawn(async move {
+			let r = self.wait().await;
+			f(&r);
+			let _ = tx.send(r);
+		});
+
+		UpdateHandle(rx)
+	}
+}
+
+
+/// spawns the updater task for the configured backend, with `resign`
+/// also the task renewing the signatures. Must be called from within the tokio runtime.
+pub fn start_nsupdater(db: Database, keys: Arc<KeyStore>, resign: bool) -> Updater {
+	let (tx, rx) = mpsc::channel(NSUPDATE_QUEUE_SIZE);
 
+	let backend = new_backend(&CONFIG.backend, db, keys.clone());
+	// only zones served or exported by ffdyndns can be signed
+	if resign && matches!(CONFIG.backend, config::Backend::Builtin(_) | config::Backend::ZoneFile(_)) {
+		tokio::spawn(run_resigner(backend.clone(), keys, CONFIG.domain.clone()));
+	}
+	tokio::spawn(run_updater(backend, rx));
 
-pub fn start_nsupdater() -> Sender<UpdateMessage> {
-	let (tx, rx) = mpsc::channel();
+	Updater { tx }
+}
+
+
+/// spawns the updater task for a backend that is not from the config
+#[cfg(test)]
+pub fn start_updater(backend: Arc<dyn Backend>) -> Updater {
+	let (tx, rx) = mpsc::channel(NSUPDATE_QUEUE_SIZE);
+	tokio::spawn(run_updater(backend, rx));
+	Updater { tx }
+}
+
+
+/// rolls the keys of the signed zones over as scheduled and renews
+/// their signatures every `DNSSEC_RESIGN_INTERVAL` seconds
+async fn run_resigner(backend: Arc<dyn Backend>, keys: Arc<KeyStore>, zones: Vec<config::Domain>) {
+	let zones: Vec<config::Domain> = zones.into_iter().filter(|z| z.dnssec.is_some()).collect();
+	if zones.is_empty() {
+		return;
+	}
+
+	let mut interval = time::interval(Duration::from_secs(DNSSEC_RESIGN_INTERVAL));
+	loop {
+		interval.tick().await;
+
+		for zone in &zones {
+			if let Err(e) = keys.maintain(zone, Utc::now()) {
+				error!("cannot maintain the keys of {}: {}", zone.name, e);
+			}
+			if let Err(e) = backend.resign(zone).await {
+				error!("cannot sign {}: {}", zone.name, e);
+			}
+		}
+	}
+}
+
+
+async fn run_updater(backend: Arc<dyn Backend>, mut rx: mpsc::Receiver<UpdateJob>) {
+	if let Err(e) = backend.start().await {
+		error!("cannot start dns backend: {}", e);
+	}
 
-	thread::spawn(move || {
-		for req in rx {
-			if let Err(e) = run_nsupdate(req) {
-				error!("nsupdate failed: {}", e);
+	while let Some(pending) = collect_batch(&mut rx).await {
+		debug!("sending batch of {} update messages", pending.len());
+
+		// the messages are kept, a failed merged message is sent again
+		// one by one so only the rejected ones fail
+		let pending = pending.into_iter().map(|j| (j.msg.clone(), (j.msg, j.done))).collect();
+		for (msg, waiting) in UpdateMessage::batch(pending) {
+			let r = backend.update(msg).await;
+			if let (Err(e), true) = (&r, waiting.len() > 1) {
+				warn!("dns update of {} merged messages failed, sending them one by one: {}", waiting.len(), e);
+				for (msg, done) in waiting {
+					send_result(done, backend.update(msg).await);
+				}
+				continue;
+			}
+
+			for (_, done) in waiting {
+				send_result(done, r.clone());
 			}
 		}
-	});
+	}
+}
 
-	tx
+
+fn send_result(done: oneshot::Sender<UpdateResult>, r: UpdateResult) {
+	if let Err(e) = &r {
+		error!("dns update failed: {}", e);
+	}
+	// the receiver may not be interested in the result
+	let _ = done.send(r);
 }
 
 
-pub fn run_nsupdate(msg: UpdateMessage) -> Result<(),String>{
+/// waits until a job arrives and then collects everything that
+/// comes in within `NSUPDATE_BATCH_WINDOW`.
+/// Returns `None` when all senders are gone.
+async fn collect_batch(rx: &mut mpsc::Receiver<UpdateJob>) -> Option<Vec<UpdateJob>> {
+	let mut pending = vec![rx.recv().await?];
+	let deadline = Instant::now() + Duration::from_millis(NSUPDATE_BATCH_WINDOW);
+
+	while let Ok(Some(job)) = time::timeout_at(deadline, rx.recv()).await {
+		pending.push(job);
+	}
+
+	Some(pending)
+}
+
+
+pub async fn run_nsupdate(msg: UpdateMessage) -> UpdateResult {
 	let mut child = Command::new(NSUPDATE_BIN)
 		.stdout(Stdio::null())
 		.stdin(Stdio::piped())
 		.stderr(Stdio::piped())
 		.args(["-t", NSUPDATE_TIMEOUT.to_string().as_str()])
 		.spawn()
-		.expect("cannot start pdns_server");
-
+		.map_err(|e| format!("cannot start nsupdate: {}", e))?;
 
 	let mut stdin = child.stdin.take().expect("stdin for nsupdate process is not available");
-	let mut stderr = child.stderr.take().expect("stderr for nsupdate process is not available");
 	let updatestring = msg.finalize();
 	debug!("sending to nsupdate: {}", updatestring);
 
-	let stdin_thread = thread::spawn(move || {
-		stdin.write_all(updatestring.as_bytes()).expect("cannot write to nsupdate process");
-		// finish input
-		stdin.flush().unwrap();
-		drop(stdin);
-	});
+	stdin.write_all(updatestring.as_bytes()).await
+		.map_err(|e| format!("cannot write to nsupdate process: {}", e))?;
+	// finish input
+	drop(stdin);
+
+	let output = child.wait_with_output().await
+		.map_err(|e| format!("nsupdate did not finish: {}", e))?;
+	info!("nsupdate returned {}", output.status);
+
+	if output.status.success() {
+		Ok(())
+	} else {
+		Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+	}
+}
+
+
+
+#[tokio::test]
+async fn rejected_names() {
+	use crate::db::Domain;
+	use std::sync::Mutex;
+
+	/// rejects every message with a command for `bad.ffhl.de.`
+	#[derive(Default)]
+	struct Picky(Mutex<usize>);
+
+	#[rocket::async_trait]
+	impl Backend for Picky {
+		async fn update(&self, msg: UpdateMessage) -> UpdateResult {
+			*self.0.lock().unwrap() += 1;
+			match msg.commands().iter().any(|c| c.domain() == "bad.ffhl.de.") {
+				true => Err("REFUSED".to_string()),
+				false => Ok(()),
+			}
+		}
+	}
+
+	let backend = Arc::new(Picky::default());
+	let updater = start_updater(backend.clone());
+	let send = |name: &str| {
+		let msg = UpdateMessage::from_domain(&Domain::new(name.to_string(), chrono::Duration::hours(1)));
+		updater.send(msg)
+	};
 
-	let mut error = String::new();
-	stderr.read_to_string(&mut error).unwrap();
+	// all three go into one merged message, only the bad one fails
+	let handles = vec![send("foo.ffhl.de.").await, send("bad.ffhl.de.").await, send("bar.ffhl.de.").await];
+	let mut results = Vec::new();
+	for h in handles {
+		results.push(h.wait().await);
+	}
+	assert_eq!(results, vec![Ok(()), Err("REFUSED".to_string()), Ok(())]);
+	assert_eq!(*backend.0.lock().unwrap(), 4);
 
-	let rcode = child.wait().unwrap();
-	info!("nsupdate returned {}", rcode);
-	stdin_thread.join().unwrap();
-	rcode.exit_ok().map_err(|_| {error})
+	// a good batch is sent once
+	let handles = vec![send("foo.ffhl.de.").await, send("bar.ffhl.de.").await];
+	for h in handles {
+		assert_eq!(h.wait().await, Ok(()));
+	}
+	assert_eq!(*backend.0.lock().unwrap(), 5);
 }
@@ -2,7 +2,10 @@ use std::net::IpAddr;
 use std::string::ToString;
 use crate::CONFIG;
 use crate::DNSTTL;
-use crate::ffdyndns::UpdateRequest;
+use crate::NSUPDATE_MAX_MESSAGE_SIZE;
+use crate::db::Domain;
+use crate::dns::message::quote_text;
+use crate::domain::Dname;
 
 
 // server 127.0.0.1
@@ -11,9 +14,11 @@ use crate::ffdyndns::UpdateRequest;
 // send
 
 
+#[derive(Clone)]
 pub enum UpdateCommand {
 	Delete(String),
 	Add(String, IpAddr),
+	AddTxt(String, String),
 }
 
 
@@ -26,16 +31,40 @@ impl UpdateCommand {
 		Self::Add(d.to_string(), a)
 	}
 
+	pub fn domain(&self) -> &String {
+		match self {
+			Self::Delete(d) => d,
+			Self::Add(d, _) => d,
+			Self::AddTxt(d, _) => d,
+		}
+	}
+
+	/// upper bound of the bytes this command takes up in the
+	/// update section of a DNS UPDATE message (no name compression)
+	fn wire_size(&self) -> usize {
+		// owner name + type, class, ttl and rdlength
+		let rr = name_wire_size(self.domain()) + 10;
+		match self {
+			Self::Delete(_) => rr,
+			Self::Add(_, IpAddr::V4(_)) => rr + 4,
+			Self::Add(_, IpAddr::V6(_)) => rr + 16,
+			// a length byte for every 255 bytes of text
+			Self::AddTxt(_, t) => rr + t.len() + (t.len() + 254) / 255,
+		}
+	}
+
 	fn finalize(self) -> String {
 		match self {
 			Self::Delete(s) => format!("update delete {}", s),
 			// update add a.dyn.example.com 60 A 123.23.123.1
 			Self::Add(d, a) => format!("update add {} {} {} {}", d, DNSTTL, addr_class(a), a),
+			Self::AddTxt(d, t) => format!("update add {} {} TXT {}", d, DNSTTL, quote_text(&t)),
 		}
 	}
 }
 
 
+#[derive(Clone)]
 pub struct UpdateMessage {
 	commands: Vec<UpdateCommand>,
 }
@@ -46,10 +75,19 @@ impl UpdateMessage {
 		Self { commands: Vec::new() }
 	}
 
-	pub fn from_updaterequest(ur: UpdateRequest) -> Self {
+	/// replaces all records of the domain with the ones it has now
+	pub fn from_domain(d: &Domain) -> Self {
 		let mut nsup = Self::new();
-		nsup.add_command(UpdateCommand::delete(&ur.domain));
-		nsup.add_command(UpdateCommand::add(&ur.domain, ur.addr));
+		nsup.add_command(UpdateCommand::delete(&d.domainname));
+		if let Some(a) = d.ipv4 {
+			nsup.add_command(UpdateCommand::add(&d.domainname, a.into()));
+		}
+		if let Some(a) = d.ipv6 {
+			nsup.add_command(UpdateCommand::add(&d.domainname, a.into()));
+		}
+		for t in &d.txt {
+			nsup.add_command(UpdateCommand::AddTxt(d.domainname.clone(), t.clone()));
+		}
 		nsup
 	}
 
@@ -63,10 +101,74 @@ impl UpdateMessage {
 		self.commands.push(cmd);
 	}
 
+	pub fn commands(&self) -> &[UpdateCommand] {
+		&self.commands
+	}
+
+	/// the zone the commands of this message belong to
+	///
+	/// Dynamic domains are always direct subdomains of a configured
+	/// suffix, so the zone is the domain with its first label removed.
+	pub fn zone(&self) -> Option<String> {
+		self.commands
+			.first()
+			.map(|c| Dname::new(c.domain().clone()).strip_subdomain())
+	}
+
+	/// upper bound of the wire size of this message
+	fn wire_size(&self) -> usize {
+		self.commands.iter().map(|c| c.wire_size()).sum()
+	}
+
+	/// merge a batch of messages into as few messages as possible
+	///
+	/// Messages are grouped by zone, keeping their order within the zone,
+	/// and a merged message is split whenever it would exceed
+	/// `NSUPDATE_MAX_MESSAGE_SIZE`. The commands of a single message are
+	/// never split up, so a delete and the following add stay atomic.
+	/// Every message carries a tag, the tags of all messages that went
+	/// into a merged message are returned alongside it.
+	pub fn batch<T>(msgs: Vec<(UpdateMessage, T)>) -> Vec<(UpdateMessage, Vec<T>)> {
+		let mut zones: Vec<(String, Vec<(UpdateMessage, Vec<T>)>)> = Vec::new();
+
+		for (msg, tag) in msgs {
+			let zone = match msg.zone() {
+				Some(z) => z,
+				None => continue,
+			};
+
+			let pos = match zones.iter().position(|(z, _)| z == &zone) {
+				Some(p) => p,
+				None => {
+					zones.push((zone.clone(), Vec::new()));
+					zones.len() - 1
+				}
+			};
+			let batches = &mut zones[pos].1;
+
+			let fits = batches.last().is_some_and(|(b, _)| {
+				update_header_size(&zone) + b.wire_size() + msg.wire_size() <= NSUPDATE_MAX_MESSAGE_SIZE
+			});
+
+			if fits {
+				let (b, tags) = batches.last_mut().unwrap();
+				b.commands.extend(msg.commands);
+				tags.push(tag);
+			} else {
+				batches.push((msg, vec![tag]));
+			}
+		}
+
+		zones.into_iter().flat_map(|(_, b)| b).collect()
+	}
+
 	pub fn finalize(self) -> String {
 		let mut out = Vec::new();
 
 		out.push(format!("server {}", CONFIG.dns_server));
+		if let Some(zone) = self.zone() {
+			out.push(format!("zone {}", zone));
+		}
 		for update in self.commands {
 			out.push(update.finalize());
 		}
@@ -79,9 +181,67 @@ impl UpdateMessage {
 
 
 
-fn addr_class(a: IpAddr) -> String {
+/// length of a fully qualified domain name in wire format
+fn name_wire_size(name: &str) -> usize {
+	let name = name.trim_end_matches('.');
+	if name.is_empty() {
+		1
+	} else {
+		name.len() + 2
+	}
+}
+
+
+/// size of the message header and the zone section
+fn update_header_size(zone: &str) -> usize {
+	12 + name_wire_size(zone) + 4
+}
+
+
+pub fn addr_class(a: IpAddr) -> String {
 	match a {
 		IpAddr::V4(_) => "A".to_string(),
 		IpAddr::V6(_) => "AAAA".to_string(),
 	}
 }
+
+
+
+#[test]
+fn batch_groups_by_zone() {
+	let mut domain = Domain::new("bar.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	domain.ipv4 = Some("10.0.0.1".parse().unwrap());
+	domain.txt = vec!["hello".to_string()];
+	let msgs = vec![
+		(UpdateMessage::new_remove_message("foo.ffhl.de.".to_string()), 1),
+		(UpdateMessage::new_remove_message("foo.ffdyn.net.".to_string()), 2),
+		(UpdateMessage::from_domain(&domain), 3),
+	];
+
+	let batches = UpdateMessage::batch(msgs);
+
+	assert_eq!(batches.len(), 2);
+	assert_eq!(batches[0].0.zone(), Some("ffhl.de.".to_string()));
+	assert_eq!(batches[0].0.commands.len(), 4);
+	assert_eq!(batches[0].0.commands[0].domain(), "foo.ffhl.de.");
+	assert_eq!(batches[0].1, vec![1, 3]);
+	assert_eq!(batches[1].0.zone(), Some("ffdyn.net.".to_string()));
+	assert_eq!(batches[1].0.commands.len(), 1);
+	assert_eq!(batches[1].1, vec![2]);
+}
+
+
+#[test]
+fn batch_respects_message_size() {
+	let msgs: Vec<(UpdateMessage, ())> = (0..5000)
+		.map(|i| (UpdateMessage::new_remove_message(format!("host{}.ffhl.de.", i)), ()))
+		.collect();
+
+	let batches = UpdateMessage::batch(msgs);
+
+	assert!(batches.len() > 1);
+	assert_eq!(batches.iter().map(|(b, _)| b.commands.len()).sum::<usize>(), 5000);
+	for (b, _) in batches {
+		assert!(update_header_size("ffhl.de.") + b.wire_size() <= NSUPDATE_MAX_MESSAGE_SIZE);
+	}
+}
@@ -0,0 +1,210 @@
+use super::{Backend, UpdateResult};
+use super::nsupdate::{addr_class, UpdateCommand, UpdateMessage};
+use crate::DNSTTL;
+use crate::dns::message::quote_text;
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use serde::Serialize;
+use serde_json as json;
+
+
+// PATCH /api/v1/servers/localhost/zones/dyn.example.com.
+// {"rrsets": [{"name": "a.dyn.example.com.", "type": "A", "ttl": 60,
+//   "changetype": "REPLACE", "records": [{"content": "123.23.123.1", "disabled": false}]}]}
+
+
+/// updates zones through the PowerDNS HTTP API
+pub struct PowerDns {
+	client: reqwest::Client,
+	url: String,
+	api_key: String,
+	server_id: String,
+}
+
+
+#[derive(Debug, Serialize)]
+struct RRSet {
+	name: String,
+	#[serde(rename = "type")]
+	rtype: String,
+	ttl: usize,
+	changetype: &'static str,
+	records: Vec<Record>,
+}
+
+
+#[derive(Debug, Serialize)]
+struct Record {
+	content: String,
+	disabled: bool,
+}
+
+
+impl PowerDns {
+	pub fn new(url: String, api_key: String, server_id: String) -> Self {
+		Self {
+			client: reqwest::Client::new(),
+			url: url.trim_end_matches('/').to_string(),
+			api_key,
+			server_id,
+		}
+	}
+
+	fn zone_url(&self, zone: &str) -> String {
+		format!("{}/api/v1/servers/{}/zones/{}", self.url, self.server_id, zone)
+	}
+}
+
+
+#[rocket::async_trait]
+impl Backend for PowerDns {
+	async fn update(&self, msg: UpdateMessage) -> UpdateResult {
+		let zone = match msg.zone() {
+			Some(z) => z,
+			None => return Ok(()),
+		};
+
+		let body = json::json!({ "rrsets": rrsets(&msg) });
+		debug!("sending to powerdns: {}", body);
+
+		let resp = self.client
+			.patch(self.zone_url(&zone))
+			.header("X-API-Key", &self.api_key)
+			.json(&body)
+			.send()
+			.await
+			.map_err(|e| format!("cannot reach powerdns api: {}", e))?;
+
+		let status = resp.status();
+		info!("powerdns returned {}", status);
+		if status.is_success() {
+			return Ok(());
+		}
+
+		// errors come as {"error": "..."}
+		let text = resp.text().await.unwrap_or_default();
+		let reason = json::from_str::<json::Value>(&text)
+			.ok()
+			.and_then(|v| v["error"].as_str().map(|s| s.to_string()))
+			.unwrap_or(text);
+
+		Err(format!("{}: {}", status, reason))
+	}
+}
+
+
+/// translates the commands of a message into the final state of every
+/// touched RRset.
+///
+/// A delete removes the A, AAAA and TXT RRsets of a name. Adds are collected into
+/// a replacement of the RRset, which is what the updater sends anyway, as
+/// every add is preceded by a delete of the name.
+fn rrsets(msg: &UpdateMessage) -> Vec<RRSet> {
+	let mut sets: Vec<RRSet> = Vec::new();
+
+	for cmd in msg.commands() {
+		match cmd {
+			UpdateCommand::Delete(name) => {
+				for rtype in &["A", "AAAA", "TXT"] {
+					let i = find(&mut sets, name, rtype.to_string());
+					sets[i].changetype = "DELETE";
+					sets[i].records.clear();
+				}
+			}
+			UpdateCommand::Add(name, addr) => {
+				let i = find(&mut sets, name, addr_class(*addr));
+				sets[i].changetype = "REPLACE";
+				sets[i].records.push(Record {
+					content: addr.to_string(),
+					disabled: false,
+				});
+			}
+			UpdateCommand::AddTxt(name, txt) => {
+				let i = find(&mut sets, name, "TXT".to_string());
+				sets[i].changetype = "REPLACE";
+				sets[i].records.push(Record {
+					content: quote_text(txt),
+					disabled: false,
+				});
+			}
+		}
+	}
+
+	sets
+}
+
+
+/// index of the RRset for name and type, creates it if necessary
+fn find(sets: &mut Vec<RRSet>, name: &String, rtype: String) -> usize {
+	match sets.iter().position(|s| &s.name == name && s.rtype == rtype) {
+		Some(p) => p,
+		None => {
+			sets.push(RRSet {
+				name: name.clone(),
+				rtype,
+				ttl: DNSTTL,
+				changetype: "DELETE",
+				records: Vec::new(),
+			});
+			sets.len() - 1
+		}
+	}
+}
+
+
+
+#[tokio::test]
+async fn powerdns_patch() {
+	use crate::db::Domain;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+
+	// minimal mock of the PowerDNS API, answers every request with 204
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	let mock = tokio::spawn(async move {
+		let (mut sock, _) = listener.accept().await.unwrap();
+		let mut req = Vec::new();
+		let mut buf = [0u8; 4096];
+		loop {
+			let n = sock.read(&mut buf).await.unwrap();
+			req.extend_from_slice(&buf[..n]);
+			let text = String::from_utf8_lossy(&req).to_string();
+			if let Some(end) = text.find("\r\n\r\n") {
+				let len = text.lines()
+					.find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+					.unwrap_or(0);
+				if req.len() >= end + 4 + len {
+					break;
+				}
+			}
+		}
+		sock.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+		String::from_utf8(req).unwrap()
+	});
+
+	let pdns = PowerDns::new(format!("http://{}/", addr), "secret".to_string(), "localhost".to_string());
+	let mut domain = Domain::new("foo.ffhl.de.".to_string(), chrono::Duration::hours(1));
+	domain.ipv4 = Some("10.0.0.1".parse().unwrap());
+	domain.txt = vec!["say \"hi\"".to_string()];
+	let msg = UpdateMessage::from_domain(&domain);
+
+	assert_eq!(pdns.update(msg).await, Ok(()));
+
+	let req = mock.await.unwrap();
+	let (head, body) = req.split_at(req.find("\r\n\r\n").unwrap());
+	assert!(head.starts_with("PATCH /api/v1/servers/localhost/zones/ffhl.de. HTTP/1.1"));
+	assert!(head.to_lowercase().contains("x-api-key: secret"));
+
+	let body: json::Value = json::from_str(body.trim()).unwrap();
+	let sets = body["rrsets"].as_array().unwrap();
+	assert_eq!(sets.len(), 3);
+	assert_eq!(sets[0]["type"], "A");
+	assert_eq!(sets[0]["changetype"], "REPLACE");
+	assert_eq!(sets[0]["records"][0]["content"], "10.0.0.1");
+	assert_eq!(sets[1]["type"], "AAAA");
+	assert_eq!(sets[1]["changetype"], "DELETE");
+	assert_eq!(sets[2]["type"], "TXT");
+	assert_eq!(sets[2]["changetype"], "REPLACE");
+	assert_eq!(sets[2]["records"][0]["content"], "\"say \\\"hi\\\"\"");
+}
@@ -0,0 +1,161 @@
+use super::{Backend, UpdateResult};
+use super::nsupdate::UpdateMessage;
+use crate::config;
+use crate::db::Database;
+use crate::dns::{dnssec::KeyStore, zone};
+#[allow(unused_imports)]
+use log::{error, warn, info, debug};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+
+/// writes a complete zone file for every change
+///
+/// For servers that cannot take dynamic updates. The records are
+/// read from the database, so the update commands only tell which
+/// zone has to be written again.
+pub struct ZoneFile {
+	db: Database,
+	keys: Arc<KeyStore>,
+	zones: Vec<config::Domain>,
+	directory: PathBuf,
+	reload_command: Option<String>,
+	/// updates and re-signing must not write the same file at once
+	writing: Mutex<()>,
+}
+
+impl ZoneFile {
+	pub fn new(db: Database, keys: Arc<KeyStore>, zones: Vec<config::Domain>, conf: &config::ZoneFiles) -> Self {
+		Self {
+			db,
+			keys,
+			zones,
+			directory: conf.directory.clone().into(),
+			reload_command: conf.reload_command.clone(),
+			writing: Mutex::new(()),
+		}
+	}
+
+	/// path of the zone file of a domain
+	pub fn path(&self, zone: &config::Domain) -> PathBuf {
+		self.directory.join(format!("{}.zone", zone.name.trim_end_matches('.')))
+	}
+
+	/// writes the zone with a new serial and reloads the server
+	async fn write_zone(&self, zone: &config::Domain) -> UpdateResult {
+		let _writing = self.writing.lock().await;
+		let serial = self.db.bump_serial(zone)?;
+		let path = self.path(zone);
+
+		write_atomic(&path, &zone::zone_file(&self.db, &self.keys, zone, serial)?).await
+			.map_err(|e| format!("cannot write zone file {}: {}", path.display(), e))?;
+		info!("wrote zone file {} with serial {}", path.display(), serial);
+
+		match &self.reload_command {
+			Some(cmd) => run_reload(cmd, zone).await,
+			None => Ok(()),
+		}
+	}
+}
+
+#[rocket::async_trait]
+impl Backend for ZoneFile {
+	/// the zone files are written once at startup, so they exist
+	/// before the first update
+	async fn start(&self) -> UpdateResult {
+		for zone in &self.zones {
+			self.write_zone(zone).await?;
+		}
+		Ok(())
+	}
+
+	async fn update(&self, msg: UpdateMessage) -> UpdateResult {
+		let name = match msg.zone() {
+			Some(z) => z,
+			None => return Ok(()),
+		};
+
+		match zone::find_zone(&self.zones, &name) {
+			Some(zone) => self.write_zone(zone).await,
+			None => Err(format!("{} is not a configured domain", name)),
+		}
+	}
+
+	async fn resign(&self, zone: &config::Domain) -> UpdateResult {
+		self.write_zone(zone).await
+	}
+}
+
+
+/// writes to a temporary file first and renames it, so the
+/// DNS server never reads a half written zone
+async fn write_atomic(path: &Path, content: &str) -> std::io::Result<()> {
+	let tmp = path.with_extension("zone.tmp");
+	fs::write(&tmp, content).await?;
+	fs::rename(&tmp, path).await
+}
+
+
+async fn run_reload(cmd: &str, zone: &config::Domain) -> UpdateResult {
+	let cmd = cmd.replace("{zone}", zone.name.trim_end_matches('.'));
+	debug!("running {}", cmd);
+
+	let output = Command::new("sh")
+		.args(["-c", &cmd])
+		.output()
+		.await
+		.map_err(|e| format!("cannot run reload command: {}", e))?;
+
+	if output.status.success() {
+		Ok(())
+	} else {
+		Err(format!("reload command failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+	}
+}
+
+
+
+#[tokio::test]
+async fn write_zone_files() {
+	use crate::db;
+	use super::nsupdate::UpdateCommand;
+
+	let zones: Vec<config::Domain> = vec![toml::from_str(r#"
+		name = "ffhl.de."
+		description = "test"
+		allowed_ips = []
+		validity = 1
+		nameservers = ["ns1.ffhl.de."]
+	"#).unwrap()];
+
+	let (tmp, db) = db::test_database(&["foo.ffhl.de."], chrono::Duration::hours(1));
+	let dir = tmp.path();
+	let keys = Arc::new(KeyStore::new(dir.join("keys")));
+
+	let backend = ZoneFile::new(db.clone(), keys, zones.clone(), &config::ZoneFiles {
+		directory: dir.to_str().unwrap().to_string(),
+		reload_command: Some(format!("echo {{zone}} >> {}/reloads", dir.display())),
+	});
+	backend.start().await.unwrap();
+
+	db.modify_domain("foo.ffhl.de.", |d| {
+		d.ipv4 = Some("10.0.0.1".parse().unwrap());
+		Ok::<_, db::Error>(())
+	}).unwrap();
+
+	let mut msg = UpdateMessage::new();
+	msg.add_command(UpdateCommand::add(&"foo.ffhl.de.".to_string(), "10.0.0.1".parse().unwrap()));
+	backend.update(msg).await.unwrap();
+
+	let serial = db.get_serial(&zones[0]).unwrap();
+	let content = std::fs::read_to_string(dir.join("ffhl.de.zone")).unwrap();
+	assert!(content.starts_with("$ORIGIN .\n$TTL 30\n"));
+	assert!(content.contains(&format!("ffhl.de. 30 IN SOA ns1.ffhl.de. hostmaster.ffhl.de. {} ", serial)));
+	assert!(content.contains("ffhl.de. 30 IN NS ns1.ffhl.de.\n"));
+	assert!(content.contains("foo.ffhl.de. 60 IN A 10.0.0.1\n"));
+	assert!(!dir.join("ffhl.de.zone.tmp").exists());
+	assert_eq!(std::fs::read_to_string(dir.join("reloads")).unwrap(), "ffhl.de\nffhl.de\n");
+}
@@ -0,0 +1,218 @@
+use super::{AppState, ClientIp};
+use super::password::Denied;
+use super::web::TemplateContext;
+use crate::CONFIG;
+use crate::db::Domain;
+use crate::domain::Dname;
+use chrono::Utc;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use rocket;
+use rocket::form::{Form, FromForm};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
+use rocket::State;
+use rocket::{catch, get, post};
+use rocket_dyn_templates::Template;
+use serde_json as json;
+use serde_json::json;
+
+/// private cookie with the name of the account
+const SESSION_COOKIE: &str = "account";
+const MIN_PASSWORD_LENGTH: usize = 10;
+
+
+/// a user logged in to an account
+pub struct User(String);
+
+impl User {
+	pub fn name(&self) -> &str {
+		&self.0
+	}
+}
+
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+	type Error = String;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		match request.cookies().get_private(SESSION_COOKIE) {
+			Some(c) => Outcome::Success(User(c.value().to_string())),
+			None => Outcome::Failure((Status::Unauthorized, "login required".to_string())),
+		}
+	}
+}
+
+
+/// the account pages send the browser to the login
+#[catch(401)]
+pub fn login_required() -> Redirect {
+	Redirect::to("/login")
+}
+
+
+fn login_page(error: Option<String>) -> Template {
+	Template::render("login", TemplateContext::new(json!({
+		"error": error,
+		"min_password_length": MIN_PASSWORD_LENGTH,
+	})))
+}
+
+
+#[get("/login")]
+pub fn login() -> Template {
+	login_page(None)
+}
+
+
+#[derive(FromForm)]
+pub struct Login {
+	name: String,
+	password: String,
+}
+
+
+#[post("/login", data = "<form>")]
+pub async fn login_submit(state: &State<AppState>, cookies: &CookieJar<'_>, ip: ClientIp, form: Form<Login>) -> Result<Redirect, Template> {
+	let account = state.service.get_account(&form.name)
+		.map_err(|e| login_page(Some(e.to_string())))?;
+
+	let hash = account.as_ref().map(|a| a.password.as_str());
+	match (state.passwords.check(&form.name, ip.into_inner(), &form.password, hash).await, account) {
+		(Ok(()), Some(account)) => {
+			cookies.add_private(Cookie::new(SESSION_COOKIE, account.name));
+			Ok(Redirect::to("/account"))
+		}
+		(r, _) => {
+			let e = r.err().unwrap_or(Denied::WrongPassword);
+			warn!("login to account {} failed: {:?}", form.name, e);
+			Err(login_page(Some(e.to_string())))
+		}
+	}
+}
+
+
+#[derive(FromForm)]
+pub struct Register {
+	name: String,
+	password: String,
+	/// the password again
+	repeat: String,
+}
+
+
+#[post("/register", data = "<form>")]
+pub async fn register(state: &State<AppState>, cookies: &CookieJar<'_>, form: Form<Register>) -> Result<Redirect, Template> {
+	if form.password.chars().count() < MIN_PASSWORD_LENGTH {
+		return Err(login_page(Some(format!("the password needs at least {} characters", MIN_PASSWORD_LENGTH))));
+	}
+	if form.password != form.repeat {
+		return Err(login_page(Some("the passwords differ".to_string())));
+	}
+
+	let hash = state.passwords.hash(&form.password).await
+		.map_err(|e| login_page(Some(e)))?;
+	let account = state.service
+		.create_account(form.name.trim(), hash)
+		.map_err(|e| login_page(Some(e.to_string())))?;
+	info!("created account {}", account.name);
+
+	cookies.add_private(Cookie::new(SESSION_COOKIE, account.name));
+	Ok(Redirect::to("/account"))
+}
+
+
+#[post("/logout")]
+pub fn logout(cookies: &CookieJar<'_>) -> Redirect {
+	cookies.remove_private(Cookie::named(SESSION_COOKIE));
+	Redirect::to("/")
+}
+
+
+/// the domains of the account, and forms for new ones
+#[get("/")]
+pub fn dashboard(state: &State<AppState>, user: User) -> Template {
+	dashboard_page(state, &user, None)
+}
+
+
+#[derive(FromForm)]
+pub struct NewDomain {
+	domainname: String,
+	suffix: String,
+	email: Option<String>,
+}
+
+
+#[post("/domain", data = "<form>")]
+pub fn new_domain(state: &State<AppState>, user: User, form: Form<NewDomain>) -> Template {
+	let name = Dname::new(format!("{}.{}", form.domainname.trim(), form.suffix));
+	let r = state.service
+		.new_domain(name.clone(), form.email.as_deref(), Some(user.name()))
+		.map(|_| format!("{} was registered", name))
+		.map_err(|e| e.to_string());
+
+	dashboard_page(state, &user, Some(r))
+}
+
+
+#[derive(FromForm)]
+pub struct Adopt {
+	domain: Dname,
+	token: String,
+}
+
+
+/// adds a domain registered before to the account
+#[post("/adopt", data = "<form>")]
+pub fn adopt(state: &State<AppState>, user: User, form: Form<Adopt>) -> Template {
+	let r = state.service
+		.adopt_domain(user.name(), &form.domain.to_string(), form.token.trim())
+		.map(|d| format!("{} belongs to your account now", d.domainname))
+		.map_err(|e| e.to_string());
+
+	dashboard_page(state, &user, Some(r))
+}
+
+
+fn dashboard_page(state: &AppState, user: &User, result: Option<Result<String, String>>) -> Template {
+	let (message, mut error) = match result {
+		Some(Ok(m)) => (Some(m), None),
+		Some(Err(e)) => (None, Some(e)),
+		None => (None, None),
+	};
+
+	let domains = match state.service.account_domains(user.name()) {
+		Ok(d) => d,
+		Err(e) => {
+			error = error.or_else(|| Some(e.to_string()));
+			Vec::new()
+		}
+	};
+
+	Template::render("account", TemplateContext::new(json!({
+		"name": user.name(),
+		"domains": domains.iter().map(domain_json).collect::<Vec<json::Value>>(),
+		"mails": CONFIG.smtp.is_some(),
+		"message": message,
+		"error": error,
+	})))
+}
+
+
+fn domain_json(d: &Domain) -> json::Value {
+	json!({
+		"domainname": d.domainname,
+		"token": d.token,
+		"ipv4": d.ipv4,
+		"ipv6": d.ipv6,
+		"lastupdate": d.lastupdate.format("%Y-%m-%d %H:%M").to_string(),
+		"valid_until": d.valid_until.format("%Y-%m-%d %H:%M").to_string(),
+		"expired": d.valid_until < Utc::now(),
+		"locked": d.locked,
+		"suspended": d.suspended.as_ref().map(|s| &s.reason),
+		"email": d.email.as_ref().map(|e| &e.address),
+	})
+}
@@ -0,0 +1,301 @@
+use super::{AppState, ClientIp};
+use super::password::Denied;
+use super::web::{history_entries, TemplateContext};
+use crate::CONFIG;
+use crate::db::Domain;
+use crate::domain::Dname;
+use crate::ffdyndns::{DomainFilter, Error};
+use chrono::{Duration, Utc};
+use crypto::scrypt::{scrypt_simple, ScryptParams};
+use crypto::util::fixed_time_eq;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use rocket;
+use rocket::form::{Form, FromForm};
+use rocket::http::{Header, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use rocket::{catch, get, post};
+use rocket_dyn_templates::Template;
+use serde_json as json;
+use serde_json::json;
+
+
+/// an admin who logged in with HTTP basic auth
+pub struct Admin(String);
+
+impl Admin {
+	pub fn name(&self) -> &str {
+		&self.0
+	}
+}
+
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+	type Error = String;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let denied = |e: &str| Outcome::Failure((Status::Unauthorized, e.to_string()));
+
+		let (name, password) = match request.headers().get_one("Authorization").and_then(basic_credentials) {
+			Some(c) => c,
+			None => return denied("credentials are missing"),
+		};
+		let state = try_outcome!(request.guard::<&State<AppState>>().await.map_failure(|(s, _)| (s, String::new())));
+		let ip = try_outcome!(request.guard::<ClientIp>().await);
+
+		let hash = CONFIG.admin.iter().find(|a| a.name == name).map(|a| a.password.as_str());
+		match state.passwords.check_cached(&name, ip.into_inner(), &password, hash).await {
+			Ok(()) => Outcome::Success(Admin(name)),
+			Err(Denied::InvalidHash(e)) => {
+				error!("the password of admin {} is not a valid hash: {}", name, e);
+				denied("invalid credentials")
+			}
+			Err(e) => {
+				warn!("login of admin {} failed: {:?}", name, e);
+				denied("invalid credentials")
+			}
+		}
+	}
+}
+
+
+/// name and password of a `Basic` Authorization header
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+	let (scheme, value) = header.split_once(' ')?;
+	if !scheme.eq_ignore_ascii_case("basic") {
+		return None;
+	}
+
+	let decoded = String::from_utf8(base64::decode(value.trim()).ok()?).ok()?;
+	let (name, password) = decoded.split_once(':')?;
+	Some((name.to_string(), password.to_string()))
+}
+
+
+/// the hash of a password for the admin config
+pub fn hash_password(password: &str) -> String {
+	scrypt_simple(password, &ScryptParams::new(15, 8, 1)).unwrap()
+}
+
+
+#[derive(rocket::Responder)]
+#[response(status = 401)]
+pub struct LoginRequired(&'static str, Header<'static>);
+
+/// makes the browser ask for the credentials
+#[catch(401)]
+pub fn login_required() -> LoginRequired {
+	LoginRequired(
+		"login required\n",
+		Header::new("WWW-Authenticate", "Basic realm=\"ffdyndns admin\", charset=\"UTF-8\""),
+	)
+}
+
+
+/// the forms are only accepted with this token, so other sites
+/// cannot make the browser of an admin change domains
+fn form_token(state: &AppState, admin: &Admin) -> String {
+	crate::sha256!(&format!("{}:{}", state.form_secret, admin.name()))
+}
+
+
+/// all domains with filters. `expires` is `expired` or a number of
+/// days, `stale` the days since the last update, `status` is
+/// `locked` or `suspended`.
+#[get("/?<q>&<suffix>&<expires>&<stale>&<status>")]
+pub fn domains(
+	state: &State<AppState>,
+	_admin: Admin,
+	q: Option<String>,
+	suffix: Option<String>,
+	expires: Option<String>,
+	stale: Option<i64>,
+	status: Option<String>,
+) -> Template {
+	let now = Utc::now();
+	let q = q.unwrap_or_default();
+	let suffix = suffix.filter(|s| !s.is_empty());
+	let expires = expires.unwrap_or_default();
+	let status = status.unwrap_or_default();
+
+	let filter = DomainFilter {
+		suffix: suffix.clone(),
+		text: Some(q.clone()).filter(|q| !q.is_empty()),
+		expires_before: match expires.as_str() {
+			"expired" => Some(now),
+			days => days.parse().ok().map(|days| now + Duration::days(days)),
+		},
+		updated_before: stale.map(|days| now - Duration::days(days)),
+		locked: (status == "locked").then_some(true),
+		suspended: (status == "suspended").then_some(true),
+	};
+
+	let template_data = match state.service.find_domains(&filter) {
+		Ok(domains) => {
+			let domains: Vec<json::Value> = domains.iter().map(domain_json).collect();
+
+			json!({
+				"count": domains.len(),
+				"domains": domains,
+			})
+		}
+		Err(e) => json!({
+			"error": e.to_string(),
+		}),
+	};
+
+	Template::render("admin", TemplateContext::new(json!({
+		"q": q,
+		"suffix": suffix,
+		"expires": expires,
+		"stale": stale,
+		"status": status,
+		"result": template_data,
+	})))
+}
+
+
+/// a domain with its history and the actions
+#[get("/domain?<name>")]
+pub fn domain(state: &State<AppState>, admin: Admin, name: String) -> Template {
+	domain_page(state, &admin, &name, None)
+}
+
+
+#[derive(FromForm)]
+pub struct Action {
+	name: String,
+	/// delete, renew, lock, unlock, suspend, unsuspend or reset-token
+	action: String,
+	/// for renew
+	days: Option<i64>,
+	/// for suspend
+	reason: Option<String>,
+	token: String,
+}
+
+
+#[post("/domain", data = "<form>")]
+pub async fn domain_action(state: &State<AppState>, admin: Admin, form: Form<Action>) -> Template {
+	let service = &state.service;
+	let name = form.name.as_str();
+
+	if !fixed_time_eq(form.token.as_bytes(), form_token(state, &admin).as_bytes()) {
+		let r = Err("the form has expired, reload the page and try again".to_string());
+		return domain_page(state, &admin, name, Some(r));
+	}
+
+	info!("admin {}: {} {}", admin.name(), form.action, name);
+	let r = match form.action.as_str() {
+		"delete" => match service.remove_domain(name).await {
+			Ok(handle) => match service.wait_for_update(handle).await {
+				Ok(()) => Ok(format!("{} was deleted", name)),
+				Err(e) => Err(format!("{} was deleted, but {}", name, e)),
+			},
+			Err(e) => Err(e.to_string()),
+		},
+		"renew" => match form.days {
+			Some(days) if days > 0 => service
+				.renew_domain(name, Duration::days(days))
+				.map(|d| format!("valid until {}", d.valid_until.format("%Y-%m-%d %H:%M UTC"))),
+			_ => Err(Error::UpdateError("the days must be a positive number".to_string())),
+		}
+		.map_err(|e| e.to_string()),
+		"lock" => service.lock_domain(name, true)
+			.map(|_| "locked, the token cannot change the records anymore".to_string())
+			.map_err(|e| e.to_string()),
+		"unlock" => service.lock_domain(name, false)
+			.map(|_| "unlocked".to_string())
+			.map_err(|e| e.to_string()),
+		"suspend" => match service.suspend_domain(name, form.reason.as_deref().unwrap_or("")).await {
+			Ok(handle) => match service.wait_for_update(handle).await {
+				Ok(()) => Ok("suspended, the records were removed".to_string()),
+				Err(e) => Err(format!("suspended, but {}", e)),
+			},
+			Err(e) => Err(e.to_string()),
+		},
+		"unsuspend" => service.unsuspend_domain(name)
+			.map(|_| "unsuspended, the owner can set the records again".to_string())
+			.map_err(|e| e.to_string()),
+		"reset-token" => service.reset_token(name)
+			.map(|t| format!("the new token is {}", t))
+			.map_err(|e| e.to_string()),
+		a => Err(format!("unknown action {}", a)),
+	};
+
+	domain_page(state, &admin, name, Some(r))
+}
+
+
+fn domain_page(state: &AppState, admin: &Admin, name: &str, result: Option<Result<String, String>>) -> Template {
+	let (message, mut error) = match result {
+		Some(Ok(m)) => (Some(m), None),
+		Some(Err(e)) => (None, Some(e)),
+		None => (None, None),
+	};
+
+	let service = &state.service;
+	let (domain, history) = match (service.get_domain(&name.to_string()), service.domain_history(name)) {
+		(Ok(d), Ok(h)) => (d, h),
+		(Err(e), _) | (_, Err(e)) => {
+			error = error.or_else(|| Some(e.to_string()));
+			(None, Vec::new())
+		}
+	};
+	let validity = CONFIG.get_domain_config(&Dname::new(name.to_string()).strip_subdomain())
+		.map_or(30, |c| c.validity.num_days().max(1));
+
+	Template::render("admindomain", TemplateContext::new(json!({
+		"name": name,
+		"domain": domain.as_ref().map(domain_json),
+		"history": history_entries(&history),
+		"validity_days": validity,
+		"form_token": form_token(state, admin),
+		"message": message,
+		"error": error,
+	})))
+}
+
+
+fn domain_json(d: &Domain) -> json::Value {
+	let now = Utc::now();
+	json!({
+		"domainname": d.domainname,
+		"ipv4": d.ipv4,
+		"ipv6": d.ipv6,
+		"txt": d.txt,
+		"lastupdate": d.lastupdate.format("%Y-%m-%d %H:%M").to_string(),
+		"valid_until": d.valid_until.format("%Y-%m-%d %H:%M").to_string(),
+		"expired": d.valid_until < now,
+		"locked": d.locked,
+		"suspended": d.suspended.as_ref().map(|s| json!({
+			"reason": s.reason,
+			"since": s.since.format("%Y-%m-%d %H:%M").to_string(),
+		})),
+		"email": d.email.as_ref().map(|e| json!({
+			"address": e.address,
+			"verified": e.verified,
+			"reminded": e.reminded.map(|t| t.format("%Y-%m-%d %H:%M").to_string()),
+		})),
+		"owner": d.owner,
+	})
+}
+
+
+
+#[test]
+fn credentials() {
+	assert_eq!(basic_credentials("Basic YWRtaW46c2VjcmV0OjE="), Some(("admin".to_string(), "secret:1".to_string())));
+	assert_eq!(basic_credentials("basic YWRtaW46c2VjcmV0OjE="), Some(("admin".to_string(), "secret:1".to_string())));
+	assert_eq!(basic_credentials("Bearer YWRtaW46c2VjcmV0OjE="), None);
+	assert_eq!(basic_credentials("Basic not-base64"), None);
+
+	use crypto::scrypt::scrypt_check;
+
+	let hash = scrypt_simple("secret", &ScryptParams::new(4, 8, 1)).unwrap();
+	assert_eq!(scrypt_check("secret", &hash), Ok(true));
+	assert_eq!(scrypt_check("wrong", &hash), Ok(false));
+}
@@ -0,0 +1,442 @@
+use super::AppState;
+use super::AuthorizationToken;
+use super::ClientIp;
+use super::api::error_status;
+use crate::CONFIG;
+use crate::config::ApiKey;
+use crate::{ADMIN_API_MAX_PAGE_SIZE, ADMIN_API_PAGE_SIZE};
+use crate::db::{Domain, Email, Suspension};
+use crate::domain::Dname;
+use crate::ffdyndns::{DomainFilter, Error};
+use chrono::{DateTime, Duration, Utc};
+use crypto::util::fixed_time_eq;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use rocket;
+use rocket::form::FromForm;
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket::{catch, delete, get, post};
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+
+type ApiResult<T> = Result<T, Custom<Json<json::Value>>>;
+
+
+/// a request with one of the configured API keys in the
+/// Authorization header, as `Bearer <key>` or only the key
+pub struct ApiAdmin(String);
+
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiAdmin {
+	type Error = String;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let token = try_outcome!(request.guard::<AuthorizationToken>().await);
+
+		match find_key(token.inner(), &CONFIG.api_key) {
+			Some(k) => Outcome::Success(ApiAdmin(k.name.clone())),
+			None => {
+				let ip = request.guard::<ClientIp>().await.succeeded().map(ClientIp::into_inner);
+				warn!("admin API request from {:?} with an unknown key", ip);
+				Outcome::Failure((Status::Forbidden, "invalid API key".to_string()))
+			}
+		}
+	}
+}
+
+
+/// the key of an Authorization header, with or without `Bearer`
+fn find_key<'a>(header: &str, keys: &'a [ApiKey]) -> Option<&'a ApiKey> {
+	let key = header.strip_prefix("Bearer ").unwrap_or(header).trim();
+	let hash = crate::sha256!(key);
+
+	keys.iter().find(|k| fixed_time_eq(k.key_sha256.to_lowercase().as_bytes(), hash.as_bytes()))
+}
+
+
+/// errors are JSON like the answers
+#[catch(default)]
+pub fn error_catcher(status: Status, _: &Request) -> Json<json::Value> {
+	Json(json!({"error": status.reason().unwrap_or("error").to_lowercase()}))
+}
+
+
+fn error_response(e: Error) -> Custom<Json<json::Value>> {
+	let status = match e {
+		Error::DomainNotFound => Status::NotFound,
+		Error::DomainExists | Error::DomainInQuarantine(_) => Status::Conflict,
+		ref e => error_status(e),
+	};
+
+	Custom(status, Json(json!({"error": e.to_string()})))
+}
+
+
+fn bad_request(e: &str) -> Custom<Json<json::Value>> {
+	Custom(Status::BadRequest, Json(json!({"error": e})))
+}
+
+
+#[derive(Serialize)]
+pub struct DomainPage {
+	/// number of matching domains on all pages
+	total: usize,
+	page: usize,
+	per_page: usize,
+	domains: Vec<DomainView>,
+}
+
+
+/// a domain without its token, only the owner gets to see that
+#[derive(Debug, Serialize)]
+pub struct DomainView {
+	domainname: String,
+	lastupdate: DateTime<Utc>,
+	valid_until: DateTime<Utc>,
+	ipv4: Option<Ipv4Addr>,
+	ipv6: Option<Ipv6Addr>,
+	txt: Vec<String>,
+	locked: bool,
+	suspended: Option<Suspension>,
+	email: Option<Email>,
+	owner: Option<String>,
+}
+
+impl From<Domain> for DomainView {
+	fn from(d: Domain) -> Self {
+		Self {
+			domainname: d.domainname,
+			lastupdate: d.lastupdate,
+			valid_until: d.valid_until,
+			ipv4: d.ipv4,
+			ipv6: d.ipv6,
+			txt: d.txt,
+			locked: d.locked,
+			suspended: d.suspended,
+			email: d.email,
+			owner: d.owner,
+		}
+	}
+}
+
+
+/// the filters of `domains`, all are optional. `expires` and
+/// `stale` are days from now, `page` starts at 1.
+#[derive(Debug, Default, FromForm)]
+pub struct DomainQuery {
+	/// part of the name
+	q: Option<String>,
+	suffix: Option<String>,
+	expired: Option<bool>,
+	expires: Option<i64>,
+	stale: Option<i64>,
+	locked: Option<bool>,
+	suspended: Option<bool>,
+	page: Option<usize>,
+	per_page: Option<usize>,
+}
+
+impl DomainQuery {
+	fn filter(&self, now: DateTime<Utc>) -> DomainFilter {
+		let expires_before = match (self.expired, self.expires) {
+			(Some(true), _) => Some(now),
+			(_, Some(days)) => Some(now + Duration::days(days)),
+			_ => None,
+		};
+
+		DomainFilter {
+			suffix: self.suffix.clone(),
+			text: self.q.clone(),
+			expires_before,
+			updated_before: self.stale.map(|days| now - Duration::days(days)),
+			locked: self.locked,
+			suspended: self.suspended,
+		}
+	}
+
+	/// the requested page of `domains`
+	fn page(&self, domains: Vec<Domain>) -> DomainPage {
+		let page = self.page.unwrap_or(1).max(1);
+		let per_page = self.per_page.unwrap_or(ADMIN_API_PAGE_SIZE).clamp(1, ADMIN_API_MAX_PAGE_SIZE);
+
+		DomainPage {
+			total: domains.len(),
+			page,
+			per_page,
+			domains: domains.into_iter().skip((page - 1).saturating_mul(per_page)).take(per_page).map(DomainView::from).collect(),
+		}
+	}
+}
+
+
+/// the domains matching all given filters, sorted by name
+#[get("/domains?<query..>")]
+pub fn domains(state: &State<AppState>, _admin: ApiAdmin, query: DomainQuery) -> ApiResult<Json<DomainPage>> {
+	let domains = state.service
+		.find_domains(&query.filter(Utc::now()))
+		.map_err(error_response)?;
+
+	Ok(Json(query.page(domains)))
+}
+
+
+#[get("/domains/<domain>")]
+pub fn domain(state: &State<AppState>, _admin: ApiAdmin, domain: Dname) -> ApiResult<Json<DomainView>> {
+	match state.service.get_domain(&domain.to_string()) {
+		Ok(Some(d)) => Ok(Json(d.into())),
+		Ok(None) => Err(error_response(Error::DomainNotFound)),
+		Err(e) => Err(error_response(e)),
+	}
+}
+
+
+#[derive(Deserialize)]
+pub struct NewDomain {
+	domainname: String,
+	/// default is a new random token
+	token: Option<String>,
+}
+
+
+/// registers a domain and answers with its token
+#[post("/domains", data = "<new>")]
+pub fn create(state: &State<AppState>, admin: ApiAdmin, new: Json<NewDomain>) -> ApiResult<Custom<Json<json::Value>>> {
+	if new.token.as_ref().is_some_and(|t| t.is_empty() || t.contains(char::is_whitespace)) {
+		return Err(bad_request("the token must not be empty or contain spaces"));
+	}
+
+	let name = Dname::new(new.domainname.clone());
+	let token = state.service
+		.add_domain(name.clone(), new.token.clone())
+		.map_err(error_response)?;
+	info!("admin API {}: added {}", admin.0, name);
+
+	Ok(Custom(Status::Created, Json(json!({
+		"domainname": name.to_string(),
+		"token": token,
+	}))))
+}
+
+
+/// removes the domain and waits for the DNS server
+#[delete("/domains/<domain>")]
+pub async fn remove(state: &State<AppState>, admin: ApiAdmin, domain: Dname) -> ApiResult<Json<json::Value>> {
+	let handle = state.service
+		.remove_domain(&domain.to_string())
+		.await
+		.map_err(error_response)?;
+	info!("admin API {}: removed {}", admin.0, domain);
+
+	state.service
+		.wait_for_update(handle)
+		.await
+		.map_err(error_response)?;
+
+	Ok(Json(json!({"deleted": domain.to_string()})))
+}
+
+
+#[derive(Deserialize)]
+pub struct Renew {
+	/// the domain is valid for this many days from now on
+	days: i64,
+}
+
+
+#[post("/domains/<domain>/renew", data = "<renew>")]
+pub fn renew(state: &State<AppState>, admin: ApiAdmin, domain: Dname, renew: Json<Renew>) -> ApiResult<Json<DomainView>> {
+	if renew.days < 1 {
+		return Err(bad_request("days must be at least 1"));
+	}
+
+	let d = state.service
+		.renew_domain(&domain.to_string(), Duration::days(renew.days))
+		.map_err(error_response)?;
+	info!("admin API {}: renewed {} for {} days", admin.0, domain, renew.days);
+
+	Ok(Json(d.into()))
+}
+
+
+#[post("/domains/<domain>/lock")]
+pub fn lock(state: &State<AppState>, admin: ApiAdmin, domain: Dname) -> ApiResult<Json<DomainView>> {
+	set_locked(state, admin, domain, true)
+}
+
+
+#[post("/domains/<domain>/unlock")]
+pub fn unlock(state: &State<AppState>, admin: ApiAdmin, domain: Dname) -> ApiResult<Json<DomainView>> {
+	set_locked(state, admin, domain, false)
+}
+
+
+fn set_locked(state: &AppState, admin: ApiAdmin, domain: Dname, locked: bool) -> ApiResult<Json<DomainView>> {
+	let d = state.service
+		.lock_domain(&domain.to_string(), locked)
+		.map_err(error_response)?;
+	info!("admin API {}: {} {}", admin.0, if locked { "locked" } else { "unlocked" }, domain);
+
+	Ok(Json(d.into()))
+}
+
+
+#[derive(Deserialize)]
+pub struct Suspend {
+	reason: String,
+}
+
+
+/// removes the records and waits for the DNS server
+#[post("/domains/<domain>/suspend", data = "<suspend>")]
+pub async fn suspend(state: &State<AppState>, admin: ApiAdmin, domain: Dname, suspend: Json<Suspend>) -> ApiResult<Json<DomainView>> {
+	let name = domain.to_string();
+	let handle = state.service
+		.suspend_domain(&name, &suspend.reason)
+		.await
+		.map_err(error_response)?;
+	info!("admin API {}: suspended {}", admin.0, domain);
+
+	state.service
+		.wait_for_update(handle)
+		.await
+		.map_err(error_response)?;
+
+	self::domain(state, admin, domain)
+}
+
+
+#[post("/domains/<domain>/unsuspend")]
+pub fn unsuspend(state: &State<AppState>, admin: ApiAdmin, domain: Dname) -> ApiResult<Json<DomainView>> {
+	let d = state.service
+		.unsuspend_domain(&domain.to_string())
+		.map_err(error_response)?;
+	info!("admin API {}: unsuspended {}", admin.0, domain);
+
+	Ok(Json(d.into()))
+}
+
+
+#[derive(Default, Serialize)]
+pub struct Statistics {
+	domains: usize,
+	expired: usize,
+	locked: usize,
+	suspended: usize,
+	/// domains updated within the last day
+	updated_last_day: usize,
+	with_ipv4: usize,
+	with_ipv6: usize,
+	with_txt: usize,
+	/// number of domains by suffix
+	suffixes: BTreeMap<String, usize>,
+}
+
+
+#[get("/statistics")]
+pub fn statistics(state: &State<AppState>, _admin: ApiAdmin) -> ApiResult<Json<Statistics>> {
+	let now = Utc::now();
+	let domains = state.service
+		.find_domains(&DomainFilter::default())
+		.map_err(error_response)?;
+
+	let mut stats = Statistics::default();
+	for c in &CONFIG.domain {
+		stats.suffixes.insert(c.name.clone(), 0);
+	}
+
+	for d in &domains {
+		stats.domains += 1;
+		stats.expired += (d.valid_until < now) as usize;
+		stats.locked += d.locked as usize;
+		stats.suspended += d.suspended.is_some() as usize;
+		stats.updated_last_day += (d.lastupdate > now - Duration::days(1)) as usize;
+		stats.with_ipv4 += d.ipv4.is_some() as usize;
+		stats.with_ipv6 += d.ipv6.is_some() as usize;
+		stats.with_txt += !d.txt.is_empty() as usize;
+		*stats.suffixes.entry(Dname::new(d.domainname.clone()).strip_subdomain()).or_default() += 1;
+	}
+
+	Ok(Json(stats))
+}
+
+
+
+#[test]
+fn api_keys() {
+	let keys: Vec<ApiKey> = vec![
+		toml::from_str(&format!("name = \"monitoring\"\nkey_sha256 = \"{}\"", crate::sha256!("secret"))).unwrap(),
+		toml::from_str(&format!("name = \"billing\"\nkey_sha256 = \"{}\"", crate::sha256!("other").to_uppercase())).unwrap(),
+	];
+	let name = |header| find_key(header, &keys).map(|k| k.name.as_str());
+
+	assert_eq!(name("Bearer secret"), Some("monitoring"));
+	assert_eq!(name("secret"), Some("monitoring"));
+	assert_eq!(name(" other "), Some("billing"));
+	assert_eq!(name("Bearer wrong"), None);
+	assert_eq!(name("Basic secret"), None);
+	assert_eq!(name(""), None);
+}
+
+
+
+#[test]
+fn domain_query() {
+	let now = Utc::now();
+	let filter = DomainQuery {
+		q: Some("foo".to_string()),
+		expires: Some(7),
+		stale: Some(30),
+		locked: Some(false),
+		..Default::default()
+	}.filter(now);
+	assert_eq!(filter.text.as_deref(), Some("foo"));
+	assert_eq!(filter.expires_before, Some(now + Duration::days(7)));
+	assert_eq!(filter.updated_before, Some(now - Duration::days(30)));
+	assert_eq!((filter.locked, filter.suspended, filter.suffix), (Some(false), None, None));
+
+	// expired wins over expires
+	let filter = DomainQuery { expired: Some(true), expires: Some(7), ..Default::default() }.filter(now);
+	assert_eq!(filter.expires_before, Some(now));
+	let filter = DomainQuery { expired: Some(false), ..Default::default() }.filter(now);
+	assert_eq!(filter.expires_before, None);
+
+	let domains: Vec<Domain> = (0..25)
+		.map(|i| Domain::new(format!("d{:02}.ffhl.de.", i), Duration::hours(1)))
+		.collect();
+	let names = |p: &DomainPage| p.domains.iter().map(|d| d.domainname.clone()).collect::<Vec<_>>();
+	let page = |page, per_page| DomainQuery { page, per_page, ..Default::default() }.page(domains.clone());
+
+	let p = page(Some(3), Some(10));
+	assert_eq!((p.total, p.page, p.per_page), (25, 3, 10));
+	assert_eq!(names(&p), (20..25).map(|i| format!("d{}.ffhl.de.", i)).collect::<Vec<_>>());
+	assert_eq!(names(&page(Some(0), Some(2))), vec!["d00.ffhl.de.", "d01.ffhl.de."]);
+	assert!(page(Some(4), Some(10)).domains.is_empty());
+	assert!(page(Some(usize::MAX), Some(10)).domains.is_empty());
+	assert_eq!(page(None, Some(0)).per_page, 1);
+	assert_eq!(page(None, Some(usize::MAX)).per_page, ADMIN_API_MAX_PAGE_SIZE);
+	assert_eq!(page(None, None).domains.len(), 25);
+}
+
+
+
+#[test]
+fn no_tokens() {
+	let mut d = Domain::new("foo.ffhl.de.".to_string(), Duration::hours(1));
+	d.ipv4 = Some("10.0.0.1".parse().unwrap());
+	let view = json::to_value(DomainView::from(d.clone())).unwrap();
+
+	assert_eq!(view.get("token"), None);
+	assert_eq!(view["domainname"], "foo.ffhl.de.");
+	assert_eq!(view["ipv4"], "10.0.0.1");
+	assert!(!json::to_string(&view).unwrap().contains(&d.token));
+}
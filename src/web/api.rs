@@ -1,7 +1,10 @@
 use super::AppState;
+use super::AuthorizationToken;
 use super::ClientIp;
+use crate::CONFIG;
 use crate::domain::Dname;
-use crate::ffdyndns::UpdateRequest;
+use crate::db::{self, HistoryEntry};
+use crate::ffdyndns::{Error, RecordChange, UpdateRequest};
 #[allow(unused_imports)]
 use log::{debug, error, info};
 use rocket;
@@ -9,17 +12,21 @@ use rocket::http::Status;
 use rocket::State;
 use rocket::{get};
 use std::net::IpAddr;
-use rocket::response::{content::Plain};
+use rocket::response::{content::Plain, status::Custom};
+use rocket::serde::json::Json;
 
 
-#[get("/update?<token>&<domain>&<ip>")]
-pub fn update(
+/// `wait=1` makes the request wait for the answer of the DNS server
+#[get("/update?<token>&<domain>&<ip>&<wait>")]
+pub async fn update(
 	state: &State<AppState>,
 	clientip: ClientIp,
 	token: String,
 	domain: Dname,
 	ip: Option<String>,
-) -> Result<Plain<String>, Status> {
+	wait: Option<String>,
+) -> Result<Plain<String>, Custom<Plain<String>>> {
+	let source = *clientip.inner();
 	// prefer the ip address from parameters
 	let new_ip: IpAddr = {
 		if let Some(iip) = ip {
@@ -29,14 +36,105 @@ pub fn update(
 		}
 	};
 
-	state.service
+	let wait = is_enabled(wait.as_deref()) || CONFIG
+		.get_domain_config(&domain.strip_subdomain())
+		.is_some_and(|c| c.wait_for_update);
+
+	let handle = state.service
 		.update_domain(UpdateRequest {
-			addr: new_ip,
+			changes: vec![RecordChange::SetAddr(new_ip)],
 			token: token,
 			domain: domain.to_string(),
+			source: Some(source),
+			check: None,
+		})
+		.await
+		.map_err(error_response)?;
+
+	if wait {
+		state.service
+			.wait_for_update(handle)
+			.await
+			.map_err(error_response)?;
+	}
+
+	Ok(Plain("Update successful\n".to_string()))
+}
+
+
+/// gives an expired domain in quarantine back to the owner of the token
+#[get("/reclaim?<token>&<domain>")]
+pub fn reclaim(
+	state: &State<AppState>,
+	token: String,
+	domain: Dname,
+) -> Result<Plain<String>, Custom<Plain<String>>> {
+	state.service
+		.reclaim_domain(&domain.to_string(), &token)
+		.map(|d| Plain(format!("Reclaimed, valid until {}\n", d.valid_until.format("%Y-%m-%d %H:%M UTC"))))
+		.map_err(error_response)
+}
+
+
+/// sets the address for the expiry reminders, an empty `email` removes it
+#[get("/email?<token>&<domain>&<email>")]
+pub fn email(
+	state: &State<AppState>,
+	token: String,
+	domain: Dname,
+	email: String,
+) -> Result<Plain<String>, Custom<Plain<String>>> {
+	state.service
+		.set_email(&domain.to_string(), &token, Some(&email))
+		.map(|d| Plain(match d.email {
+			Some(e) if e.verified => "The address is verified\n".to_string(),
+			Some(e) => format!("A link to verify the address was sent to {}\n", e.address),
+			None => "The address was removed\n".to_string(),
+		}))
+		.map_err(error_response)
+}
+
+
+/// the last changes of a domain. The token of the
+/// domain is sent in the Authorization header.
+#[get("/history?<domain>")]
+pub fn history(
+	state: &State<AppState>,
+	domain: Dname,
+	token: AuthorizationToken,
+) -> Result<Json<Vec<HistoryEntry>>, Custom<Plain<String>>> {
+	state.service
+		.history(&domain.to_string(), token.inner())
+		.map(Json)
+		.map_err(|e| match e {
+			// nobody learns which domains exist
+			Error::DomainNotFound | Error::InvalidToken => {
+				Custom(Status::Forbidden, Plain(format!("{}\n", Error::InvalidToken)))
+			}
+			e => error_response(e),
 		})
-		.map(|_| Plain("Update successful\n".to_string()))
-		.map_err(|_| Status::BadRequest)
+}
+
+
+fn is_enabled(param: Option<&str>) -> bool {
+	matches!(param, Some("") | Some("1") | Some("true") | Some("yes") | Some("on"))
+}
+
+
+fn error_response(e: Error) -> Custom<Plain<String>> {
+	Custom(error_status(&e), Plain(format!("{}\n", e)))
+}
+
+
+pub(super) fn error_status(e: &Error) -> Status {
+	match e {
+		Error::DomainLocked | Error::DomainSuspended(_) | Error::DomainExpired => Status::Forbidden,
+		Error::DnsUpdateFailed(_) => Status::BadGateway,
+		Error::DnsUpdateTimeout => Status::GatewayTimeout,
+		Error::Database(db::Error::Unavailable(_)) => Status::ServiceUnavailable,
+		Error::Database(_) => Status::InternalServerError,
+		_ => Status::BadRequest,
+	}
 }
 
 
@@ -86,8 +184,9 @@ pub fn update(
 #[get("/status?<domain>")]
 fn status(state: &State<AppState>, domain: String) -> String {
 	let domaininfo = match state.service.get_domain(&domain) {
-		None => return "domain not found".to_string(),
-		Some(r) => r,
+		Ok(None) => return "domain not found".to_string(),
+		Ok(Some(r)) => r,
+		Err(e) => return e.to_string(),
 	};
 
 	format!("{:#?}", domaininfo)
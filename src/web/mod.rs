@@ -1,15 +1,20 @@
+mod account;
+pub mod admin;
+mod adminapi;
 mod api;
+mod password;
 mod web;
 
 use crate::ffdyndns;
 #[allow(unused_imports)]
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rocket;
 use rocket::request::FromRequest;
 use rocket::request::Outcome;
+use rocket::config::SecretKey;
 use rocket::http::Status;
 use rocket::request::Request;
-use rocket::routes;
+use rocket::{catchers, routes};
 use std::fmt::{self, Display};
 use std::net::IpAddr;
 use crate::CONFIG;
@@ -20,6 +25,9 @@ use rocket_dyn_templates::Template;
 pub struct AppState {
 	// templates: Tera,
 	service: ffdyndns::Service,
+	/// secret for the tokens of the admin forms, new on every start
+	form_secret: String,
+	passwords: password::Passwords,
 }
 
 pub struct ClientIp(IpAddr);
@@ -43,17 +51,37 @@ impl Display for ClientIp {
 }
 
 
+/// the peer address, or the one the trusted proxies got the request from
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ClientIp {
 	type Error = String;
 
 	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-		let ip = request.headers().get("X-Forwarded-For").next()
-			.map(|x| x.parse().unwrap())
-			.unwrap_or(request.client_ip().unwrap());
+		let peer = match request.remote() {
+			Some(a) => a.ip(),
+			None => return Outcome::Failure((Status::BadRequest, "the client address is unknown".to_string())),
+		};
+		let forwarded: Vec<&str> = request.headers().get("X-Forwarded-For").collect();
+
+		Outcome::Success(ClientIp(client_ip(peer, &forwarded, &CONFIG.trusted_proxies)))
+	}
+}
+
 
-		Outcome::Success(ClientIp(ip))
+/// every proxy appends the address it got the request from, so going back
+/// from the peer, the first one that is not a trusted proxy is the client
+fn client_ip(peer: IpAddr, forwarded: &[&str], trusted: &[IpAddr]) -> IpAddr {
+	let mut ip = peer;
+	for hop in forwarded.iter().flat_map(|h| h.split(',')).rev() {
+		if !trusted.contains(&ip) {
+			break;
+		}
+		match hop.trim().parse() {
+			Ok(hop) => ip = hop,
+			Err(_) => break,
+		}
 	}
+	ip
 }
 
 
@@ -89,41 +117,122 @@ impl<'r> FromRequest<'r> for AuthorizationToken {
 }
 
 
-pub async fn start_web(app: ffdyndns::Service) {
+/// runs the web server, fails if it cannot be started
+pub async fn start_web(app: ffdyndns::Service) -> Result<(), String> {
 	let appstate = AppState {
 		service: app,
+		form_secret: format!("{}{}", ffdyndns::generate_token(), ffdyndns::generate_token()),
+		passwords: password::Passwords::new(),
 	};
 
-	let config = rocket_config();
+	let config = rocket_config()?;
 
 	rocket::custom(config)
 		.mount("/", routes![
 			web::index,
-			web::newdomain
+			web::newdomain,
+			web::history,
+			web::reclaim,
+			web::verify,
+			web::recover,
+			web::recover_request,
+			web::recover_token,
+			web::recover_set_token,
 		])
 		.mount("/api", routes![
 			api::update,
+			api::history,
+			api::reclaim,
+			api::email,
 			// api::update_rest
 		])
+		.mount("/", routes![
+			account::login,
+			account::login_submit,
+			account::register,
+			account::logout,
+		])
+		.mount("/account", routes![
+			account::dashboard,
+			account::new_domain,
+			account::adopt,
+		])
+		.register("/account", catchers![account::login_required])
+		.mount("/admin", routes![
+			admin::domains,
+			admin::domain,
+			admin::domain_action,
+		])
+		.register("/admin", catchers![admin::login_required])
+		.mount("/api/admin", routes![
+			adminapi::domains,
+			adminapi::domain,
+			adminapi::create,
+			adminapi::remove,
+			adminapi::renew,
+			adminapi::lock,
+			adminapi::unlock,
+			adminapi::suspend,
+			adminapi::unsuspend,
+			adminapi::statistics,
+		])
+		.register("/api/admin", catchers![adminapi::error_catcher])
 		.mount("/static", FileServer::from("./static"))
 		.manage(appstate)
 		.attach(Template::fairing())
-		.launch().await.unwrap();
+		.launch().await
+		.map_err(|e| e.to_string())
 }
 
 
 #[cfg(debug_assertions)]
-fn rocket_config() -> rocket::Config {
+fn rocket_config() -> Result<rocket::Config, String> {
 	let mut conf = rocket::Config::debug_default();
 	conf.port = CONFIG.bind_port as u16;
 	conf.address = CONFIG.bind_address;
-	conf
+	conf.secret_key = secret_key()?;
+	Ok(conf)
 }
 
 #[cfg(not(debug_assertions))]
-fn rocket_config() -> rocket::Config {
+fn rocket_config() -> Result<rocket::Config, String> {
 	let mut conf = rocket::Config::release_default();
 	conf.port = CONFIG.bind_port as u16;
 	conf.address = CONFIG.bind_address;
-	conf
+	conf.secret_key = secret_key()?;
+	Ok(conf)
+}
+
+
+/// the key of the login cookies. Without one in the
+/// config, the users are logged out on every restart.
+fn secret_key() -> Result<SecretKey, String> {
+	Ok(match CONFIG.secret_key()? {
+		Some(key) => SecretKey::derive_from(&key),
+		None => {
+			warn!("no secret_key configured, the users are logged out on every restart");
+			let key: Vec<u8> = (0..64).map(|_| rand::random::<u8>()).collect();
+			SecretKey::from(&key)
+		}
+	})
+}
+
+
+
+#[test]
+fn forwarded_for() {
+	let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+	let client: IpAddr = "192.0.2.1".parse().unwrap();
+	let trusted = [proxy, "10.0.0.1".parse().unwrap()];
+
+	assert_eq!(client_ip(client, &[], &trusted), client);
+	assert_eq!(client_ip(proxy, &["192.0.2.1"], &trusted), client);
+	// only the proxies can set the address
+	assert_eq!(client_ip(client, &["198.51.100.1"], &trusted), client);
+	// the client can put anything in front
+	assert_eq!(client_ip(proxy, &["198.51.100.1, 192.0.2.1"], &trusted), client);
+	assert_eq!(client_ip(proxy, &["198.51.100.1", "192.0.2.1 "], &trusted), client);
+	assert_eq!(client_ip(proxy, &["192.0.2.1", "10.0.0.1"], &trusted), client);
+	assert_eq!(client_ip(proxy, &["garbage"], &trusted), proxy);
+	assert_eq!(client_ip(proxy, &["192.0.2.1"], &[]), proxy);
 }
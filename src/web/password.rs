@@ -0,0 +1,151 @@
+use super::admin::hash_password;
+use crate::ffdyndns::RateLimit;
+use crate::{LOGIN_FAILURE_DELAY, MAX_PASSWORD_CHECKS};
+use chrono::{Duration, Utc};
+use crypto::scrypt::scrypt_check;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::fmt::{self, Display};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+
+/// checked instead when there is no such user, so unknown names take as
+/// long as known ones. Made by `hash_password` of a forgotten password.
+const DUMMY_HASH: &str = "$rscrypt$0$DwgB$CZ2lokUYxL8VHFRvDDkwYw==$MkXwYBvbksD4C8CChxzBp7jumheV1F9O3i/gyWlTs6E=$";
+
+
+/// why a login was refused
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+	/// a login for the name or from the address failed shortly before
+	TooManyFailures,
+	WrongPassword,
+	/// the stored hash cannot be read
+	InvalidHash(&'static str),
+}
+
+impl Display for Denied {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", match self {
+			Self::TooManyFailures => "too many failed logins, try again in a few seconds",
+			Self::WrongPassword | Self::InvalidHash(_) => "wrong name or password",
+		})
+	}
+}
+
+
+/// hashes and checks passwords. scrypt takes a while and a lot of
+/// memory, so only a few run at a time and a name or address that
+/// just failed to log in has to wait before trying again.
+pub struct Passwords {
+	running: Semaphore,
+	/// of the failed logins, by name and by address
+	failed: RateLimit,
+	/// hashes of the credentials that were right before
+	verified: Mutex<HashSet<String>>,
+	dummy: String,
+}
+
+impl Passwords {
+	pub fn new() -> Self {
+		Self {
+			running: Semaphore::new(MAX_PASSWORD_CHECKS),
+			failed: RateLimit::default(),
+			verified: Mutex::new(HashSet::new()),
+			dummy: DUMMY_HASH.to_string(),
+		}
+	}
+
+	/// the hash of a new password
+	pub async fn hash(&self, password: &str) -> Result<String, String> {
+		let _permit = self.running.acquire().await.map_err(|e| e.to_string())?;
+		let password = password.to_string();
+		tokio::task::spawn_blocking(move || hash_password(&password)).await
+			.map_err(|e| e.to_string())
+	}
+
+	/// checks the password of `name` from `ip` against `hash`,
+	/// which is `None` if there is no such user
+	pub async fn check(&self, name: &str, ip: IpAddr, password: &str, hash: Option<&str>) -> Result<(), Denied> {
+		let now = Utc::now();
+		let delay = Duration::seconds(LOGIN_FAILURE_DELAY);
+		let (name, ip) = (name.to_lowercase(), ip.to_string());
+		if self.failed.limited(&name, delay, now) || self.failed.limited(&ip, delay, now) {
+			return Err(Denied::TooManyFailures);
+		}
+
+		let r = match hash {
+			Some(hash) => self.verify(password, hash).await,
+			None => self.verify(password, &self.dummy).await.and(Err(Denied::WrongPassword)),
+		};
+		if r.is_err() {
+			self.failed.record(&name, now);
+			self.failed.record(&ip, now);
+		}
+		r
+	}
+
+	/// like `check`, but credentials that were right once are not
+	/// checked again. For HTTP basic auth, which sends them with
+	/// every request.
+	pub async fn check_cached(&self, name: &str, ip: IpAddr, password: &str, hash: Option<&str>) -> Result<(), Denied> {
+		// names do not contain a colon, hashes neither
+		let key = hash.map(|h| crate::sha256!(&format!("{}:{}:{}", name, h, password)));
+		if matches!(&key, Some(k) if self.verified.lock().unwrap().contains(k)) {
+			return Ok(());
+		}
+
+		self.check(name, ip, password, hash).await?;
+		if let Some(k) = key {
+			self.verified.lock().unwrap().insert(k);
+		}
+		Ok(())
+	}
+
+	async fn verify(&self, password: &str, hash: &str) -> Result<(), Denied> {
+		let _permit = self.running.acquire().await.map_err(|_| Denied::WrongPassword)?;
+		// checking the password takes a while, the other requests go on meanwhile
+		let (password, hash) = (password.to_string(), hash.to_string());
+		match tokio::task::spawn_blocking(move || scrypt_check(&password, &hash)).await {
+			Ok(Ok(true)) => Ok(()),
+			Ok(Err(e)) => Err(Denied::InvalidHash(e)),
+			_ => Err(Denied::WrongPassword),
+		}
+	}
+}
+
+
+
+#[tokio::test]
+async fn failed_logins() {
+	use crypto::scrypt::{scrypt_simple, ScryptParams};
+
+	let mut passwords = Passwords::new();
+	let hash = scrypt_simple("secret", &ScryptParams::new(4, 8, 1)).unwrap();
+	// a known password is refused for unknown users
+	passwords.dummy = hash.clone();
+	let (ip, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+
+	assert_eq!(passwords.check("alice", ip, "secret", Some(&hash)).await, Ok(()));
+	assert_eq!(passwords.check("alice", ip, "secret", Some(&hash)).await, Ok(()));
+	assert_eq!(passwords.check("alice", ip, "wrong", Some(&hash)).await, Err(Denied::WrongPassword));
+
+	// after a failure the name and the address have to wait
+	assert_eq!(passwords.check("Alice", other, "secret", Some(&hash)).await, Err(Denied::TooManyFailures));
+	assert_eq!(passwords.check("bob", ip, "secret", Some(&hash)).await, Err(Denied::TooManyFailures));
+	assert_eq!(passwords.check("bob", other, "secret", None).await, Err(Denied::WrongPassword));
+	assert_eq!(passwords.check("carol", other, "secret", Some(&hash)).await, Err(Denied::TooManyFailures));
+	let r = passwords.check("carol", "192.0.2.3".parse().unwrap(), "secret", Some("garbage")).await;
+	assert!(matches!(r, Err(Denied::InvalidHash(_))));
+
+	// remembered credentials pass without scrypt, and even while the
+	// name has to wait, others do not
+	let passwords = Passwords::new();
+	assert_eq!(passwords.check_cached("root", ip, "secret", Some(&hash)).await, Ok(()));
+	assert_eq!(passwords.check_cached("root", other, "wrong", Some(&hash)).await, Err(Denied::WrongPassword));
+	assert_eq!(passwords.check_cached("root", ip, "secret", Some(&hash)).await, Ok(()));
+	assert_eq!(passwords.check_cached("root", ip, "secret", Some("garbage")).await, Err(Denied::TooManyFailures));
+	assert_eq!(passwords.verified.lock().unwrap().len(), 1);
+}
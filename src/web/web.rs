@@ -1,10 +1,15 @@
 use crate::CONFIG;
+use crate::db::{HistoryEntry, UpdateStatus, ZoneRecord};
+use crate::dns::message::quote_text;
+use crate::dns::tsig;
 use crate::domain::Dname;
+use crate::ffdyndns::Error;
 #[allow(unused_imports)]
 use log::{debug, error, info};
 use rocket_dyn_templates::Template;
 use rocket;
-use rocket::get;
+use rocket::form::{Form, FromForm};
+use rocket::{get, post};
 use rocket::State;
 use serde_json as json;
 use serde_json::json;
@@ -14,7 +19,7 @@ use super::AppState;
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct TemplateContext<T> {
+pub(super) struct TemplateContext<T> {
 	server_url: String,
 	domains: Vec<json::Value>,
 	name: String,
@@ -29,7 +34,7 @@ impl TemplateContext<()> {
 }
 
 impl<T> TemplateContext<T> {
-	fn new(data: T) -> Self {
+	pub(super) fn new(data: T) -> Self {
 		Self {
 			name: CONFIG.name.clone(),
 			description: CONFIG.description.clone(),
@@ -54,29 +59,36 @@ pub fn index(_state: &State<AppState>) -> Template {
 }
 
 
-#[get("/newdomain?<domainname>&<suffix>&<tos>")]
+#[get("/newdomain?<domainname>&<suffix>&<email>&<tos>")]
 pub fn newdomain(
 	state: &State<AppState>,
 	domainname: Option<String>,
 	suffix: Option<String>,
+	email: Option<String>,
 	tos: Option<bool>,
 ) -> Template {
 	let template_data = match (&domainname, &suffix, tos) {
 		(Some(name), Some(suffix), Some(tos)) if tos => {
-			let newdomain: Dname = format!("{}.{}", name, suffix).parse().unwrap();
-			let r = state.service.new_domain(newdomain.clone());
+			let newdomain = format!("{}.{}", name, suffix);
+			let r = newdomain.parse::<Dname>()
+				.map_err(|_| Error::InvalidDomainName)
+				.and_then(|d| state.service.new_domain(d, email.as_deref(), None));
 
 			json!({
 				"form_request": true,
 				"error": r.is_err(),
-				"errormsg": r,
+				"errormsg": r.as_ref().map_err(|e| e.to_string()),
 				"token": r,
-				"domainname": newdomain.to_string()
+				"tsig_secret": r.as_ref().ok().map(|t| base64::encode(tsig::domain_secret(t))),
+				"update_listen": CONFIG.update_listen,
+				"domainname": Dname::new(newdomain).to_string(),
+				"email": email.filter(|e| r.is_ok() && !e.trim().is_empty()),
 			})
 		}
 		_ => {
 			json!({
 				"form_request": false,
+				"mails": CONFIG.smtp.is_some(),
 				"available_domains": CONFIG.domain.iter().map(|x| &x.name).collect::<Vec<&String>>()
 			})
 		}
@@ -87,3 +99,199 @@ pub fn newdomain(
 		TemplateContext::new(template_data)
 	)
 }
+
+
+/// the link in the mail to verify the address of the owner
+#[get("/verify?<domain>&<code>")]
+pub fn verify(state: &State<AppState>, domain: String, code: String) -> Template {
+	let template_data = match state.service.verify_email(&domain, &code) {
+		Ok(d) => json!({
+			"domainname": d.domainname,
+			"reminder_days": CONFIG.reminder_days,
+		}),
+		Err(e) => json!({
+			"error": match e {
+				Error::DomainNotFound => "the link is invalid or outdated".to_string(),
+				e => e.to_string(),
+			},
+		}),
+	};
+
+	Template::render(
+		"verify",
+		TemplateContext::new(template_data)
+	)
+}
+
+
+#[get("/recover")]
+pub fn recover() -> Template {
+	Template::render(
+		"recover",
+		TemplateContext::new(json!({
+			"form_request": false,
+			"mails": CONFIG.smtp.is_some(),
+		}))
+	)
+}
+
+
+#[derive(FromForm)]
+pub struct Recover {
+	domain: Dname,
+}
+
+
+/// mails a link to set a new token to the verified address of the domain
+#[post("/recover", data = "<form>")]
+pub fn recover_request(state: &State<AppState>, form: Form<Recover>) -> Template {
+	let r = state.service.request_recovery(&form.domain.to_string());
+
+	Template::render(
+		"recover",
+		TemplateContext::new(json!({
+			"form_request": true,
+			"mails": CONFIG.smtp.is_some(),
+			"domainname": form.domain.to_string(),
+			"error": r.err().map(|e| e.to_string()),
+			"validity": crate::RECOVERY_LINK_VALIDITY,
+		}))
+	)
+}
+
+
+/// the link in the recovery mail, asks for the new token
+#[get("/recover/token?<domain>&<expires>&<code>")]
+pub fn recover_token(domain: String, expires: i64, code: String) -> Template {
+	Template::render(
+		"recovertoken",
+		TemplateContext::new(json!({
+			"domainname": domain,
+			"expires": expires,
+			"code": code,
+		}))
+	)
+}
+
+
+#[derive(FromForm)]
+pub struct NewToken {
+	domain: String,
+	expires: i64,
+	code: String,
+	/// a new random token if empty
+	token: Option<String>,
+}
+
+
+#[post("/recover/token", data = "<form>")]
+pub fn recover_set_token(state: &State<AppState>, form: Form<NewToken>) -> Template {
+	let r = state.service.recover_token(&form.domain, form.expires, &form.code, form.token.as_deref());
+
+	Template::render(
+		"recovertoken",
+		TemplateContext::new(json!({
+			"domainname": form.domain,
+			"expires": form.expires,
+			"code": form.code,
+			"token": r.as_ref().ok(),
+			"error": r.err().map(|e| e.to_string()),
+		}))
+	)
+}
+
+
+/// gives an expired domain back to the owner of the token
+#[get("/reclaim?<domain>&<token>")]
+pub fn reclaim(
+	state: &State<AppState>,
+	domain: Option<String>,
+	token: Option<String>,
+) -> Template {
+	let mut template_data = match (&domain, &token) {
+		(Some(domain), Some(token)) => match state.service.reclaim_domain(domain, token) {
+			Ok(d) => json!({
+				"form_request": true,
+				"domainname": d.domainname,
+				"valid_until": d.valid_until.format("%Y-%m-%d %H:%M UTC").to_string(),
+			}),
+			Err(e) => json!({
+				"form_request": true,
+				"domainname": domain,
+				"error": match e {
+					// nobody learns which domains exist
+					Error::DomainNotFound | Error::InvalidToken => "unknown domain or invalid token".to_string(),
+					e => e.to_string(),
+				},
+			}),
+		},
+		_ => json!({
+			"form_request": false,
+			"domainname": domain,
+		}),
+	};
+	template_data["quarantine_days"] = json!(CONFIG.quarantine_days);
+
+	Template::render(
+		"reclaim",
+		TemplateContext::new(template_data)
+	)
+}
+
+
+/// the changes of a domain for the owner of the token
+#[get("/history?<domain>&<token>")]
+pub fn history(
+	state: &State<AppState>,
+	domain: Option<String>,
+	token: Option<String>,
+) -> Template {
+	let template_data = match (&domain, &token) {
+		(Some(domain), Some(token)) => match state.service.history(domain, token) {
+			Ok(entries) => json!({
+				"form_request": true,
+				"domainname": domain,
+				"history": history_entries(&entries),
+			}),
+			Err(_) => json!({
+				"form_request": true,
+				"domainname": domain,
+				"error": "unknown domain or invalid token",
+			}),
+		},
+		_ => json!({
+			"form_request": false,
+		}),
+	};
+
+	Template::render(
+		"history",
+		TemplateContext::new(template_data)
+	)
+}
+
+
+/// the history as shown in the templates
+pub(super) fn history_entries(entries: &[HistoryEntry]) -> Vec<json::Value> {
+	entries.iter().map(|e| json!({
+		"time": e.time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+		"source": e.source.map_or("ffdyndns".to_string(), |a| a.to_string()),
+		"token": e.token,
+		"old": records_text(&e.old),
+		"new": records_text(&e.new),
+		"status": match &e.status {
+			UpdateStatus::Pending => "pending".to_string(),
+			UpdateStatus::Ok => "ok".to_string(),
+			UpdateStatus::Failed(err) => format!("failed: {}", err),
+		},
+	})).collect()
+}
+
+
+fn records_text(records: &[ZoneRecord]) -> Vec<String> {
+	records.iter().map(|r| match r {
+		ZoneRecord::A(_, a) => format!("A {}", a),
+		ZoneRecord::Aaaa(_, a) => format!("AAAA {}", a),
+		ZoneRecord::Txt(_, t) => format!("TXT {}", quote_text(t)),
+	}).collect()
+}